@@ -1,58 +1,24 @@
 use core::{fmt, ops::Deref};
 
-use rstd::alloc::{borrow::ToOwned, string::String, sync::Arc, vec::Vec};
-use spin::RwLock;
-
-/// The raw SDT header struct, as defined by the ACPI specification.
-#[derive(Copy, Clone, Debug)]
-#[repr(packed)]
-pub struct SdtHeader {
-    pub signature: [u8; 4],
-    pub length: u32,
-    pub revision: u8,
-    pub checksum: u8,
-    pub oem_id: [u8; 6],
-    pub oem_table_id: [u8; 8],
-    pub oem_revision: u32,
-    pub creator_id: u32,
-    pub creator_revision: u32,
-}
-
-impl SdtHeader {
-    pub fn signature(&self) -> SdtSignature {
-        SdtSignature {
-            signature: self.signature,
-            oem_id: self.oem_id,
-            oem_table_id: self.oem_table_id,
-        }
-    }
-    pub fn length(&self) -> usize {
-        self.length
-            .try_into()
-            .expect("expected usize to be at least 32 bits")
-    }
-}
-
-unsafe impl plain::Plain for SdtHeader {}
-
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct SdtSignature {
-    pub signature: [u8; 4],
-    pub oem_id: [u8; 6],
-    pub oem_table_id: [u8; 8],
-}
-
-impl fmt::Display for SdtSignature {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}-{}-{}",
-            String::from_utf8_lossy(&self.signature),
-            String::from_utf8_lossy(&self.oem_id),
-            String::from_utf8_lossy(&self.oem_table_id)
-        )
-    }
-}
+use aml::{AmlContext, DebugVerbosity};
+use rstd::alloc::{borrow::ToOwned, boxed::Box, format, string::String, vec::Vec};
+use spin::{Mutex, RwLock};
+
+pub use acpid::sdt::{
+    AmlContainingTable, Dsdt, Fadt, FadtAcpi2Struct, FadtStruct, GenericAddressStructure,
+    InvalidSdtError, MAX_TABLE_LENGTH, PossibleAmlTables, Sdt, SdtHeader, SdtSignature, Ssdt,
+};
+use acpid::{
+    dmar::{Dmar, DmarEntry},
+    fpdt::{self, Fpdt},
+    hpet::Hpet,
+    ivrs::{Ivrs, IvrsEntry},
+    madt::Madt,
+    slit::Slit,
+    srat::Srat,
+};
+
+use crate::aml_handler::AmlHandler;
 
 struct PhysmapGuard {
     virt: *const u8,
@@ -79,21 +45,6 @@ impl Deref for PhysmapGuard {
     }
 }
 
-#[derive(Debug)]
-pub enum InvalidSdtError {
-    InvalidSize,
-    BadChecksum,
-}
-
-impl fmt::Display for InvalidSdtError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::InvalidSize => write!(f, "Invalid size of sdt"),
-            Self::BadChecksum => write!(f, "Bad checksum for sdt"),
-        }
-    }
-}
-
 #[derive(Debug)]
 pub enum TablePhysLoadError {
     Io,
@@ -109,271 +60,162 @@ impl fmt::Display for TablePhysLoadError {
     }
 }
 
-#[derive(Clone)]
-pub struct Sdt(Arc<[u8]>);
-
-impl Sdt {
-    pub fn new(slice: Arc<[u8]>) -> Result<Self, InvalidSdtError> {
-        let header = match plain::from_bytes::<SdtHeader>(&slice) {
-            Ok(header) => header,
-            Err(plain::Error::TooShort) => return Err(InvalidSdtError::InvalidSize),
-            Err(plain::Error::BadAlignment) => panic!(
-                "plain::from_bytes failed due to alignment, but SdtHeader is #[repr(packed)]!"
-            ),
-        };
-
-        if header.length() != slice.len() {
-            return Err(InvalidSdtError::InvalidSize);
-        }
-
-        let checksum = slice
-            .iter()
-            .copied()
-            .fold(0_u8, |current_sum, item| current_sum.wrapping_add(item));
-
-        if checksum != 0 {
-            return Err(InvalidSdtError::BadChecksum);
-        }
-
-        Ok(Self(slice))
-    }
-
-    pub fn load_from_physical(physaddr: usize) -> Result<Self, TablePhysLoadError> {
-        let physaddr_start_page = physaddr / 4096 * 4096;
-        let physaddr_page_offset = physaddr % 4096;
+/// Copies and validates the table at `physaddr`.
+pub fn load_physical_table(physaddr: usize) -> Result<Sdt, TablePhysLoadError> {
+    Sdt::new(read_physical_table(physaddr)?.into()).map_err(TablePhysLoadError::Validity)
+}
 
-        // Begin by reading and validating the header first. The SDT header is always 36 bytes
-        // long, and can thus span either one or two page table frames.
-        let needs_extra_page = (4096 - physaddr_page_offset)
-            .checked_sub(core::mem::size_of::<SdtHeader>())
-            .is_none();
-        let page_table_count = 1 + if needs_extra_page { 1 } else { 0 };
+/// Copies the table at `physaddr`, as long as its header says it is, without validating
+/// it.
+pub fn read_physical_table(physaddr: usize) -> Result<Vec<u8>, TablePhysLoadError> {
+    let physaddr_start_page = physaddr / 4096 * 4096;
+    let physaddr_page_offset = physaddr % 4096;
 
-        let pages = PhysmapGuard::map(physaddr_start_page, page_table_count)?;
-        assert!(pages.len() >= core::mem::size_of::<SdtHeader>());
-        let sdt_mem = &pages[physaddr_page_offset..];
+    // Begin by reading and validating the header first. The SDT header is always 36 bytes
+    // long, and can thus span either one or two page table frames.
+    let needs_extra_page = (4096 - physaddr_page_offset)
+        .checked_sub(core::mem::size_of::<SdtHeader>())
+        .is_none();
+    let page_table_count = 1 + if needs_extra_page { 1 } else { 0 };
 
-        let sdt = plain::from_bytes::<SdtHeader>(&sdt_mem[..core::mem::size_of::<SdtHeader>()])
-            .expect("either alignment is wrong, or the length is too short, both of which are already checked for");
+    let pages = PhysmapGuard::map(physaddr_start_page, page_table_count)?;
+    assert!(pages.len() >= core::mem::size_of::<SdtHeader>());
+    let sdt_mem = &pages[physaddr_page_offset..];
 
-        let total_length = sdt.length();
-        let base_length = core::cmp::min(total_length, sdt_mem.len());
-        let extended_length = total_length - base_length;
+    let sdt = plain::from_bytes::<SdtHeader>(&sdt_mem[..core::mem::size_of::<SdtHeader>()])
+        .expect("either alignment is wrong, or the length is too short, both of which are already checked for");
 
-        let mut loaded = sdt_mem[..base_length].to_owned();
-        loaded.reserve(extended_length);
+    let total_length = sdt.length();
+    if !(core::mem::size_of::<SdtHeader>()..=MAX_TABLE_LENGTH).contains(&total_length) {
+        return Err(TablePhysLoadError::Validity(InvalidSdtError::InvalidSize));
+    }
+    let base_length = core::cmp::min(total_length, sdt_mem.len());
+    let extended_length = total_length - base_length;
 
-        const SIMULTANEOUS_PAGE_COUNT: usize = 4;
+    let mut loaded = sdt_mem[..base_length].to_owned();
+    loaded.reserve(extended_length);
 
-        let mut left = extended_length;
-        let mut offset = physaddr_start_page + page_table_count * 4096;
+    const SIMULTANEOUS_PAGE_COUNT: usize = 4;
 
-        let length_per_iteration = 4096 * SIMULTANEOUS_PAGE_COUNT;
+    let mut left = extended_length;
+    let mut offset = physaddr_start_page + page_table_count * 4096;
 
-        while left > 0 {
-            let to_copy = core::cmp::min(left, length_per_iteration);
-            let additional_pages = PhysmapGuard::map(offset, to_copy.div_ceil(4096))?;
+    let length_per_iteration = 4096 * SIMULTANEOUS_PAGE_COUNT;
 
-            loaded.extend(&additional_pages[..to_copy]);
+    while left > 0 {
+        let to_copy = core::cmp::min(left, length_per_iteration);
+        let additional_pages = PhysmapGuard::map(offset, to_copy.div_ceil(4096))?;
 
-            left -= to_copy;
-            offset += to_copy;
-        }
-        assert_eq!(left, 0);
+        loaded.extend(&additional_pages[..to_copy]);
 
-        Self::new(loaded.into()).map_err(|err| TablePhysLoadError::Validity(err))
+        left -= to_copy;
+        offset += to_copy;
     }
+    assert_eq!(left, 0);
 
-    pub fn as_slice(&self) -> &[u8] {
-        &self.0
-    }
+    Ok(loaded)
 }
 
-impl Deref for Sdt {
-    type Target = SdtHeader;
-
-    fn deref(&self) -> &Self::Target {
-        plain::from_bytes::<SdtHeader>(&self.0)
-            .expect("expected already validated Sdt to be able to get its header")
-    }
+/// Copies `length` bytes of physical memory from `physaddr`, for the structures tables point
+/// at that are not tables themselves.
+fn read_physical_bytes(physaddr: usize, length: usize) -> Result<Vec<u8>, TablePhysLoadError> {
+    let start_page = physaddr / 4096 * 4096;
+    let page_offset = physaddr % 4096;
+    let end = page_offset
+        .checked_add(length)
+        .ok_or(TablePhysLoadError::Io)?;
+
+    let pages = PhysmapGuard::map(start_page, end.div_ceil(4096))?;
+    Ok(pages[page_offset..end].to_owned())
 }
 
-impl Sdt {
-    pub fn data(&self) -> &[u8] {
-        &self.0[core::mem::size_of::<SdtHeader>()..]
+/// Copies a table the FPDT points at, as long as its header says it is, if it has
+/// `signature`.
+fn read_fpdt_table(physaddr: u64, signature: &[u8; 4]) -> Option<Vec<u8>> {
+    let physaddr = usize::try_from(physaddr).ok()?;
+    let header = read_physical_bytes(physaddr, fpdt::TABLE_HEADER_LEN).ok()?;
+    let length = fpdt::table_length(&header, signature)?;
+    if !(fpdt::TABLE_HEADER_LEN..=MAX_TABLE_LENGTH).contains(&length) {
+        return None;
     }
+    read_physical_bytes(physaddr, length).ok()
 }
 
-impl fmt::Debug for Sdt {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Sdt")
-            .field("header", &*self as &SdtHeader)
-            .field("extra_len", &self.data().len())
-            .finish()
-    }
-}
-
-pub struct Dsdt(Sdt);
-pub struct Ssdt(Sdt);
-
-#[repr(packed)]
-#[derive(Clone, Copy, Debug)]
-pub struct FadtStruct {
-    pub header: SdtHeader,
-    pub firmware_ctrl: u32,
-    pub dsdt: u32,
-
-    // field used in ACPI 1.0; no longer in use, for compatibility only
-    reserved: u8,
-
-    pub preferred_power_managament: u8,
-    pub sci_interrupt: u16,
-    pub smi_command_port: u32,
-    pub acpi_enable: u8,
-    pub acpi_disable: u8,
-    pub s4_bios_req: u8,
-    pub pstate_control: u8,
-    pub pm1a_event_block: u32,
-    pub pm1b_event_block: u32,
-    pub pm1a_control_block: u32,
-    pub pm1b_control_block: u32,
-    pub pm2_control_block: u32,
-    pub pm_timer_block: u32,
-    pub gpe0_block: u32,
-    pub gpe1_block: u32,
-    pub pm1_event_length: u8,
-    pub pm1_control_length: u8,
-    pub pm2_control_length: u8,
-    pub pm_timer_length: u8,
-    pub gpe0_ength: u8,
-    pub gpe1_length: u8,
-    pub gpe1_base: u8,
-    pub c_state_control: u8,
-    pub worst_c2_latency: u16,
-    pub worst_c3_latency: u16,
-    pub flush_size: u16,
-    pub flush_stride: u16,
-    pub duty_offset: u8,
-    pub duty_width: u8,
-    pub day_alarm: u8,
-    pub month_alarm: u8,
-    pub century: u8,
-
-    // reserved in ACPI 1.0; used since ACPI 2.0+
-    pub boot_architecture_flags: u16,
-
-    reserved2: u8,
-    pub flags: u32,
-}
-unsafe impl plain::Plain for FadtStruct {}
-
-#[repr(packed)]
-#[derive(Clone, Copy, Debug, Default)]
-pub struct GenericAddressStructure {
-    address_space: u8,
-    bit_width: u8,
-    bit_offset: u8,
-    access_size: u8,
-    address: u64,
-}
+/// Sets up the FADT, then loads the DSDT it points at.
+fn init_fadt(context: &mut AcpiContext) {
+    let Some(fadt_sdt) = context.take_single_sdt(*b"FACP") else {
+        context.warn(None, "no usable FADT".into());
+        return;
+    };
+
+    let signature = fadt_sdt.signature();
+    let fadt = match Fadt::new(fadt_sdt) {
+        Some(fadt) => fadt,
+        None => {
+            context.warn(Some(signature), "too short for a FADT".into());
+            return;
+        }
+    };
 
-#[repr(packed)]
-#[derive(Clone, Copy, Debug)]
-pub struct FadtAcpi2Struct {
-    // 12 byte structure; see below for details
-    pub reset_reg: GenericAddressStructure,
-
-    pub reset_value: u8,
-    reserved3: [u8; 3],
-
-    // 64bit pointers - Available on ACPI 2.0+
-    pub x_firmware_control: u64,
-    pub x_dsdt: u64,
-
-    pub x_pm1a_event_block: GenericAddressStructure,
-    pub x_pm1b_event_block: GenericAddressStructure,
-    pub x_pm1a_control_block: GenericAddressStructure,
-    pub x_pm1b_control_block: GenericAddressStructure,
-    pub x_pm2_control_block: GenericAddressStructure,
-    pub x_pm_timer_block: GenericAddressStructure,
-    pub x_gpe0_block: GenericAddressStructure,
-    pub x_gpe1_block: GenericAddressStructure,
-}
-unsafe impl plain::Plain for FadtAcpi2Struct {}
+    // X_DSDT supersedes DSDT when it is set, and firmware is free to leave it zero.
+    let x_dsdt = fadt
+        .acpi_2_struct()
+        .map(|fadt2| fadt2.x_dsdt)
+        .filter(|&x_dsdt| x_dsdt != 0)
+        .and_then(|x_dsdt| usize::try_from(x_dsdt).ok());
+    let dsdt_ptr = x_dsdt.unwrap_or_else(|| {
+        usize::try_from(fadt.dsdt).expect("expected any given u32 to fit within usize")
+    });
 
-#[derive(Clone)]
-pub struct Fadt(Sdt);
+    println!("DSDT at {:X}", dsdt_ptr);
 
-impl Fadt {
-    pub fn acpi_2_struct(&self) -> Option<&FadtAcpi2Struct> {
-        let bytes = &self.0.0[core::mem::size_of::<FadtStruct>()..];
+    context.fadt = Some(fadt.clone());
 
-        match plain::from_bytes::<FadtAcpi2Struct>(bytes) {
-            Ok(fadt2) => Some(fadt2),
-            Err(plain::Error::TooShort) => None,
-            Err(plain::Error::BadAlignment) => unreachable!(
-                "plain::from_bytes reported bad alignment, but FadtAcpi2Struct is #[repr(packed)]"
-            ),
-        }
+    if let Some(dsdt_sdt) = context.load_table(dsdt_ptr) {
+        context.dsdt = Some(Dsdt(dsdt_sdt));
     }
 }
 
-impl Deref for Fadt {
-    type Target = FadtStruct;
-
-    fn deref(&self) -> &Self::Target {
-        plain::from_bytes::<FadtStruct>(&self.0.0)
-            .expect("expected FADT struct to already be validated in Deref impl")
-    }
+/// Where acpid looks for tables that replace or add to the firmware's. This is read
+/// through the kernel like `/drv`, as fsmd only comes up after acpid.
+const OVERRIDE_DIR: &str = "/acpi";
+
+/// When this file exists, tables with a bad checksum are used rather than quarantined. See
+/// [`Sdt::new_ignoring_checksum`].
+const ACCEPT_BAD_CHECKSUMS: &str = "/acpi/accept-bad-checksums";
+
+/// Where a table in [`AcpiContext::tables`] came from.
+#[derive(Clone, Debug)]
+pub enum TableOrigin {
+    /// The firmware's, at this physical address.
+    Physical(usize),
+    /// An override, from this file.
+    Override(String),
 }
 
-impl Fadt {
-    pub fn new(sdt: Sdt) -> Option<Fadt> {
-        if sdt.signature != *b"FACP" || sdt.length() < core::mem::size_of::<Fadt>() {
-            return None;
-        }
-        Some(Fadt(sdt))
+/// Reads the whole of the file at `path`, or `None` if it cannot be opened.
+fn read_file(path: &str) -> Option<Vec<u8>> {
+    let fd = rstd::fs::open(path, 0) as usize;
+    if fd == usize::MAX {
+        return None;
     }
 
-    pub fn init(context: &mut AcpiContext) {
-        let fadt_sdt = context
-            .take_single_sdt(*b"FACP")
-            .expect("expected ACPI to always have a FADT");
-
-        let fadt = match Fadt::new(fadt_sdt) {
-            Some(fadt) => fadt,
-            None => {
-                println!("Failed to find FADT");
-                return;
-            }
-        };
-
-        let dsdt_ptr = match fadt.acpi_2_struct() {
-            Some(fadt2) => usize::try_from(fadt2.x_dsdt).unwrap_or_else(|_| {
-                usize::try_from(fadt.dsdt).expect("expected any given u32 to fit within usize")
-            }),
-            None => usize::try_from(fadt.dsdt).expect("expected any given u32 to fit within usize"),
-        };
-
-        println!("FACP at {:X}", { dsdt_ptr });
+    let mut stat = rstd::stat::Stat::default();
+    rstd::fs::fstat(fd, stat.as_mut_ptr() as usize);
 
-        let dsdt_sdt = match Sdt::load_from_physical(fadt.dsdt as usize) {
-            Ok(dsdt) => dsdt,
-            Err(error) => {
-                println!("Failed to load DSDT: {}", error);
-                return;
-            }
-        };
-
-        context.fadt = Some(fadt.clone());
-        context.dsdt = Some(Dsdt(dsdt_sdt.clone()));
-
-        context.tables.push(dsdt_sdt);
-    }
+    let mut bytes = rstd::alloc::vec![0u8; stat.st_size as usize];
+    rstd::fs::read(fd, bytes.as_mut_ptr() as usize, bytes.len());
+    Some(bytes)
 }
 
 pub struct AcpiContext {
     tables: Vec<Sdt>,
+    /// Where each table in `tables` came from.
+    origins: Vec<TableOrigin>,
+    /// Tables with a bad checksum, kept for diagnosis but not used.
+    quarantine: Vec<(usize, Sdt)>,
+    /// Whether [`ACCEPT_BAD_CHECKSUMS`] exists.
+    accept_bad_checksums: bool,
     dsdt: Option<Dsdt>,
     fadt: Option<Fadt>,
 
@@ -382,43 +224,294 @@ pub struct AcpiContext {
     // generate an index only for those.
     sdt_order: RwLock<Vec<Option<SdtSignature>>>,
 
+    /// The namespace built from the DSDT and SSDTs.
+    aml_context: Mutex<AmlContext>,
+
+    /// Problems found in the tables, with the table they concern if any, for `:acpi:report`.
+    warnings: Mutex<Vec<(Option<SdtSignature>, String)>>,
+
     pub next_ctx: RwLock<u64>,
 }
 
 impl AcpiContext {
     pub fn init(rxsdt_physaddrs: impl Iterator<Item = u64>) -> Self {
-        let tables = rxsdt_physaddrs
-            .map(|physaddr| {
-                let physaddr: usize = physaddr
-                    .try_into()
-                    .expect("expected ACPI addresses to be compatible with the current word size");
-
-                println!("TABLE AT {:#>08X}", physaddr);
-
-                Sdt::load_from_physical(physaddr).expect("failed to load physical SDT")
-            })
-            .collect::<Vec<Sdt>>();
-
         let mut this = Self {
-            tables,
+            tables: Vec::new(),
+            origins: Vec::new(),
+            quarantine: Vec::new(),
+            accept_bad_checksums: read_file(ACCEPT_BAD_CHECKSUMS).is_some(),
             dsdt: None,
             fadt: None,
 
             next_ctx: RwLock::new(0),
 
             sdt_order: RwLock::new(Vec::new()),
+
+            aml_context: Mutex::new(AmlContext::new(
+                Box::new(AmlHandler::new()),
+                DebugVerbosity::None,
+            )),
+
+            warnings: Mutex::new(Vec::new()),
         };
 
+        for physaddr in rxsdt_physaddrs {
+            let physaddr: usize = physaddr
+                .try_into()
+                .expect("expected ACPI addresses to be compatible with the current word size");
+
+            println!("TABLE AT {:#>08X}", physaddr);
+
+            this.load_table(physaddr);
+        }
+
         for table in &this.tables {
             this.new_index(&table.signature());
         }
 
-        Fadt::init(&mut this);
-        //TODO (hangs on real hardware): Dmar::init(&this);
+        init_fadt(&mut this);
+        this.apply_overrides();
+        this.check_tables();
+
+        this.load_aml();
 
         this
     }
 
+    /// Decodes the tables acpid interprets, recording what was wrong with them, and logs the
+    /// IOMMUs the DMAR and IVRS tables describe.
+    fn check_tables(&self) {
+        let signature = |name: &[u8; 4]| self.take_single_sdt(*name).map(|sdt| sdt.signature());
+
+        if let Some(madt) = self.madt() {
+            for warning in madt.warnings {
+                self.warn(signature(b"APIC"), warning);
+            }
+        }
+        if let Some(dmar) = self.dmar() {
+            for entry in &dmar.entries {
+                if let DmarEntry::Drhd {
+                    segment,
+                    register_base,
+                    ..
+                } = entry
+                {
+                    println!(
+                        "DMAR: remapping unit at {:#x}, segment {}",
+                        register_base, segment
+                    );
+                }
+            }
+            for warning in dmar.warnings {
+                self.warn(signature(b"DMAR"), warning);
+            }
+        }
+        if let Some(ivrs) = self.ivrs() {
+            for entry in &ivrs.entries {
+                if let IvrsEntry::Ivhd { base, segment, .. } = entry {
+                    println!("IVRS: IOMMU at {:#x}, segment {}", base, segment);
+                }
+            }
+            for warning in ivrs.warnings {
+                self.warn(signature(b"IVRS"), warning);
+            }
+        }
+        if let Some(srat) = self.srat() {
+            for warning in srat.warnings {
+                self.warn(signature(b"SRAT"), warning);
+            }
+        }
+        if let Some(slit) = self.slit() {
+            for warning in slit.warnings {
+                self.warn(signature(b"SLIT"), warning);
+            }
+        }
+        if let Some(fpdt) = self.fpdt() {
+            for warning in fpdt.warnings {
+                self.warn(signature(b"FPDT"), warning);
+            }
+        }
+    }
+
+    /// Loads the table at `physaddr` into the context and returns it. A table that cannot be
+    /// read or has an impossible length is skipped. One with a bad checksum is quarantined
+    /// instead, unless [`ACCEPT_BAD_CHECKSUMS`] exists, in which case it is used like any
+    /// other. Either way the problem is recorded.
+    fn load_table(&mut self, physaddr: usize) -> Option<Sdt> {
+        let sdt = match read_physical_table(physaddr).and_then(|bytes| {
+            Sdt::new_ignoring_checksum(bytes.into()).map_err(TablePhysLoadError::Validity)
+        }) {
+            Ok(sdt) => sdt,
+            Err(error) => {
+                self.warn(
+                    None,
+                    format!("skipped the table at {:#x}: {}", physaddr, error),
+                );
+                return None;
+            }
+        };
+
+        if !sdt.checksum_valid() {
+            if self.accept_bad_checksums {
+                self.warn(Some(sdt.signature()), "bad checksum, used anyway".into());
+            } else {
+                self.warn(Some(sdt.signature()), "bad checksum, quarantined".into());
+                self.quarantine.push((physaddr, sdt));
+                return None;
+            }
+        }
+
+        self.tables.push(sdt.clone());
+        self.origins.push(TableOrigin::Physical(physaddr));
+        Some(sdt)
+    }
+
+    /// Puts the tables found in [`OVERRIDE_DIR`] in place of the firmware's. An override
+    /// replaces the table with the same signature, OEM ID and OEM table ID; failing that,
+    /// for anything but an SSDT, the table with the same signature. Otherwise it is added.
+    /// Files that do not hold a valid table, checksum included, are reported and skipped.
+    fn apply_overrides(&mut self) {
+        let fd = rstd::fs::open(OVERRIDE_DIR, 0) as usize;
+        if fd == usize::MAX {
+            return;
+        }
+
+        let mut entries = rstd::fs::list_dir(fd);
+        entries.sort_by(|a, b| a.name.as_str().cmp(b.name.as_str()));
+
+        for entry in entries {
+            let path = format!("{}/{}", OVERRIDE_DIR, entry.name.as_str());
+            if path == ACCEPT_BAD_CHECKSUMS {
+                continue;
+            }
+
+            match read_file(&path).and_then(|bytes| Sdt::new(bytes.into()).ok()) {
+                Some(sdt) => self.override_table(path, sdt),
+                None => self.warn(
+                    None,
+                    format!("skipped the override {}: not a valid table", path),
+                ),
+            }
+        }
+    }
+
+    fn override_table(&mut self, path: String, sdt: Sdt) {
+        let signature = sdt.signature();
+
+        // A FADT override is checked before it replaces anything, so that the tables and
+        // `self.fadt` keep agreeing.
+        let fadt = match &sdt.signature {
+            b"FACP" => match Fadt::new(sdt.clone()) {
+                Some(fadt) => Some(fadt),
+                None => {
+                    self.warn(
+                        Some(signature),
+                        format!("skipped the override {}: too short for a FADT", path),
+                    );
+                    return;
+                }
+            },
+            _ => None,
+        };
+
+        let position = self
+            .tables
+            .iter()
+            .position(|table| table.signature() == signature)
+            .or_else(|| {
+                self.tables
+                    .iter()
+                    .position(|table| &sdt.signature != b"SSDT" && table.signature == sdt.signature)
+            });
+
+        match position {
+            Some(position) => {
+                println!(
+                    "acpid: {} overrides {}",
+                    path,
+                    self.tables[position].signature().file_name()
+                );
+                self.tables[position] = sdt.clone();
+                self.origins[position] = TableOrigin::Override(path);
+            }
+            None => {
+                println!("acpid: {} adds {}", path, signature.file_name());
+                self.tables.push(sdt.clone());
+                self.origins.push(TableOrigin::Override(path));
+            }
+        }
+        if self.get_index_from_signature(&signature).is_none() {
+            self.new_index(&signature);
+        }
+
+        // The FADT's DSDT pointer is not followed again: an override of the DSDT goes in
+        // its own file.
+        if let Some(fadt) = fadt {
+            self.fadt = Some(fadt);
+        }
+        if &sdt.signature == b"DSDT" {
+            self.dsdt = Some(Dsdt(sdt));
+        }
+    }
+
+    /// Tables left out for a bad checksum, with their physical addresses.
+    pub fn quarantine(&self) -> &[(usize, Sdt)] {
+        &self.quarantine
+    }
+
+    /// Logs a problem with the firmware's tables and keeps it for `:acpi:report`.
+    pub fn warn(&self, table: Option<SdtSignature>, message: String) {
+        match &table {
+            Some(signature) => println!("acpid: {}: {}", signature.file_name(), message),
+            None => println!("acpid: {}", message),
+        }
+        self.warnings.lock().push((table, message));
+    }
+
+    pub fn warnings(&self) -> &Mutex<Vec<(Option<SdtSignature>, String)>> {
+        &self.warnings
+    }
+
+    /// Where each table in [`Self::tables`] came from, in the same order.
+    pub fn origins(&self) -> &[TableOrigin] {
+        &self.origins
+    }
+
+    /// Parses the DSDT, then every SSDT, into the AML namespace. A table that fails to parse
+    /// is reported and skipped; what it defined before the error stays in the namespace.
+    /// Devices are then initialized through their `_STA` and `_INI`, so that methods can be
+    /// evaluated afterwards.
+    fn load_aml(&self) {
+        let mut aml_context = self.aml_context.lock();
+
+        let tables = self
+            .dsdt()
+            .map(|dsdt| PossibleAmlTables::Dsdt(Dsdt(dsdt.0.clone())))
+            .into_iter()
+            .chain(self.ssdts().map(PossibleAmlTables::Ssdt));
+
+        for table in tables {
+            match aml_context.parse_table(table.aml()) {
+                Ok(()) => println!("Loaded AML from {}", table.header().signature()),
+                Err(error) => self.warn(
+                    Some(table.header().signature()),
+                    format!("failed to parse AML: {:?}", error),
+                ),
+            }
+        }
+
+        if let Err(error) = aml_context.initialize_objects() {
+            self.warn(
+                None,
+                format!("failed to initialize AML objects: {:?}", error),
+            );
+        }
+    }
+
+    pub fn aml_context(&self) -> &Mutex<AmlContext> {
+        &self.aml_context
+    }
+
     pub fn dsdt(&self) -> Option<&Dsdt> {
         self.dsdt.as_ref()
     }
@@ -458,6 +551,48 @@ impl AcpiContext {
     pub fn fadt(&self) -> Option<&Fadt> {
         self.fadt.as_ref()
     }
+    pub fn madt(&self) -> Option<Madt> {
+        Madt::parse(&self.take_single_sdt(*b"APIC")?)
+    }
+    pub fn dmar(&self) -> Option<Dmar> {
+        Dmar::parse(&self.take_single_sdt(*b"DMAR")?)
+    }
+    pub fn ivrs(&self) -> Option<Ivrs> {
+        Ivrs::parse(&self.take_single_sdt(*b"IVRS")?)
+    }
+    pub fn hpet(&self) -> Option<Hpet> {
+        Hpet::parse(&self.take_single_sdt(*b"HPET")?)
+    }
+    pub fn srat(&self) -> Option<Srat> {
+        Srat::parse(&self.take_single_sdt(*b"SRAT")?)
+    }
+    pub fn slit(&self) -> Option<Slit> {
+        Slit::parse(&self.take_single_sdt(*b"SLIT")?)
+    }
+    /// The FPDT, with the records of the tables it points at. A pointed-to table that cannot
+    /// be read is reported in its warnings.
+    pub fn fpdt(&self) -> Option<Fpdt> {
+        let mut fpdt = Fpdt::parse(&self.take_single_sdt(*b"FPDT")?)?;
+
+        if let Some(address) = fpdt.fbpt {
+            match read_fpdt_table(address, b"FBPT") {
+                Some(fbpt) => fpdt.add_fbpt(&fbpt),
+                None => fpdt
+                    .warnings
+                    .push(format!("unreadable FBPT at {:#x}", address)),
+            }
+        }
+        if let Some(address) = fpdt.s3pt {
+            match read_fpdt_table(address, b"S3PT") {
+                Some(s3pt) => fpdt.add_s3pt(&s3pt),
+                None => fpdt
+                    .warnings
+                    .push(format!("unreadable S3PT at {:#x}", address)),
+            }
+        }
+
+        Some(fpdt)
+    }
     pub fn sdt_from_signature(&self, signature: &SdtSignature) -> Option<&Sdt> {
         self.tables.iter().find(|sdt| {
             sdt.signature == signature.signature
@@ -465,6 +600,12 @@ impl AcpiContext {
                 && sdt.oem_table_id == signature.oem_table_id
         })
     }
+    pub fn quarantined_from_signature(&self, signature: &SdtSignature) -> Option<&Sdt> {
+        self.quarantine
+            .iter()
+            .map(|(_, sdt)| sdt)
+            .find(|sdt| sdt.signature() == *signature)
+    }
     pub fn get_signature_from_index(&self, index: usize) -> Option<SdtSignature> {
         self.sdt_order.read().get(index).copied().flatten()
     }
@@ -481,65 +622,3 @@ impl AcpiContext {
         self.sdt_order.write().push(Some(*signature));
     }
 }
-
-pub enum PossibleAmlTables {
-    Dsdt(Dsdt),
-    Ssdt(Ssdt),
-}
-impl PossibleAmlTables {
-    pub fn try_new(inner: Sdt) -> Option<Self> {
-        match &inner.signature {
-            b"DSDT" => Some(Self::Dsdt(Dsdt(inner))),
-            b"SSDT" => Some(Self::Ssdt(Ssdt(inner))),
-            _ => None,
-        }
-    }
-}
-impl AmlContainingTable for PossibleAmlTables {
-    fn aml(&self) -> &[u8] {
-        match self {
-            Self::Dsdt(dsdt) => dsdt.aml(),
-            Self::Ssdt(ssdt) => ssdt.aml(),
-        }
-    }
-    fn header(&self) -> &SdtHeader {
-        match self {
-            Self::Dsdt(dsdt) => dsdt.header(),
-            Self::Ssdt(ssdt) => ssdt.header(),
-        }
-    }
-}
-
-pub trait AmlContainingTable {
-    fn aml(&self) -> &[u8];
-    fn header(&self) -> &SdtHeader;
-}
-
-impl<T> AmlContainingTable for &T
-where
-    T: AmlContainingTable,
-{
-    fn aml(&self) -> &[u8] {
-        T::aml(*self)
-    }
-    fn header(&self) -> &SdtHeader {
-        T::header(*self)
-    }
-}
-
-impl AmlContainingTable for Dsdt {
-    fn aml(&self) -> &[u8] {
-        self.0.data()
-    }
-    fn header(&self) -> &SdtHeader {
-        &*self.0
-    }
-}
-impl AmlContainingTable for Ssdt {
-    fn aml(&self) -> &[u8] {
-        self.0.data()
-    }
-    fn header(&self) -> &SdtHeader {
-        &*self.0
-    }
-}
@@ -0,0 +1,238 @@
+use core::ptr;
+
+use aml::{AmlValue, Handler};
+use rstd::alloc::collections::BTreeSet;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const PAGE_SIZE: usize = 4096;
+
+/// PCI configuration mechanism #1, the only one that needs no MCFG.
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+/// Writes to the POST code port take about a microsecond, which is the only clock acpid has.
+const DELAY_PORT: u16 = 0x80;
+
+/// Gives the AML interpreter access to physical memory, I/O ports and PCI configuration
+/// space on behalf of the firmware's methods.
+pub struct AmlHandler {
+    /// Pages mapped for operation regions so far. They stay mapped, as AML tends to access
+    /// the same registers over and over.
+    mapped: Mutex<BTreeSet<usize>>,
+}
+
+impl AmlHandler {
+    pub fn new() -> Self {
+        Self {
+            mapped: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn map(&self, address: usize, len: usize) -> *mut u8 {
+        let mut mapped = self.mapped.lock();
+
+        let first_page = address / PAGE_SIZE * PAGE_SIZE;
+        for page in (first_page..address + len).step_by(PAGE_SIZE) {
+            if mapped.insert(page) {
+                rstd::mm::physmap(page, page, PAGE_SIZE);
+            }
+        }
+
+        address as *mut u8
+    }
+
+    fn read<T: Copy>(&self, address: usize) -> T {
+        let virt = self.map(address, core::mem::size_of::<T>());
+        unsafe { ptr::read_volatile(virt as *const T) }
+    }
+
+    fn write<T: Copy>(&self, address: usize, value: T) {
+        let virt = self.map(address, core::mem::size_of::<T>());
+        unsafe { ptr::write_volatile(virt as *mut T, value) }
+    }
+
+    /// Reads the dword holding `offset`. Only segment 0 and the first 256 bytes of each
+    /// function are reachable; anything else reads as all ones, like an absent device.
+    fn read_pci(&self, function: PciFunction, offset: u16) -> u32 {
+        if function.segment != 0 || offset >= 256 {
+            return u32::MAX;
+        }
+
+        unsafe {
+            Port::<u32>::new(PCI_CONFIG_ADDRESS).write(function.config_address(offset));
+            Port::<u32>::new(PCI_CONFIG_DATA).read()
+        }
+    }
+
+    /// Writes the `mask` bits of the dword holding `offset`, keeping the others.
+    fn write_pci(&self, function: PciFunction, offset: u16, value: u32, mask: u32) {
+        if function.segment != 0 || offset >= 256 {
+            return;
+        }
+
+        let shift = u32::from(offset & 0x3) * 8;
+        let old = self.read_pci(function, offset);
+        let new = (old & !(mask << shift)) | ((value & mask) << shift);
+
+        unsafe {
+            Port::<u32>::new(PCI_CONFIG_ADDRESS).write(function.config_address(offset));
+            Port::<u32>::new(PCI_CONFIG_DATA).write(new);
+        }
+    }
+}
+
+/// A PCI function, as the AML interpreter addresses it.
+#[derive(Clone, Copy)]
+struct PciFunction {
+    segment: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl PciFunction {
+    fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// The value selecting the dword holding `offset` through `PCI_CONFIG_ADDRESS`.
+    fn config_address(self, offset: u16) -> u32 {
+        (1 << 31)
+            | (u32::from(self.bus) << 16)
+            | (u32::from(self.device & 0x1F) << 11)
+            | (u32::from(self.function & 0x7) << 8)
+            | u32::from(offset & 0xFC)
+    }
+}
+
+impl Handler for AmlHandler {
+    fn read_u8(&self, address: usize) -> u8 {
+        self.read(address)
+    }
+    fn read_u16(&self, address: usize) -> u16 {
+        self.read(address)
+    }
+    fn read_u32(&self, address: usize) -> u32 {
+        self.read(address)
+    }
+    fn read_u64(&self, address: usize) -> u64 {
+        self.read(address)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        self.write(address, value)
+    }
+    fn write_u16(&mut self, address: usize, value: u16) {
+        self.write(address, value)
+    }
+    fn write_u32(&mut self, address: usize, value: u32) {
+        self.write(address, value)
+    }
+    fn write_u64(&mut self, address: usize, value: u64) {
+        self.write(address, value)
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { Port::new(port).read() }
+    }
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) }
+    }
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { Port::new(port).write(value) }
+    }
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        (self.read_pci(PciFunction::new(segment, bus, device, function), offset)
+            >> ((offset & 0x3) * 8)) as u8
+    }
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        (self.read_pci(PciFunction::new(segment, bus, device, function), offset)
+            >> ((offset & 0x2) * 8)) as u16
+    }
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        self.read_pci(PciFunction::new(segment, bus, device, function), offset)
+    }
+
+    fn write_pci_u8(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
+    ) {
+        self.write_pci(
+            PciFunction::new(segment, bus, device, function),
+            offset,
+            value.into(),
+            0xFF,
+        )
+    }
+    fn write_pci_u16(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
+    ) {
+        self.write_pci(
+            PciFunction::new(segment, bus, device, function),
+            offset & !0x1,
+            value.into(),
+            0xFFFF,
+        )
+    }
+    fn write_pci_u32(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        self.write_pci(
+            PciFunction::new(segment, bus, device, function),
+            offset & !0x3,
+            value,
+            u32::MAX,
+        )
+    }
+
+    fn stall(&self, microseconds: u64) {
+        for _ in 0..microseconds {
+            unsafe { Port::<u8>::new(DELAY_PORT).write(0) };
+        }
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        for _ in 0..milliseconds {
+            self.stall(1000);
+            rstd::proc::r#yield();
+        }
+    }
+
+    fn handle_debug(&self, object: &AmlValue) {
+        println!("acpid: AML debug: {:?}", object);
+    }
+}
@@ -0,0 +1,380 @@
+//! Batteries, AC adapters and thermal zones, published under `:acpi:battery`, `:acpi:ac` and
+//! `:acpi:thermal`.
+//!
+//! The current state, `status`, is evaluated whenever the file is opened. The rest, `info`,
+//! only changes when the firmware says so with `Notify`, for which the aml crate gives no hook.
+//! Notifications come from GPE handlers, so `info` is read again whenever a GPE handler has
+//! run, and in between it is served from what was last read.
+
+use core::fmt::Write;
+
+use aml::{AmlContext, AmlName, AmlValue, Args, LevelType};
+use rstd::alloc::{format, string::String, vec::Vec};
+
+use crate::acpi::AcpiContext;
+
+const BATTERY_HID: &str = "PNP0C0A";
+const AC_ADAPTER_HID: &str = "ACPI0003";
+
+/// `_STA` bits: the device is present, and for a battery, a battery is in the slot.
+const STA_PRESENT: u64 = 1 << 0;
+const STA_BATTERY_PRESENT: u64 = 1 << 4;
+
+/// What `_BIF`, `_BIX` and `_BST` report for a value the battery does not know.
+const UNKNOWN: u64 = 0xFFFF_FFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Battery,
+    Ac,
+    Thermal,
+}
+
+impl Class {
+    /// The directory the devices of the class are listed in.
+    pub fn from_dir(dir: &str) -> Option<Self> {
+        match dir {
+            "battery" => Some(Self::Battery),
+            "ac" => Some(Self::Ac),
+            "thermal" => Some(Self::Thermal),
+            _ => None,
+        }
+    }
+}
+
+/// One device and the text of its `info` file, as last read.
+struct Device {
+    name: AmlName,
+    /// What does not change while the device is present: its path and, for a battery, its
+    /// design data, or for a thermal zone, its trip points.
+    info: String,
+}
+
+pub struct Devices {
+    batteries: Vec<Device>,
+    adapters: Vec<Device>,
+    zones: Vec<Device>,
+}
+
+impl Devices {
+    /// Finds the batteries and AC adapters by `_HID`, and the thermal zones, that are present,
+    /// and reads them.
+    pub fn discover(acpi_ctx: &AcpiContext) -> Self {
+        let mut aml_context = acpi_ctx.aml_context().lock();
+
+        let mut devices = Vec::new();
+        let mut zones = Vec::new();
+        let _ = aml_context.namespace.traverse(|name, level| {
+            match level.typ {
+                LevelType::Device => devices.push(name.clone()),
+                LevelType::ThermalZone => zones.push(name.clone()),
+                _ => {}
+            }
+            Ok(true)
+        });
+
+        let device = |name: AmlName| Device {
+            name,
+            info: String::new(),
+        };
+        let mut this = Self {
+            batteries: Vec::new(),
+            adapters: Vec::new(),
+            zones: zones.into_iter().map(device).collect(),
+        };
+
+        for name in devices {
+            if status(&mut aml_context, &name) & STA_PRESENT == 0 {
+                continue;
+            }
+            match hid(&mut aml_context, &name).as_deref() {
+                Some(BATTERY_HID) => this.batteries.push(device(name)),
+                Some(AC_ADAPTER_HID) => this.adapters.push(device(name)),
+                _ => {}
+            }
+        }
+
+        this.refresh_locked(&mut aml_context);
+
+        println!(
+            "acpid: {} batteries, {} AC adapters, {} thermal zones",
+            this.batteries.len(),
+            this.adapters.len(),
+            this.zones.len()
+        );
+
+        this
+    }
+
+    /// Reads the `info` of every device again.
+    pub fn refresh(&mut self, acpi_ctx: &AcpiContext) {
+        self.refresh_locked(&mut acpi_ctx.aml_context().lock());
+    }
+
+    fn refresh_locked(&mut self, aml_context: &mut AmlContext) {
+        for battery in &mut self.batteries {
+            battery.info = battery_info(aml_context, &battery.name);
+        }
+        for adapter in &mut self.adapters {
+            adapter.info = path_line(&adapter.name);
+        }
+        for zone in &mut self.zones {
+            zone.info = zone_info(aml_context, &zone.name);
+        }
+    }
+
+    fn class(&self, class: Class) -> &[Device] {
+        match class {
+            Class::Battery => &self.batteries,
+            Class::Ac => &self.adapters,
+            Class::Thermal => &self.zones,
+        }
+    }
+
+    /// The number of devices of `class`, which are numbered from 0.
+    pub fn count(&self, class: Class) -> usize {
+        self.class(class).len()
+    }
+
+    /// The text of `file`, `info` or `status`, of the `index`th device of `class`. `status`
+    /// is evaluated now.
+    pub fn file(
+        &self,
+        acpi_ctx: &AcpiContext,
+        class: Class,
+        index: usize,
+        file: &str,
+    ) -> Option<String> {
+        let device = self.class(class).get(index)?;
+        match file {
+            "info" => Some(device.info.clone()),
+            "status" => {
+                let aml_context = &mut acpi_ctx.aml_context().lock();
+                Some(match class {
+                    Class::Battery => battery_status(aml_context, &device.name),
+                    Class::Ac => adapter_status(aml_context, &device.name),
+                    Class::Thermal => zone_status(aml_context, &device.name),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The names of the files of every device.
+pub const FILES: [&str; 2] = ["info", "status"];
+
+/// Evaluates `object` below `scope`, or `None` if it does not exist or fails.
+fn evaluate(aml_context: &mut AmlContext, scope: &AmlName, object: &str) -> Option<AmlValue> {
+    let name = AmlName::from_str(object).ok()?.resolve(scope).ok()?;
+    aml_context.namespace.get_by_path(&name).ok()?;
+    aml_context.invoke_method(&name, Args::EMPTY).ok()
+}
+
+/// The `_STA` of a device, which is "present and working" when there is none.
+fn status(aml_context: &mut AmlContext, device: &AmlName) -> u64 {
+    evaluate(aml_context, device, "_STA")
+        .and_then(|sta| sta.as_integer(aml_context).ok())
+        .unwrap_or(0xF)
+}
+
+/// The `_HID` of a device as text, decoding the compressed EISA ID form.
+fn hid(aml_context: &mut AmlContext, device: &AmlName) -> Option<String> {
+    match evaluate(aml_context, device, "_HID")? {
+        AmlValue::Integer(id) => Some(eisa_id(id as u32)),
+        AmlValue::String(id) => Some(id),
+        _ => None,
+    }
+}
+
+/// Decodes a compressed EISA ID: three letters of five bits each and four hex digits, stored
+/// byte-swapped.
+fn eisa_id(id: u32) -> String {
+    let id = id.swap_bytes();
+    let letter = |shift: u32| char::from(b'@' + ((id >> shift) & 0x1F) as u8);
+    format!(
+        "{}{}{}{:04X}",
+        letter(26),
+        letter(21),
+        letter(16),
+        id & 0xFFFF
+    )
+}
+
+fn path_line(name: &AmlName) -> String {
+    format!("path={}\n", name.as_string())
+}
+
+/// The elements of a package as integers, with `None` for the elements that are not.
+fn integers(aml_context: &AmlContext, elements: &[AmlValue]) -> Vec<Option<u64>> {
+    elements
+        .iter()
+        .map(|element| element.as_integer(aml_context).ok())
+        .collect()
+}
+
+fn write_value(out: &mut String, key: &str, value: Option<u64>) {
+    match value {
+        Some(UNKNOWN) | None => writeln!(out, "{}=unknown", key),
+        Some(value) => writeln!(out, "{}={}", key, value),
+    }
+    .unwrap();
+}
+
+/// Renders `_BIX`, or `_BIF` on firmware without it. The two share their fields, but `_BIX`
+/// starts with a revision and adds a cycle count and measurement details after the low
+/// capacity. Capacities are in mWh or mAh and voltages in mV, as `unit` says.
+fn battery_info(aml_context: &mut AmlContext, battery: &AmlName) -> String {
+    let mut out = path_line(battery);
+
+    if status(aml_context, battery) & STA_BATTERY_PRESENT == 0 {
+        out.push_str("present=0\n");
+        return out;
+    }
+    out.push_str("present=1\n");
+
+    let (elements, extended) = match evaluate(aml_context, battery, "_BIX") {
+        Some(AmlValue::Package(elements)) => (elements, true),
+        _ => match evaluate(aml_context, battery, "_BIF") {
+            Some(AmlValue::Package(elements)) => (elements, false),
+            _ => return out,
+        },
+    };
+    // Past the revision, _BIX lines up with _BIF up to the low capacity.
+    let fields = if extended {
+        elements.get(1..).unwrap_or_default()
+    } else {
+        &elements[..]
+    };
+    let values = integers(aml_context, fields);
+    let value = |i: usize| values.get(i).copied().flatten();
+
+    writeln!(
+        out,
+        "unit={}",
+        match value(0) {
+            Some(0) => "mWh",
+            Some(1) => "mAh",
+            _ => "unknown",
+        }
+    )
+    .unwrap();
+    write_value(&mut out, "design_capacity", value(1));
+    write_value(&mut out, "last_full_capacity", value(2));
+    writeln!(
+        out,
+        "technology={}",
+        match value(3) {
+            Some(0) => "primary",
+            Some(1) => "rechargeable",
+            _ => "unknown",
+        }
+    )
+    .unwrap();
+    write_value(&mut out, "design_voltage", value(4));
+    write_value(&mut out, "warning_capacity", value(5));
+    write_value(&mut out, "low_capacity", value(6));
+
+    let strings_start = if extended {
+        write_value(&mut out, "cycle_count", value(7));
+        15
+    } else {
+        9
+    };
+    for (i, key) in ["model", "serial", "type", "oem"].into_iter().enumerate() {
+        let text = fields
+            .get(strings_start + i)
+            .and_then(|element| element.as_string(aml_context).ok())
+            .unwrap_or_default();
+        writeln!(out, "{}={}", key, text.trim_end_matches('\0')).unwrap();
+    }
+
+    out
+}
+
+/// Renders `_BST`: whether the battery is charging or discharging, the rate, the remaining
+/// capacity and the voltage, in the units of `info`.
+fn battery_status(aml_context: &mut AmlContext, battery: &AmlName) -> String {
+    let mut out = String::new();
+
+    if status(aml_context, battery) & STA_BATTERY_PRESENT == 0 {
+        out.push_str("present=0\n");
+        return out;
+    }
+    out.push_str("present=1\n");
+
+    let Some(AmlValue::Package(elements)) = evaluate(aml_context, battery, "_BST") else {
+        return out;
+    };
+    let values = integers(aml_context, &elements);
+    let value = |i: usize| values.get(i).copied().flatten();
+
+    let state = value(0).unwrap_or_default();
+    out.push_str("state=");
+    if state & 0x3 == 0 {
+        out.push_str("idle");
+    }
+    for (bit, name) in [(0, "discharging"), (1, "charging"), (2, "critical")] {
+        if state & (1 << bit) != 0 {
+            if !out.ends_with('=') {
+                out.push(',');
+            }
+            out.push_str(name);
+        }
+    }
+    out.push('\n');
+    write_value(&mut out, "rate", value(1));
+    write_value(&mut out, "remaining_capacity", value(2));
+    write_value(&mut out, "voltage", value(3));
+
+    out
+}
+
+/// Renders `_PSR`: 1 if the adapter supplies power.
+fn adapter_status(aml_context: &mut AmlContext, adapter: &AmlName) -> String {
+    let online =
+        evaluate(aml_context, adapter, "_PSR").and_then(|psr| psr.as_integer(aml_context).ok());
+    let mut out = String::new();
+    write_value(&mut out, "online", online);
+    out
+}
+
+/// Writes a temperature given, as ACPI does, in tenths of a kelvin, in degrees Celsius.
+fn write_temperature(out: &mut String, key: &str, decikelvin: Option<u64>) {
+    match decikelvin {
+        Some(decikelvin) => {
+            let decicelsius = decikelvin as i64 - 2732;
+            let sign = if decicelsius < 0 { "-" } else { "" };
+            writeln!(
+                out,
+                "{}={}{}.{}",
+                key,
+                sign,
+                decicelsius.abs() / 10,
+                decicelsius.abs() % 10
+            )
+        }
+        None => writeln!(out, "{}=unknown", key),
+    }
+    .unwrap();
+}
+
+/// Renders the trip points of a thermal zone that it has: critical, hot and passive.
+fn zone_info(aml_context: &mut AmlContext, zone: &AmlName) -> String {
+    let mut out = path_line(zone);
+    for (object, key) in [("_CRT", "critical"), ("_HOT", "hot"), ("_PSV", "passive")] {
+        if let Some(value) = evaluate(aml_context, zone, object) {
+            write_temperature(&mut out, key, value.as_integer(aml_context).ok());
+        }
+    }
+    out
+}
+
+/// Renders `_TMP`, the current temperature of a thermal zone.
+fn zone_status(aml_context: &mut AmlContext, zone: &AmlName) -> String {
+    let temperature =
+        evaluate(aml_context, zone, "_TMP").and_then(|tmp| tmp.as_integer(aml_context).ok());
+    let mut out = String::new();
+    write_temperature(&mut out, "temperature", temperature);
+    out
+}
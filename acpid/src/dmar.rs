@@ -0,0 +1,237 @@
+//! The DMA Remapping table, which describes Intel VT-d IOMMUs, decoded for `:acpi:dmar`.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    bytes::{u16_at, u64_at},
+    sdt::Sdt,
+};
+
+const DRHD: u16 = 0;
+const RMRR: u16 = 1;
+const ATSR: u16 = 2;
+
+/// DRHD flag: the unit covers every device of its segment not claimed by another unit.
+pub const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+/// ATSR flag: every root port of the segment supports ATS.
+pub const ATSR_ALL_PORTS: u8 = 1 << 0;
+
+/// Where the remapping structures start, past the host address width, flags and reserved
+/// bytes.
+const STRUCTURES_OFFSET: usize = 12;
+/// The smallest remapping structure or device scope: a type and a length.
+const MIN_STRUCTURE_LEN: usize = 4;
+const MIN_SCOPE_LEN: usize = 6;
+
+/// A device, bridge or other endpoint a remapping structure applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceScope {
+    /// 1 for a PCI endpoint, 2 for a bridge and everything below it, 3 for an I/O APIC,
+    /// 4 for an HPET and 5 for an ACPI namespace device.
+    pub typ: u8,
+    /// The I/O APIC ID, HPET number or ACPI device number, for those scope types.
+    pub enumeration_id: u8,
+    pub start_bus: u8,
+    /// The (device, function) hops from `start_bus` down to the device.
+    pub path: Vec<(u8, u8)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DmarEntry {
+    /// A remapping hardware unit.
+    Drhd {
+        flags: u8,
+        segment: u16,
+        register_base: u64,
+        scopes: Vec<DeviceScope>,
+    },
+    /// Memory the firmware keeps using for DMA from the listed devices, which has to stay
+    /// identity-mapped for them.
+    Rmrr {
+        segment: u16,
+        base: u64,
+        limit: u64,
+        scopes: Vec<DeviceScope>,
+    },
+    /// Root ports that support Address Translation Services.
+    Atsr {
+        flags: u8,
+        segment: u16,
+        scopes: Vec<DeviceScope>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Dmar {
+    /// The width of DMA addresses minus one, as stored in the table.
+    pub host_address_width: u8,
+    pub flags: u8,
+    pub entries: Vec<DmarEntry>,
+    /// Problems met while decoding. The structures they concern are left out.
+    pub warnings: Vec<String>,
+}
+
+/// Decodes the device scopes filling `bytes`. A scope running past the end ends the list.
+fn parse_scopes(mut bytes: &[u8], warnings: &mut Vec<String>) -> Vec<DeviceScope> {
+    let mut scopes = Vec::new();
+
+    while let [typ, len, ..] = *bytes {
+        let len = usize::from(len);
+        let Some(scope) = bytes.get(..len).filter(|_| len >= MIN_SCOPE_LEN) else {
+            warnings.push(format!("truncated DMAR device scope of type {}", typ));
+            break;
+        };
+        bytes = &bytes[len..];
+
+        scopes.push(DeviceScope {
+            typ,
+            enumeration_id: scope[4],
+            start_bus: scope[5],
+            path: scope[MIN_SCOPE_LEN..]
+                .chunks_exact(2)
+                .map(|hop| (hop[0], hop[1]))
+                .collect(),
+        });
+    }
+
+    scopes
+}
+
+fn decode_entry(typ: u16, entry: &[u8], warnings: &mut Vec<String>) -> Option<DmarEntry> {
+    Some(match typ {
+        DRHD => DmarEntry::Drhd {
+            flags: *entry.get(4)?,
+            segment: u16_at(entry, 6)?,
+            register_base: u64_at(entry, 8)?,
+            scopes: parse_scopes(entry.get(16..)?, warnings),
+        },
+        RMRR => DmarEntry::Rmrr {
+            segment: u16_at(entry, 6)?,
+            base: u64_at(entry, 8)?,
+            limit: u64_at(entry, 16)?,
+            scopes: parse_scopes(entry.get(24..)?, warnings),
+        },
+        ATSR => DmarEntry::Atsr {
+            flags: *entry.get(4)?,
+            segment: u16_at(entry, 6)?,
+            scopes: parse_scopes(entry.get(8..)?, warnings),
+        },
+        _ => return None,
+    })
+}
+
+impl Dmar {
+    /// Decodes a `DMAR` table. Structure types other than DRHD, RMRR and ATSR are skipped; a
+    /// structure running past the end of the table, or too short to hold its own header,
+    /// ends the list.
+    pub fn parse(sdt: &Sdt) -> Option<Self> {
+        if &sdt.signature != b"DMAR" {
+            return None;
+        }
+
+        let data = sdt.data();
+        let mut dmar = Self {
+            host_address_width: *data.first()?,
+            flags: *data.get(1)?,
+            entries: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        let mut rest = data.get(STRUCTURES_OFFSET..).unwrap_or_default();
+        while let (Some(typ), Some(len)) = (u16_at(rest, 0), u16_at(rest, 2)) {
+            let len = usize::from(len);
+            let Some(entry) = rest.get(..len).filter(|_| len >= MIN_STRUCTURE_LEN) else {
+                dmar.warnings
+                    .push(format!("truncated DMAR structure of type {}", typ));
+                break;
+            };
+            rest = &rest[len..];
+
+            if let DRHD | RMRR | ATSR = typ {
+                match decode_entry(typ, entry, &mut dmar.warnings) {
+                    Some(decoded) => dmar.entries.push(decoded),
+                    None => dmar
+                        .warnings
+                        .push(format!("short DMAR structure of type {}", typ)),
+                }
+            }
+        }
+
+        Some(dmar)
+    }
+
+    /// Renders the table as text in the format of `:acpi:madt`: one line per structure, and
+    /// one indented `scope` line per device scope below it.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "host_address_width={}",
+            u32::from(self.host_address_width) + 1
+        )
+        .unwrap();
+        writeln!(out, "flags={:#x}", self.flags).unwrap();
+
+        for entry in &self.entries {
+            let scopes = match entry {
+                DmarEntry::Drhd {
+                    flags,
+                    segment,
+                    register_base,
+                    scopes,
+                } => {
+                    writeln!(
+                        out,
+                        "drhd segment={} base={:#x} flags={:#x}",
+                        segment, register_base, flags
+                    )
+                    .unwrap();
+                    scopes
+                }
+                DmarEntry::Rmrr {
+                    segment,
+                    base,
+                    limit,
+                    scopes,
+                } => {
+                    writeln!(
+                        out,
+                        "rmrr segment={} base={:#x} limit={:#x}",
+                        segment, base, limit
+                    )
+                    .unwrap();
+                    scopes
+                }
+                DmarEntry::Atsr {
+                    flags,
+                    segment,
+                    scopes,
+                } => {
+                    writeln!(out, "atsr segment={} flags={:#x}", segment, flags).unwrap();
+                    scopes
+                }
+            };
+
+            for scope in scopes {
+                write!(
+                    out,
+                    "  scope type={} id={} bus={} path=",
+                    scope.typ, scope.enumeration_id, scope.start_bus
+                )
+                .unwrap();
+                for (i, (device, function)) in scope.path.iter().enumerate() {
+                    if i != 0 {
+                        out.push('/');
+                    }
+                    write!(out, "{:02x}.{}", device, function).unwrap();
+                }
+                out.push('\n');
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,388 @@
+//! ACPI mode, fixed events and general-purpose events, published on `:acpi:events`.
+//!
+//! rstd has no way to wait for an interrupt, so the SCI is not taken. Instead the status
+//! registers that would raise it are polled from acpid's main loop, at most every
+//! [`POLL_INTERVAL_MS`] as told by the PM timer, and the events are handled the way the SCI
+//! handler would. Events are thus seen up to that much later than the SCI would report them.
+
+use core::fmt::Write;
+
+use aml::{AmlName, Args, Handler};
+use rstd::alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+
+use crate::{
+    acpi::{AcpiContext, Fadt},
+    aml_handler::AmlHandler,
+    register::Register,
+};
+
+/// FADT flags: the power and sleep buttons are control-method devices rather than fixed
+/// events.
+const PWR_BUTTON: u32 = 1 << 4;
+const SLP_BUTTON: u32 = 1 << 5;
+
+const SCI_EN: u16 = 1 << 0;
+
+const PWRBTN: u16 = 1 << 8;
+const SLPBTN: u16 = 1 << 9;
+
+/// FADT flag: the PM timer counts on 32 bits rather than 24.
+const TMR_VAL_EXT: u32 = 1 << 8;
+
+/// The frequency of the PM timer.
+const PM_TIMER_HZ: u32 = 3_579_545;
+
+/// How often the status registers are read.
+const POLL_INTERVAL_MS: u32 = 100;
+const POLL_INTERVAL_TICKS: u32 = PM_TIMER_HZ / 1000 * POLL_INTERVAL_MS;
+
+/// Without a PM timer, the status registers are read once every this many main loop
+/// iterations instead.
+const POLL_ITERATIONS: u32 = 1024;
+
+/// How long to wait for the firmware to hand over to ACPI mode.
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 3000;
+
+/// Events not read yet are dropped past this many bytes, rather than growing without bound
+/// when nobody listens.
+const MAX_PENDING: usize = 4096;
+
+/// One GPE block: its status bytes, followed by as many enable bytes.
+struct GpeBlock {
+    /// The status and enable register of each byte of the block.
+    registers: Vec<(Register, Register)>,
+    /// The GPE number of bit 0 of the first status byte.
+    base: u16,
+}
+
+/// The status and enable register of each of the `len` bytes of the event block at `block`,
+/// unless the block runs past the end of its address space.
+fn split_block(block: Register, len: u16) -> Option<Vec<(Register, Register)>> {
+    (0..len)
+        .map(|i| Some((block.offset(i)?, block.offset(len.checked_add(i)?)?)))
+        .collect()
+}
+
+/// The handler method of a GPE and whether the GPE is edge-triggered (`_Exx`) or
+/// level-triggered (`_Lxx`).
+struct GpeMethod {
+    name: AmlName,
+    edge: bool,
+}
+
+pub struct Events {
+    handler: AmlHandler,
+    /// The status and enable registers of the PM1a and PM1b event blocks.
+    pm1: Vec<(Register, Register)>,
+    /// The fixed events enabled.
+    fixed: u16,
+    gpe_blocks: Vec<GpeBlock>,
+    gpe_methods: BTreeMap<u16, GpeMethod>,
+    /// Text lines, one per event, not read yet.
+    pending: Vec<u8>,
+    /// The PM timer and the mask of its valid bits.
+    timer: Option<(Register, u32)>,
+    /// The PM timer value, or the main loop iteration, of the last poll.
+    last_poll: u32,
+}
+
+impl Events {
+    /// Switches the system to ACPI mode, then enables the fixed button events and every GPE
+    /// with a handler method.
+    pub fn init(acpi_ctx: &AcpiContext) -> Self {
+        let mut this = Self {
+            handler: AmlHandler::new(),
+            pm1: Vec::new(),
+            fixed: 0,
+            gpe_blocks: Vec::new(),
+            gpe_methods: BTreeMap::new(),
+            pending: Vec::new(),
+            timer: None,
+            last_poll: 0,
+        };
+
+        let Some(fadt) = acpi_ctx.fadt() else {
+            return this;
+        };
+
+        this.enable_acpi(fadt);
+
+        let extended = fadt.acpi_2_struct();
+        let pm1_len = u16::from(fadt.pm1_event_length) / 2;
+        this.pm1 = [
+            extended
+                .and_then(|fadt2| Register::from_gas(&fadt2.x_pm1a_event_block))
+                .or_else(|| Register::from_port(fadt.pm1a_event_block)),
+            extended
+                .and_then(|fadt2| Register::from_gas(&fadt2.x_pm1b_event_block))
+                .or_else(|| Register::from_port(fadt.pm1b_event_block)),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|block| {
+            // Both registers are 16-bit, so the enable register's second byte must fit too.
+            block.offset(pm1_len.checked_add(1)?)?;
+            Some((block, block.offset(pm1_len)?))
+        })
+        .collect();
+
+        if fadt.flags & PWR_BUTTON == 0 {
+            this.fixed |= PWRBTN;
+        }
+        if fadt.flags & SLP_BUTTON == 0 {
+            this.fixed |= SLPBTN;
+        }
+        for &(status, enable) in &this.pm1 {
+            // The status bits are write-one-to-clear: drop anything stale before enabling.
+            status.write_u16(&mut this.handler, this.fixed);
+            let value = enable.read_u16(&this.handler);
+            enable.write_u16(&mut this.handler, value | this.fixed);
+        }
+
+        let gpe0 = extended
+            .and_then(|fadt2| Register::from_gas(&fadt2.x_gpe0_block))
+            .or_else(|| Register::from_port(fadt.gpe0_block));
+        let gpe1 = extended
+            .and_then(|fadt2| Register::from_gas(&fadt2.x_gpe1_block))
+            .or_else(|| Register::from_port(fadt.gpe1_block));
+        for (block, len, base) in [
+            (gpe0, fadt.gpe0_ength, 0),
+            (gpe1, fadt.gpe1_length, fadt.gpe1_base),
+        ] {
+            let Some(block) = block else {
+                continue;
+            };
+            match split_block(block, u16::from(len) / 2) {
+                Some(registers) => this.gpe_blocks.push(GpeBlock {
+                    registers,
+                    base: base.into(),
+                }),
+                None => println!("acpid: GPE block {:?} runs past its address space", block),
+            }
+        }
+
+        this.timer = extended
+            .and_then(|fadt2| Register::from_gas(&fadt2.x_pm_timer_block))
+            .or_else(|| Register::from_port(fadt.pm_timer_block))
+            .filter(|_| fadt.pm_timer_length == 4)
+            .map(|timer| {
+                let mask = if fadt.flags & TMR_VAL_EXT != 0 {
+                    u32::MAX
+                } else {
+                    0x00FF_FFFF
+                };
+                (timer, mask)
+            });
+        if let Some((timer, mask)) = this.timer {
+            this.last_poll = timer.read_u32(&this.handler) & mask;
+        }
+
+        this.find_gpe_methods(acpi_ctx);
+        this.enable_gpes();
+
+        println!(
+            "acpid: SCI {}, {} GPE handlers",
+            { fadt.sci_interrupt },
+            this.gpe_methods.len()
+        );
+
+        this
+    }
+
+    /// Asks the firmware to hand the fixed hardware over, unless it already has.
+    fn enable_acpi(&mut self, fadt: &Fadt) {
+        let Some(pm1a_control) = fadt
+            .acpi_2_struct()
+            .and_then(|fadt2| Register::from_gas(&fadt2.x_pm1a_control_block))
+            .or_else(|| Register::from_port(fadt.pm1a_control_block))
+        else {
+            return;
+        };
+
+        if pm1a_control.read_u16(&self.handler) & SCI_EN != 0 {
+            return;
+        }
+
+        let (smi_command, acpi_enable) = (fadt.smi_command_port, fadt.acpi_enable);
+        let Ok(smi_command) = u16::try_from(smi_command) else {
+            return;
+        };
+        if smi_command == 0 || acpi_enable == 0 {
+            // Hardware-reduced, or ACPI-only: there is no legacy mode to leave.
+            return;
+        }
+
+        self.handler.write_io_u8(smi_command, acpi_enable);
+        for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+            if pm1a_control.read_u16(&self.handler) & SCI_EN != 0 {
+                println!("acpid: enabled ACPI mode");
+                return;
+            }
+            self.handler.sleep(1);
+        }
+        println!("acpid: timed out enabling ACPI mode");
+    }
+
+    /// Records the `\_GPE._Exx` and `\_GPE._Lxx` methods of the GPEs in our blocks.
+    fn find_gpe_methods(&mut self, acpi_ctx: &AcpiContext) {
+        let aml_context = acpi_ctx.aml_context().lock();
+
+        for block in &self.gpe_blocks {
+            for gpe in block.base..block.base + block.registers.len() as u16 * 8 {
+                // Method names only have room for two hex digits.
+                if gpe > 0xFF {
+                    break;
+                }
+
+                for (prefix, edge) in [("_E", true), ("_L", false)] {
+                    let Ok(name) = AmlName::from_str(&format!("\\_GPE.{}{:02X}", prefix, gpe))
+                    else {
+                        continue;
+                    };
+                    if aml_context.namespace.get_by_path(&name).is_ok() {
+                        self.gpe_methods.insert(gpe, GpeMethod { name, edge });
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Enables exactly the GPEs that have a handler method, after clearing their status.
+    fn enable_gpes(&mut self) {
+        for block in &self.gpe_blocks {
+            for (i, &(status, enable_register)) in block.registers.iter().enumerate() {
+                let mut enable = 0;
+                for bit in 0..8 {
+                    if self
+                        .gpe_methods
+                        .contains_key(&(block.base + i as u16 * 8 + bit))
+                    {
+                        enable |= 1 << bit;
+                    }
+                }
+
+                status.write_u8(&mut self.handler, enable);
+                enable_register.write_u8(&mut self.handler, enable);
+            }
+        }
+    }
+
+    /// Whether the status registers are due to be read again.
+    fn due(&mut self) -> bool {
+        match self.timer {
+            Some((timer, mask)) => {
+                let now = timer.read_u32(&self.handler) & mask;
+                if now.wrapping_sub(self.last_poll) & mask < POLL_INTERVAL_TICKS {
+                    return false;
+                }
+                self.last_poll = now;
+                true
+            }
+            None => {
+                self.last_poll = self.last_poll.wrapping_add(1);
+                self.last_poll.is_multiple_of(POLL_ITERATIONS)
+            }
+        }
+    }
+
+    /// Handles whatever events are pending, if they are due to be checked. Called from the
+    /// main loop. Returns whether a GPE handler ran, as that is what notifies devices of
+    /// changes.
+    pub fn poll(&mut self, acpi_ctx: &AcpiContext) -> bool {
+        if !self.due() {
+            return false;
+        }
+
+        let mut handled_gpe = false;
+
+        for i in 0..self.pm1.len() {
+            let (status_register, enable_register) = self.pm1[i];
+            let status = status_register.read_u16(&self.handler);
+            let enable = enable_register.read_u16(&self.handler);
+
+            let fired = status & enable & self.fixed;
+            if fired == 0 {
+                continue;
+            }
+            status_register.write_u16(&mut self.handler, fired);
+
+            if fired & PWRBTN != 0 {
+                self.publish(format_args!("power_button"));
+            }
+            if fired & SLPBTN != 0 {
+                self.publish(format_args!("sleep_button"));
+            }
+        }
+
+        for b in 0..self.gpe_blocks.len() {
+            let base = self.gpe_blocks[b].base;
+
+            for i in 0..self.gpe_blocks[b].registers.len() {
+                let (status_register, enable_register) = self.gpe_blocks[b].registers[i];
+                let status = status_register.read_u8(&self.handler);
+                let enable = enable_register.read_u8(&self.handler);
+
+                let fired = status & enable;
+                for bit in (0..8).filter(|bit| fired & (1 << bit) != 0) {
+                    let gpe = base + i as u16 * 8 + bit;
+                    self.dispatch_gpe(acpi_ctx, status_register, 1 << bit, gpe);
+                    handled_gpe = true;
+                }
+            }
+        }
+
+        handled_gpe
+    }
+
+    /// Runs the handler of `gpe`, whose status bit is `mask` in `status`. Edge events are
+    /// acknowledged before the method runs so that a new edge is not lost; level events only
+    /// after, once the method has dealt with the source.
+    fn dispatch_gpe(&mut self, acpi_ctx: &AcpiContext, status: Register, mask: u8, gpe: u16) {
+        let Some(method) = self.gpe_methods.get(&gpe) else {
+            status.write_u8(&mut self.handler, mask);
+            return;
+        };
+
+        if method.edge {
+            status.write_u8(&mut self.handler, mask);
+        }
+        if let Err(error) = acpi_ctx
+            .aml_context()
+            .lock()
+            .invoke_method(&method.name, Args::EMPTY)
+        {
+            println!("acpid: GPE {:#x} handler failed: {:?}", gpe, error);
+        }
+        if !method.edge {
+            status.write_u8(&mut self.handler, mask);
+        }
+
+        self.publish(format_args!("gpe {:#x}", gpe));
+    }
+
+    fn publish(&mut self, event: core::fmt::Arguments) {
+        let mut line = String::new();
+        line.write_fmt(event).unwrap();
+        line.push('\n');
+
+        if self.pending.len() + line.len() > MAX_PENDING {
+            println!("acpid: event queue full, dropping {}", line.trim_end());
+            return;
+        }
+        self.pending.extend_from_slice(line.as_bytes());
+    }
+
+    /// The number of bytes of events waiting to be read.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Moves as many pending bytes as fit into `buf`, oldest first.
+    pub fn take(&mut self, buf: &mut [u8]) -> usize {
+        let len = core::cmp::min(buf.len(), self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        len
+    }
+}
@@ -0,0 +1,226 @@
+//! The Firmware Performance Data Table, decoded for `:acpi:fpdt`.
+//!
+//! The FPDT itself only points at the tables holding the records, the Firmware Basic Boot
+//! Performance Table and the S3 Performance Table, which are not ACPI tables. Those are read
+//! from physical memory by the daemon and decoded here.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    bytes::{u16_at, u32_at, u64_at},
+    sdt::Sdt,
+};
+
+const FBPT_POINTER: u16 = 0;
+const S3PT_POINTER: u16 = 1;
+
+const BASIC_BOOT: u16 = 2;
+const S3_RESUME: u16 = 0;
+const S3_SUSPEND: u16 = 1;
+
+/// The size of the header of the FPDT's records and those of the tables it points at: a
+/// type, a length and a revision.
+const RECORD_HEADER_LEN: usize = 4;
+/// The size of the header of the pointed-to tables: a signature and a length.
+pub const TABLE_HEADER_LEN: usize = 8;
+
+/// A performance record. Times are in nanoseconds; timestamps count from reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FpdtRecord {
+    /// When the firmware handed over to the OS loader, and when the loader left boot
+    /// services.
+    BasicBoot {
+        reset_end: u64,
+        load_image_start: u64,
+        start_image_start: u64,
+        exit_boot_services_entry: u64,
+        exit_boot_services_exit: u64,
+    },
+    /// How long the last resume from S3 took, and the average over `count` resumes.
+    S3Resume { count: u32, full: u64, average: u64 },
+    /// When the last suspend to S3 started and ended.
+    S3Suspend { start: u64, end: u64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Fpdt {
+    /// The physical address of the Firmware Basic Boot Performance Table.
+    pub fbpt: Option<u64>,
+    /// The physical address of the S3 Performance Table.
+    pub s3pt: Option<u64>,
+    pub records: Vec<FpdtRecord>,
+    /// Problems met while decoding. The records they concern are left out.
+    pub warnings: Vec<String>,
+}
+
+/// Calls `f` with the type and bytes of each record filling `bytes`. A record running past
+/// the end, or too short to hold its own header, ends the list.
+fn for_each_record(
+    mut bytes: &[u8],
+    table: &str,
+    warnings: &mut Vec<String>,
+    mut f: impl FnMut(u16, &[u8]),
+) {
+    while let (Some(typ), Some(&len)) = (u16_at(bytes, 0), bytes.get(2)) {
+        let len = usize::from(len);
+        let Some(record) = bytes.get(..len).filter(|_| len >= RECORD_HEADER_LEN) else {
+            warnings.push(format!("truncated {} record of type {}", table, typ));
+            break;
+        };
+        bytes = &bytes[len..];
+
+        f(typ, record);
+    }
+}
+
+fn decode_record(typ: u16, record: &[u8]) -> Option<FpdtRecord> {
+    Some(match typ {
+        BASIC_BOOT => FpdtRecord::BasicBoot {
+            reset_end: u64_at(record, 8)?,
+            load_image_start: u64_at(record, 16)?,
+            start_image_start: u64_at(record, 24)?,
+            exit_boot_services_entry: u64_at(record, 32)?,
+            exit_boot_services_exit: u64_at(record, 40)?,
+        },
+        S3_RESUME => FpdtRecord::S3Resume {
+            count: u32_at(record, 4)?,
+            full: u64_at(record, 8)?,
+            average: u64_at(record, 16)?,
+        },
+        S3_SUSPEND => FpdtRecord::S3Suspend {
+            start: u64_at(record, 4)?,
+            end: u64_at(record, 12)?,
+        },
+        _ => return None,
+    })
+}
+
+/// The total length a pointed-to table's header gives, if `header` is one with `signature`.
+pub fn table_length(header: &[u8], signature: &[u8; 4]) -> Option<usize> {
+    if header.get(..4)? != signature {
+        return None;
+    }
+    usize::try_from(u32_at(header, 4)?).ok()
+}
+
+impl Fpdt {
+    /// Decodes an `FPDT` table into the addresses of the tables it points at. Their records
+    /// are added with [`Self::add_fbpt`] and [`Self::add_s3pt`].
+    pub fn parse(sdt: &Sdt) -> Option<Self> {
+        if &sdt.signature != b"FPDT" {
+            return None;
+        }
+
+        let mut fpdt = Self {
+            fbpt: None,
+            s3pt: None,
+            records: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        let mut pointers = Vec::new();
+        for_each_record(sdt.data(), "FPDT", &mut fpdt.warnings, |typ, record| {
+            pointers.push((typ, u64_at(record, 8)))
+        });
+        for (typ, address) in pointers {
+            match (typ, address) {
+                (FBPT_POINTER, Some(address)) => fpdt.fbpt = Some(address),
+                (S3PT_POINTER, Some(address)) => fpdt.s3pt = Some(address),
+                (FBPT_POINTER | S3PT_POINTER, None) => fpdt
+                    .warnings
+                    .push(format!("short FPDT record of type {}", typ)),
+                _ => {}
+            }
+        }
+
+        Some(fpdt)
+    }
+
+    /// Adds the records of the Firmware Basic Boot Performance Table, `table` being all of it.
+    pub fn add_fbpt(&mut self, table: &[u8]) {
+        self.add_records(table, b"FBPT", &[BASIC_BOOT]);
+    }
+
+    /// Adds the records of the S3 Performance Table, `table` being all of it.
+    pub fn add_s3pt(&mut self, table: &[u8]) {
+        self.add_records(table, b"S3PT", &[S3_RESUME, S3_SUSPEND]);
+    }
+
+    fn add_records(&mut self, table: &[u8], signature: &[u8; 4], types: &[u16]) {
+        let name = String::from_utf8_lossy(signature);
+        if table_length(table, signature) != Some(table.len()) {
+            self.warnings.push(format!("bad {} header", name));
+            return;
+        }
+
+        let mut records = Vec::new();
+        for_each_record(
+            &table[TABLE_HEADER_LEN..],
+            &name,
+            &mut self.warnings,
+            |typ, record| {
+                if types.contains(&typ) {
+                    records.push((typ, decode_record(typ, record)));
+                }
+            },
+        );
+        for (typ, record) in records {
+            match record {
+                Some(record) => self.records.push(record),
+                None => self
+                    .warnings
+                    .push(format!("short {} record of type {}", name, typ)),
+            }
+        }
+    }
+
+    /// Renders the table as text: the addresses of the pointed-to tables, then one line per
+    /// record with its times in nanoseconds.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        if let Some(fbpt) = self.fbpt {
+            writeln!(out, "fbpt={:#x}", fbpt).unwrap();
+        }
+        if let Some(s3pt) = self.s3pt {
+            writeln!(out, "s3pt={:#x}", s3pt).unwrap();
+        }
+
+        for record in &self.records {
+            match *record {
+                FpdtRecord::BasicBoot {
+                    reset_end,
+                    load_image_start,
+                    start_image_start,
+                    exit_boot_services_entry,
+                    exit_boot_services_exit,
+                } => writeln!(
+                    out,
+                    "boot reset_end={} load_image_start={} start_image_start={} exit_boot_services_entry={} exit_boot_services_exit={}",
+                    reset_end,
+                    load_image_start,
+                    start_image_start,
+                    exit_boot_services_entry,
+                    exit_boot_services_exit
+                ),
+                FpdtRecord::S3Resume {
+                    count,
+                    full,
+                    average,
+                } => writeln!(
+                    out,
+                    "s3_resume count={} full={} average={}",
+                    count, full, average
+                ),
+                FpdtRecord::S3Suspend { start, end } => {
+                    writeln!(out, "s3_suspend start={} end={}", start, end)
+                }
+            }
+            .unwrap();
+        }
+
+        out
+    }
+}
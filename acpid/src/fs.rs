@@ -1,107 +1,138 @@
+//! The `:acpi` scheme: tables, the AML namespace, decoded tables, events, power control and
+//! devices.
+//!
+//! The kernel does not give `USER_OPEN` a way to return a handle ID: it tags every later
+//! request with the path it was opened with, and that is all acpid sees. Handles are
+//! therefore per path, not per open. Clients opening the same path share its handle, and
+//! opening a rendered file such as an evaluation or a device status again renders it anew
+//! for everyone reading it, so a size read before that may no longer match.
+
+use acpid::sdt::parse_table;
+use aml::AmlName;
 use rstd::{
-    alloc::{string::String, vec::Vec},
-    fs::{USER_LIST, USER_OPEN, USER_READ, USER_SIZE, UserCommand},
+    alloc::{
+        collections::{BTreeMap, VecDeque},
+        string::{String, ToString},
+        vec::Vec,
+    },
+    fs::{USER_LIST, USER_OPEN, USER_READ, USER_SIZE, USER_WRITE, UserCommand},
 };
 use spin::Mutex;
 
-use crate::acpi::{AcpiContext, SdtSignature};
-
-fn parse_hex_digit(hex: u8) -> Option<u8> {
-    let hex = hex.to_ascii_lowercase();
-
-    if hex >= b'a' && hex <= b'f' {
-        Some(hex - b'a' + 10)
-    } else if hex >= b'0' && hex <= b'9' {
-        Some(hex - b'0')
-    } else {
-        None
-    }
-}
-
-fn parse_hex_2digit(hex: &[u8]) -> Option<u8> {
-    parse_hex_digit(hex[0])
-        .and_then(|most_significant| Some((most_significant << 4) | parse_hex_digit(hex[1])?))
-}
-
-fn parse_oem_id(hex: [u8; 12]) -> Option<[u8; 6]> {
-    Some([
-        parse_hex_2digit(&hex[0..2])?,
-        parse_hex_2digit(&hex[2..4])?,
-        parse_hex_2digit(&hex[4..6])?,
-        parse_hex_2digit(&hex[6..8])?,
-        parse_hex_2digit(&hex[8..10])?,
-        parse_hex_2digit(&hex[10..12])?,
-    ])
-}
-fn parse_oem_table_id(hex: [u8; 16]) -> Option<[u8; 8]> {
-    Some([
-        parse_hex_2digit(&hex[0..2])?,
-        parse_hex_2digit(&hex[2..4])?,
-        parse_hex_2digit(&hex[4..6])?,
-        parse_hex_2digit(&hex[6..8])?,
-        parse_hex_2digit(&hex[8..10])?,
-        parse_hex_2digit(&hex[10..12])?,
-        parse_hex_2digit(&hex[12..14])?,
-        parse_hex_2digit(&hex[14..16])?,
-    ])
-}
-
-fn parse_table(table: &[u8]) -> Option<SdtSignature> {
-    let signature_part = table.get(..4)?;
-    let first_hyphen = table.get(4)?;
-    let oem_id_part = table.get(5..17)?;
-    let second_hyphen = table.get(17)?;
-    let oem_table_part = table.get(18..34)?;
-
-    if *first_hyphen != b'-' {
-        return None;
-    }
-    if *second_hyphen != b'-' {
-        return None;
-    }
-
-    if table.len() > 34 {
-        return None;
-    }
-
-    Some(SdtSignature {
-        signature: <[u8; 4]>::try_from(signature_part)
-            .expect("expected 4-byte slice to be convertible into [u8; 4]"),
-        oem_id: {
-            let hex = <[u8; 12]>::try_from(oem_id_part)
-                .expect("expected 12-byte slice to be convertible into [u8; 12]");
-            parse_oem_id(hex)?
-        },
-        oem_table_id: {
-            let hex = <[u8; 16]>::try_from(oem_table_part)
-                .expect("expected 16-byte slice to be convertible into [u8; 16]");
-            parse_oem_table_id(hex)?
-        },
-    })
-}
+use crate::{
+    acpi::{AcpiContext, SdtSignature},
+    devices::{self, Class, Devices},
+    events::Events,
+    namespace, power, report,
+};
 
 enum AcpiHandle {
-    NoHandle,
     TopLevel,
     Tables,
     Table(SdtSignature),
+    /// Tables left out for a bad checksum, readable for diagnosis.
+    Quarantine,
+    QuarantinedTable(SdtSignature),
+    /// An object or scope in the AML namespace. Reading it describes the object, listing it
+    /// names the objects below it.
+    Namespace(AmlName),
+    /// The result of evaluating an object, rendered when the handle was opened so that its
+    /// size and contents agree. At most [`MAX_RENDERED`] such handles and [`Self::DeviceFile`]
+    /// ones are kept.
+    Evaluation(String),
+    /// The MADT, decoded as text.
+    Madt,
+    /// The DMAR (Intel) or IVRS (AMD) IOMMU description, decoded as text.
+    Dmar,
+    Ivrs,
+    /// The HPET, SRAT, SLIT and FPDT, decoded as text.
+    Hpet,
+    Srat,
+    Slit,
+    Fpdt,
+    /// Every table with its address, revision, length, checksum status and warnings.
+    Report,
+    /// Fixed and general-purpose events, one line each. Reading consumes them.
+    Events,
+    Power,
+    /// Writing `1` to it powers the system off. Anything else is refused, so a stray write
+    /// cannot take the machine down.
+    Shutdown,
+    /// Writing `1` to it resets the system.
+    Reboot,
+    /// The batteries, AC adapters or thermal zones, numbered from 0.
+    Devices(Class),
+    Device(Class, usize),
+    /// A file of a device, rendered when the handle was opened.
+    DeviceFile(String),
 }
 
+/// The most handles holding a rendered result that are kept open. Clients never close
+/// handles, and every distinct evaluation path opens a new one, so past this the oldest are
+/// dropped, even if a client is still reading it. Its reads then fail until it opens the
+/// path again.
+const MAX_RENDERED: usize = 64;
+
 impl AcpiHandle {
     fn len(&self, acpi_ctx: &AcpiContext) -> Result<usize, ()> {
         Ok(match self {
             // Files
             Self::Table(signature) => acpi_ctx.sdt_from_signature(signature).ok_or(())?.length(),
+            Self::QuarantinedTable(signature) => acpi_ctx
+                .quarantined_from_signature(signature)
+                .ok_or(())?
+                .length(),
+            Self::Namespace(name) => namespace::describe(&mut acpi_ctx.aml_context().lock(), name)
+                .ok_or(())?
+                .len(),
+            Self::Evaluation(result) | Self::DeviceFile(result) => result.len(),
+            Self::Madt
+            | Self::Dmar
+            | Self::Ivrs
+            | Self::Hpet
+            | Self::Srat
+            | Self::Slit
+            | Self::Fpdt
+            | Self::Report => self.decoded(acpi_ctx).ok_or(())?.len(),
+            Self::Shutdown | Self::Reboot | Self::Events => 0,
             // Directories
-            Self::TopLevel | Self::NoHandle | Self::Tables => 0,
+            Self::TopLevel
+            | Self::Tables
+            | Self::Quarantine
+            | Self::Power
+            | Self::Devices(_)
+            | Self::Device(..) => 0,
         })
     }
+
+    /// The text of the handles showing a decoded table or the report, or `None` if the
+    /// firmware has no such table or it is malformed.
+    fn decoded(&self, acpi_ctx: &AcpiContext) -> Option<String> {
+        match self {
+            Self::Madt => Some(acpi_ctx.madt()?.to_text()),
+            Self::Dmar => Some(acpi_ctx.dmar()?.to_text()),
+            Self::Ivrs => Some(acpi_ctx.ivrs()?.to_text()),
+            Self::Hpet => Some(acpi_ctx.hpet()?.to_text()),
+            Self::Srat => Some(acpi_ctx.srat()?.to_text()),
+            Self::Slit => Some(acpi_ctx.slit()?.to_text()),
+            Self::Fpdt => Some(acpi_ctx.fpdt()?.to_text()),
+            Self::Report => Some(report::render(acpi_ctx)),
+            _ => None,
+        }
+    }
 }
 
 pub struct AcpiFS {
     lock: Mutex<()>,
     acpi_context: AcpiContext,
-    current_handle: AcpiHandle,
+    events: Events,
+    devices: Devices,
+    /// Open handles, keyed by the path they were opened with, which is how the kernel tags
+    /// every request. Clients reading different files do not disturb each other; clients
+    /// reading the same path share a handle.
+    handles: BTreeMap<String, AcpiHandle>,
+    /// The paths of the `Evaluation` and `DeviceFile` handles, oldest first.
+    rendered: VecDeque<String>,
     user_command: UserCommand,
 }
 
@@ -109,8 +140,11 @@ impl AcpiFS {
     pub fn new(ctx: AcpiContext) -> Self {
         Self {
             lock: Mutex::new(()),
+            events: Events::init(&ctx),
+            devices: Devices::discover(&ctx),
             acpi_context: ctx,
-            current_handle: AcpiHandle::NoHandle,
+            handles: BTreeMap::new(),
+            rendered: VecDeque::new(),
             user_command: UserCommand::default(),
         }
     }
@@ -126,15 +160,18 @@ impl AcpiFS {
             let cmd = self.user_command.cmd;
             if cmd != 0 {
                 match cmd {
-                    USER_OPEN => self.open(
-                        str::from_utf8(unsafe {
+                    USER_OPEN => {
+                        // No handle is opened for a path that is not UTF-8, so requests on it
+                        // fail.
+                        if let Ok(path) = str::from_utf8(unsafe {
                             core::slice::from_raw_parts(
                                 self.user_command.buf_addr as *const u8,
                                 self.user_command.buf_size,
                             )
-                        })
-                        .unwrap(),
-                    ),
+                        }) {
+                            self.open(path);
+                        }
+                    }
                     USER_READ => {
                         if self
                             .read(unsafe {
@@ -150,6 +187,21 @@ impl AcpiFS {
                             self.user_command.ret_val = -1;
                         }
                     }
+                    USER_WRITE => {
+                        if self
+                            .write(unsafe {
+                                core::slice::from_raw_parts(
+                                    self.user_command.buf_addr as *const u8,
+                                    self.user_command.buf_size,
+                                )
+                            })
+                            .is_ok()
+                        {
+                            self.user_command.ret_val = 0;
+                        } else {
+                            self.user_command.ret_val = -1;
+                        }
+                    }
                     USER_SIZE => {
                         if let Ok(size) = self.size() {
                             self.user_command.ret_val = size as isize;
@@ -172,48 +224,181 @@ impl AcpiFS {
                 self.user_command.cmd = 0;
             }
 
+            {
+                let _guard = self.lock.lock();
+                if self.events.poll(&self.acpi_context) {
+                    self.devices.refresh(&self.acpi_context);
+                }
+            }
+
             rstd::proc::r#yield();
         }
     }
 
+    /// The path the handle of the request being served was opened with.
+    fn request_path(&self) -> Result<String, ()> {
+        let path_addr = self.user_command.ret_val as *const u8;
+        let path_len = self.user_command.ret_val2 as usize;
+        unsafe { str::from_utf8(core::slice::from_raw_parts(path_addr, path_len)) }
+            .map(str::to_string)
+            .map_err(|_| ())
+    }
+
     fn open(&mut self, path: &str) {
-        let guard = self.lock.lock();
-        match path {
-            "" => {
-                self.current_handle = AcpiHandle::TopLevel;
-            }
-            "tables" => {
-                self.current_handle = AcpiHandle::Tables;
-            }
-            _ => {
-                drop(guard);
-                self.open_table(path)
+        let _guard = self.lock.lock();
+
+        // A failed open must not leave an earlier handle for the same path behind.
+        self.handles.remove(path);
+        self.rendered.retain(|rendered| rendered != path);
+
+        let Some(handle) = self.new_handle(path) else {
+            return;
+        };
+        if let AcpiHandle::Evaluation(_) | AcpiHandle::DeviceFile(_) = handle {
+            if self.rendered.len() == MAX_RENDERED
+                && let Some(oldest) = self.rendered.pop_front()
+            {
+                self.handles.remove(&oldest);
             }
+            self.rendered.push_back(path.to_string());
         }
+        self.handles.insert(path.to_string(), handle);
     }
 
-    fn open_table(&mut self, path: &str) {
-        let _guard = self.lock.lock();
-        if let Some((tables, table)) = path.split_once(':') {
-            match tables {
-                "tables" => {
-                    if let Some(table) = parse_table(table.as_bytes()) {
-                        self.current_handle = AcpiHandle::Table(table);
-                    }
+    fn new_handle(&self, path: &str) -> Option<AcpiHandle> {
+        match path {
+            "" => Some(AcpiHandle::TopLevel),
+            "tables" => Some(AcpiHandle::Tables),
+            "namespace" => Some(AcpiHandle::Namespace(AmlName::root())),
+            "power" => Some(AcpiHandle::Power),
+            "madt" => Some(AcpiHandle::Madt),
+            "dmar" => Some(AcpiHandle::Dmar),
+            "ivrs" => Some(AcpiHandle::Ivrs),
+            "hpet" => Some(AcpiHandle::Hpet),
+            "srat" => Some(AcpiHandle::Srat),
+            "slit" => Some(AcpiHandle::Slit),
+            "fpdt" => Some(AcpiHandle::Fpdt),
+            "events" => Some(AcpiHandle::Events),
+            "report" => Some(AcpiHandle::Report),
+            "quarantine" => Some(AcpiHandle::Quarantine),
+            _ => match Class::from_dir(path) {
+                Some(class) => Some(AcpiHandle::Devices(class)),
+                None => self.new_file_handle(path),
+            },
+        }
+    }
+
+    fn new_file_handle(&self, path: &str) -> Option<AcpiHandle> {
+        let (tables, table) = path.split_once(':')?;
+        match tables {
+            "tables" => parse_table(table.as_bytes()).map(AcpiHandle::Table),
+            "quarantine" => parse_table(table.as_bytes()).map(AcpiHandle::QuarantinedTable),
+            "namespace" => namespace::parse_path(table).map(AcpiHandle::Namespace),
+            "evaluate" => self.evaluate(table),
+            "power" => match table {
+                "shutdown" => Some(AcpiHandle::Shutdown),
+                "reboot" => Some(AcpiHandle::Reboot),
+                _ => {
+                    println!("Unknown power control: {}", table);
+                    None
+                }
+            },
+            _ => match Class::from_dir(tables) {
+                Some(class) => self.device_handle(class, table),
+                None => {
+                    println!("Unknown path: {}", tables);
+                    None
                 }
-                _ => println!("Unknown path: {}", tables),
+            },
+        }
+    }
+
+    /// Opens `<index>` or `<index>:<file>` of a device of `class`.
+    fn device_handle(&self, class: Class, path: &str) -> Option<AcpiHandle> {
+        let (index, file) = match path.split_once(':') {
+            Some((index, file)) => (index, Some(file)),
+            None => (path, None),
+        };
+        let index = index
+            .parse()
+            .ok()
+            .filter(|&index| index < self.devices.count(class))?;
+
+        match file {
+            None => Some(AcpiHandle::Device(class, index)),
+            Some(file) => Some(AcpiHandle::DeviceFile(self.devices.file(
+                &self.acpi_context,
+                class,
+                index,
+                file,
+            )?)),
+        }
+    }
+
+    /// Evaluates `<path>[:<arguments>]`, see [`namespace::parse_arguments`] for the argument
+    /// syntax. Failures are reported and leave no handle, so that reading it fails.
+    fn evaluate(&self, path: &str) -> Option<AcpiHandle> {
+        let (path, arguments) = path.split_once(':').unwrap_or((path, ""));
+        let (Some(name), Some(arguments)) = (
+            namespace::parse_path(path),
+            namespace::parse_arguments(arguments),
+        ) else {
+            println!("acpid: invalid evaluation: {}", path);
+            return None;
+        };
+
+        match namespace::evaluate(
+            &mut self.acpi_context.aml_context().lock(),
+            &name,
+            arguments,
+        ) {
+            Ok(result) => Some(AcpiHandle::Evaluation(result)),
+            Err(error) => {
+                println!("acpid: failed to evaluate {}: {:?}", name, error);
+                None
             }
         }
     }
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
         let _guard = self.lock.lock();
-        let src_buf = match &self.current_handle {
+        let handle = self.handles.get(&self.request_path()?).ok_or(())?;
+
+        // Events are a stream: every read takes the oldest ones, whatever the offset.
+        if let AcpiHandle::Events = handle {
+            return Ok(self.events.take(buf));
+        }
+
+        let description;
+        let src_buf = match handle {
             AcpiHandle::Table(signature) => self
                 .acpi_context
                 .sdt_from_signature(signature)
                 .ok_or(())?
                 .as_slice(),
+            AcpiHandle::QuarantinedTable(signature) => self
+                .acpi_context
+                .quarantined_from_signature(signature)
+                .ok_or(())?
+                .as_slice(),
+            AcpiHandle::Namespace(name) => {
+                description =
+                    namespace::describe(&mut self.acpi_context.aml_context().lock(), name)
+                        .ok_or(())?;
+                description.as_bytes()
+            }
+            AcpiHandle::Evaluation(result) | AcpiHandle::DeviceFile(result) => result.as_bytes(),
+            AcpiHandle::Madt
+            | AcpiHandle::Dmar
+            | AcpiHandle::Ivrs
+            | AcpiHandle::Hpet
+            | AcpiHandle::Srat
+            | AcpiHandle::Slit
+            | AcpiHandle::Fpdt
+            | AcpiHandle::Report => {
+                description = handle.decoded(&self.acpi_context).ok_or(())?;
+                description.as_bytes()
+            }
             _ => return Err(()),
         };
 
@@ -224,41 +409,83 @@ impl AcpiFS {
 
         buf[..to_copy].copy_from_slice(&src_buf[..to_copy]);
 
-        self.current_handle = AcpiHandle::NoHandle;
-
         Ok(to_copy)
     }
 
+    /// Carries out the power control the handle names, if `buf` is `1`, optionally followed by
+    /// a newline.
+    fn write(&mut self, buf: &[u8]) -> Result<(), ()> {
+        let _guard = self.lock.lock();
+        if !matches!(buf, b"1" | b"1\n") {
+            return Err(());
+        }
+        match self.handles.get(&self.request_path()?).ok_or(())? {
+            AcpiHandle::Shutdown => power::shutdown(&self.acpi_context),
+            AcpiHandle::Reboot => power::reboot(&self.acpi_context),
+            _ => Err(()),
+        }
+    }
+
     fn size(&mut self) -> Result<usize, ()> {
         let _guard = self.lock.lock();
-        self.current_handle
-            .len(&self.acpi_context)
-            .try_into()
-            .unwrap_or(Err(()))
+        match self.handles.get(&self.request_path()?).ok_or(())? {
+            AcpiHandle::Events => Ok(self.events.pending()),
+            handle => handle.len(&self.acpi_context),
+        }
     }
 
     fn list(&mut self) -> Result<(usize, usize, usize), ()> {
         let _guard = self.lock.lock();
-        match &self.current_handle {
+        match self.handles.get(&self.request_path()?).ok_or(())? {
             AcpiHandle::Tables => {
                 let mut result = Vec::new();
 
                 for table in self.acpi_context.tables().iter() {
-                    let utf8_or_eio = |bytes| str::from_utf8(bytes).map_err(|_| ());
+                    result.push(table.signature().file_name());
+                }
 
-                    let mut name = String::new();
-                    name.push_str(utf8_or_eio(&table.signature[..])?);
-                    name.push('-');
-                    for byte in table.oem_id.iter() {
-                        core::fmt::write(&mut name, format_args!("{:>02X}", byte)).unwrap();
-                    }
-                    name.push('-');
-                    for byte in table.oem_table_id.iter() {
-                        core::fmt::write(&mut name, format_args!("{:>02X}", byte)).unwrap();
-                    }
+                let (ret_struct_addr, ret_struct_len, ret_struct_cap) = result.into_raw_parts();
 
-                    result.push(name);
-                }
+                Ok((ret_struct_addr as usize, ret_struct_len, ret_struct_cap))
+            }
+            AcpiHandle::Quarantine => {
+                let result = self
+                    .acpi_context
+                    .quarantine()
+                    .iter()
+                    .map(|(_, table)| table.signature().file_name())
+                    .collect::<Vec<_>>();
+
+                let (ret_struct_addr, ret_struct_len, ret_struct_cap) = result.into_raw_parts();
+
+                Ok((ret_struct_addr as usize, ret_struct_len, ret_struct_cap))
+            }
+            AcpiHandle::Power => {
+                let result = ["shutdown", "reboot"].map(String::from).to_vec();
+
+                let (ret_struct_addr, ret_struct_len, ret_struct_cap) = result.into_raw_parts();
+
+                Ok((ret_struct_addr as usize, ret_struct_len, ret_struct_cap))
+            }
+            AcpiHandle::Devices(class) => {
+                let result = (0..self.devices.count(*class))
+                    .map(|index| index.to_string())
+                    .collect::<Vec<_>>();
+
+                let (ret_struct_addr, ret_struct_len, ret_struct_cap) = result.into_raw_parts();
+
+                Ok((ret_struct_addr as usize, ret_struct_len, ret_struct_cap))
+            }
+            AcpiHandle::Device(..) => {
+                let result = devices::FILES.map(String::from).to_vec();
+
+                let (ret_struct_addr, ret_struct_len, ret_struct_cap) = result.into_raw_parts();
+
+                Ok((ret_struct_addr as usize, ret_struct_len, ret_struct_cap))
+            }
+            AcpiHandle::Namespace(name) => {
+                let result = namespace::children(&mut self.acpi_context.aml_context().lock(), name)
+                    .ok_or(())?;
 
                 let (ret_struct_addr, ret_struct_len, ret_struct_cap) = result.into_raw_parts();
 
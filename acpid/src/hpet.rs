@@ -0,0 +1,83 @@
+//! The High Precision Event Timer table, decoded for `:acpi:hpet`.
+
+use core::fmt::Write;
+
+use alloc::string::String;
+
+use crate::{
+    bytes::{u16_at, u32_at, u64_at},
+    sdt::Sdt,
+};
+
+#[derive(Clone, Debug)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    /// The number of comparators, i.e. timers, in the block.
+    pub comparators: u8,
+    /// Whether the main counter is 64 bits wide rather than 32.
+    pub counter_64bit: bool,
+    /// Whether the block can take over the legacy PIT and RTC interrupts.
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// The address space of `address`, as in a generic address structure: 0 for memory.
+    pub address_space: u8,
+    pub address: u64,
+    /// Which HPET block this is, for systems with more than one.
+    pub number: u8,
+    /// The smallest period, in main counter ticks, that periodic mode can be set to without
+    /// losing interrupts.
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    /// Decodes an `HPET` table, or returns `None` if it is too short.
+    pub fn parse(sdt: &Sdt) -> Option<Self> {
+        if &sdt.signature != b"HPET" {
+            return None;
+        }
+
+        let data = sdt.data();
+        let id = u32_at(data, 0)?;
+        Some(Self {
+            hardware_revision: id as u8,
+            comparators: ((id >> 8) & 0x1F) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            address_space: *data.get(4)?,
+            address: u64_at(data, 8)?,
+            number: *data.get(16)?,
+            minimum_tick: u16_at(data, 17)?,
+            page_protection: *data.get(19)?,
+        })
+    }
+
+    /// Renders the table as text, one `key=value` field per line.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "number={}", self.number).unwrap();
+        writeln!(out, "address={:#x}", self.address).unwrap();
+        writeln!(out, "address_space={}", self.address_space).unwrap();
+        writeln!(out, "comparators={}", self.comparators).unwrap();
+        writeln!(
+            out,
+            "counter_size={}",
+            if self.counter_64bit { 64 } else { 32 }
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "legacy_replacement={}",
+            u8::from(self.legacy_replacement)
+        )
+        .unwrap();
+        writeln!(out, "minimum_tick={}", self.minimum_tick).unwrap();
+        writeln!(out, "hardware_revision={}", self.hardware_revision).unwrap();
+        writeln!(out, "pci_vendor_id={:#06x}", self.pci_vendor_id).unwrap();
+        writeln!(out, "page_protection={:#x}", self.page_protection).unwrap();
+
+        out
+    }
+}
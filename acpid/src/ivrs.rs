@@ -0,0 +1,386 @@
+//! The I/O Virtualization Reporting Structure, which describes AMD-Vi IOMMUs, decoded for
+//! `:acpi:ivrs`.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    bytes::{u16_at, u32_at, u64_at},
+    sdt::Sdt,
+};
+
+const IVHD_FIXED: u8 = 0x10;
+const IVHD_MIXED: u8 = 0x11;
+const IVHD_ACPI_HID: u8 = 0x40;
+const IVMD_ALL: u8 = 0x20;
+const IVMD_SELECT: u8 = 0x21;
+const IVMD_RANGE: u8 = 0x22;
+
+const DEVICE_ALL: u8 = 0x01;
+const DEVICE_SELECT: u8 = 0x02;
+const DEVICE_RANGE_START: u8 = 0x03;
+const DEVICE_RANGE_END: u8 = 0x04;
+const DEVICE_ALIAS_SELECT: u8 = 0x42;
+const DEVICE_ALIAS_RANGE_START: u8 = 0x43;
+const DEVICE_EXTENDED_SELECT: u8 = 0x46;
+const DEVICE_EXTENDED_RANGE_START: u8 = 0x47;
+const DEVICE_SPECIAL: u8 = 0x48;
+const DEVICE_ACPI_HID: u8 = 0xF0;
+
+/// Where the IVHD and IVMD blocks start, past the IVinfo field and reserved bytes.
+const BLOCKS_OFFSET: usize = 12;
+/// The smallest block: a type, flags and a length.
+const MIN_BLOCK_LEN: usize = 4;
+/// The length of the fixed part of an ACPI HID device entry, before its UID.
+const ACPI_HID_ENTRY_LEN: usize = 22;
+
+/// Which devices an IVHD entry's DTE settings apply to. Device IDs are PCI
+/// bus/device/function numbers on the block's segment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IvhdDevice {
+    All {
+        setting: u8,
+    },
+    Range {
+        first: u16,
+        last: u16,
+        setting: u8,
+    },
+    /// Devices whose requests reach the IOMMU with the ID of `source`, e.g. behind a PCIe to
+    /// PCI bridge.
+    Alias {
+        first: u16,
+        last: u16,
+        source: u16,
+        setting: u8,
+    },
+    /// An I/O APIC (`variety` 1) or HPET (`variety` 2) with its `handle` from the MADT or
+    /// HPET table.
+    Special {
+        variety: u8,
+        handle: u8,
+        device_id: u16,
+        setting: u8,
+    },
+    /// An ACPI namespace device, by `_HID`.
+    AcpiDevice {
+        hid: [u8; 8],
+        device_id: u16,
+        setting: u8,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IvrsEntry {
+    /// An IOMMU and the devices it translates for.
+    Ivhd {
+        typ: u8,
+        flags: u8,
+        /// The PCI function of the IOMMU itself.
+        device_id: u16,
+        segment: u16,
+        base: u64,
+        devices: Vec<IvhdDevice>,
+    },
+    /// Memory that must stay mapped, with the given permissions, for DMA from the listed
+    /// devices.
+    Ivmd {
+        typ: u8,
+        flags: u8,
+        first: u16,
+        last: u16,
+        start: u64,
+        length: u64,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Ivrs {
+    pub info: u32,
+    pub entries: Vec<IvrsEntry>,
+    /// Problems met while decoding. The structures they concern are left out.
+    pub warnings: Vec<String>,
+}
+
+/// The length of an IVHD device entry of type `typ` starting `entry`, which the two top
+/// bits of the type encode for all but the variable-length entries.
+fn device_entry_len(typ: u8, entry: &[u8]) -> Option<usize> {
+    match typ {
+        0x00..=0x3F => Some(4),
+        0x40..=0x7F => Some(8),
+        DEVICE_ACPI_HID => Some(ACPI_HID_ENTRY_LEN + usize::from(*entry.get(21)?)),
+        _ => None,
+    }
+}
+
+/// Decodes the device entries filling `bytes`. Range starts are paired with the range end
+/// that follows them. An entry running past the end, or of an unknown variable-length
+/// type, ends the list.
+fn parse_devices(mut bytes: &[u8], warnings: &mut Vec<String>) -> Vec<IvhdDevice> {
+    let mut devices = Vec::new();
+    // A range start waiting for its end: its first device, alias source and setting.
+    let mut range_start: Option<(u16, Option<u16>, u8)> = None;
+
+    while let Some(&typ) = bytes.first() {
+        let entry = device_entry_len(typ, bytes).and_then(|len| bytes.get(..len));
+        let Some(entry) = entry else {
+            warnings.push(format!("bad IVHD device entry of type {:#x}", typ));
+            break;
+        };
+        bytes = &bytes[entry.len()..];
+
+        let device_id = u16_at(entry, 1).unwrap_or_default();
+        let setting = entry[3];
+
+        match typ {
+            DEVICE_ALL => devices.push(IvhdDevice::All { setting }),
+            DEVICE_SELECT | DEVICE_EXTENDED_SELECT => devices.push(IvhdDevice::Range {
+                first: device_id,
+                last: device_id,
+                setting,
+            }),
+            DEVICE_RANGE_START | DEVICE_EXTENDED_RANGE_START => {
+                range_start = Some((device_id, None, setting))
+            }
+            DEVICE_ALIAS_SELECT => devices.push(IvhdDevice::Alias {
+                first: device_id,
+                last: device_id,
+                source: u16_at(entry, 5).unwrap_or_default(),
+                setting,
+            }),
+            DEVICE_ALIAS_RANGE_START => {
+                range_start = Some((device_id, u16_at(entry, 5), setting));
+            }
+            DEVICE_RANGE_END => match range_start.take() {
+                Some((first, None, setting)) => devices.push(IvhdDevice::Range {
+                    first,
+                    last: device_id,
+                    setting,
+                }),
+                Some((first, Some(source), setting)) => devices.push(IvhdDevice::Alias {
+                    first,
+                    last: device_id,
+                    source,
+                    setting,
+                }),
+                None => warnings.push(String::from("IVHD range end without a start")),
+            },
+            DEVICE_SPECIAL => devices.push(IvhdDevice::Special {
+                handle: entry[4],
+                device_id: u16_at(entry, 5).unwrap_or_default(),
+                variety: entry[7],
+                setting,
+            }),
+            DEVICE_ACPI_HID => devices.push(IvhdDevice::AcpiDevice {
+                hid: entry[4..12].try_into().unwrap(),
+                device_id,
+                setting,
+            }),
+            _ => {}
+        }
+    }
+
+    devices
+}
+
+fn decode_entry(typ: u8, block: &[u8], warnings: &mut Vec<String>) -> Option<IvrsEntry> {
+    Some(match typ {
+        IVHD_FIXED | IVHD_MIXED | IVHD_ACPI_HID => IvrsEntry::Ivhd {
+            typ,
+            flags: *block.get(1)?,
+            device_id: u16_at(block, 4)?,
+            base: u64_at(block, 8)?,
+            segment: u16_at(block, 16)?,
+            // Type 10h has a 24-byte header, the later types add the EFR image.
+            devices: parse_devices(
+                block.get(if typ == IVHD_FIXED { 24 } else { 40 }..)?,
+                warnings,
+            ),
+        },
+        IVMD_ALL | IVMD_SELECT | IVMD_RANGE => {
+            let device_id = u16_at(block, 4)?;
+            let (first, last) = match typ {
+                IVMD_ALL => (0, u16::MAX),
+                IVMD_SELECT => (device_id, device_id),
+                _ => (device_id, u16_at(block, 6)?),
+            };
+            IvrsEntry::Ivmd {
+                typ,
+                flags: *block.get(1)?,
+                first,
+                last,
+                start: u64_at(block, 16)?,
+                length: u64_at(block, 24)?,
+            }
+        }
+        _ => return None,
+    })
+}
+
+/// Formats a PCI device ID as `bus:device.function`.
+fn bdf(device_id: u16) -> impl core::fmt::Display {
+    struct Bdf(u16);
+    impl core::fmt::Display for Bdf {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            write!(
+                f,
+                "{:02x}:{:02x}.{}",
+                self.0 >> 8,
+                (self.0 >> 3) & 0x1F,
+                self.0 & 0x7
+            )
+        }
+    }
+    Bdf(device_id)
+}
+
+impl Ivrs {
+    /// Decodes an `IVRS` table. Unknown block types are skipped; a block running past the end
+    /// of the table, or too short to hold its own header, ends the list.
+    pub fn parse(sdt: &Sdt) -> Option<Self> {
+        if &sdt.signature != b"IVRS" {
+            return None;
+        }
+
+        let data = sdt.data();
+        let mut ivrs = Self {
+            info: u32_at(data, 0)?,
+            entries: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        let mut rest = data.get(BLOCKS_OFFSET..).unwrap_or_default();
+        while let (Some(&typ), Some(len)) = (rest.first(), u16_at(rest, 2)) {
+            let len = usize::from(len);
+            let Some(block) = rest.get(..len).filter(|_| len >= MIN_BLOCK_LEN) else {
+                ivrs.warnings
+                    .push(format!("truncated IVRS block of type {:#x}", typ));
+                break;
+            };
+            rest = &rest[len..];
+
+            if let IVHD_FIXED | IVHD_MIXED | IVHD_ACPI_HID | IVMD_ALL | IVMD_SELECT | IVMD_RANGE =
+                typ
+            {
+                match decode_entry(typ, block, &mut ivrs.warnings) {
+                    Some(decoded) => ivrs.entries.push(decoded),
+                    None => ivrs
+                        .warnings
+                        .push(format!("short IVRS block of type {:#x}", typ)),
+                }
+            }
+        }
+
+        Some(ivrs)
+    }
+
+    /// Renders the table as text in the format of `:acpi:dmar`: one line per block, and one
+    /// indented line per device entry below an IVHD. Device IDs are `bus:device.function`.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "info={:#x}", self.info).unwrap();
+
+        for entry in &self.entries {
+            match entry {
+                IvrsEntry::Ivhd {
+                    typ,
+                    flags,
+                    device_id,
+                    segment,
+                    base,
+                    devices,
+                } => {
+                    writeln!(
+                        out,
+                        "ivhd type={:#x} segment={} device={} base={:#x} flags={:#x}",
+                        typ,
+                        segment,
+                        bdf(*device_id),
+                        base,
+                        flags
+                    )
+                    .unwrap();
+
+                    for device in devices {
+                        match device {
+                            IvhdDevice::All { setting } => {
+                                writeln!(out, "  all setting={:#x}", setting)
+                            }
+                            IvhdDevice::Range {
+                                first,
+                                last,
+                                setting,
+                            } => writeln!(
+                                out,
+                                "  devices first={} last={} setting={:#x}",
+                                bdf(*first),
+                                bdf(*last),
+                                setting
+                            ),
+                            IvhdDevice::Alias {
+                                first,
+                                last,
+                                source,
+                                setting,
+                            } => writeln!(
+                                out,
+                                "  alias first={} last={} source={} setting={:#x}",
+                                bdf(*first),
+                                bdf(*last),
+                                bdf(*source),
+                                setting
+                            ),
+                            IvhdDevice::Special {
+                                variety,
+                                handle,
+                                device_id,
+                                setting,
+                            } => writeln!(
+                                out,
+                                "  special variety={} handle={} device={} setting={:#x}",
+                                variety,
+                                handle,
+                                bdf(*device_id),
+                                setting
+                            ),
+                            IvhdDevice::AcpiDevice {
+                                hid,
+                                device_id,
+                                setting,
+                            } => writeln!(
+                                out,
+                                "  acpi hid={} device={} setting={:#x}",
+                                String::from_utf8_lossy(hid).trim_end_matches('\0'),
+                                bdf(*device_id),
+                                setting
+                            ),
+                        }
+                        .unwrap();
+                    }
+                }
+                IvrsEntry::Ivmd {
+                    typ,
+                    flags,
+                    first,
+                    last,
+                    start,
+                    length,
+                } => writeln!(
+                    out,
+                    "ivmd type={:#x} first={} last={} start={:#x} length={:#x} flags={:#x}",
+                    typ,
+                    bdf(*first),
+                    bdf(*last),
+                    start,
+                    length,
+                    flags
+                )
+                .unwrap(),
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,16 @@
+//! The table decoding behind acpid. It only works on bytes, so it also builds on the host,
+//! where the tests run it against captured tables: `cargo test --no-default-features`.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod bytes;
+pub mod dmar;
+pub mod fpdt;
+pub mod hpet;
+pub mod ivrs;
+pub mod madt;
+pub mod sdt;
+pub mod slit;
+pub mod srat;
@@ -0,0 +1,237 @@
+//! The Multiple APIC Description Table, decoded for `:acpi:madt`.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    bytes::{u16_at, u32_at, u64_at},
+    sdt::Sdt,
+};
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const NMI_SOURCE: u8 = 3;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+const LOCAL_X2APIC_NMI: u8 = 0xA;
+
+/// The processor UID local APIC NMI entries use to mean every processor.
+pub const ALL_PROCESSORS: u32 = u32::MAX;
+
+/// One interrupt controller structure from the MADT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MadtEntry {
+    /// A processor's local APIC or x2APIC.
+    LocalApic {
+        processor_uid: u32,
+        apic_id: u32,
+        flags: u32,
+        x2apic: bool,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    /// An ISA interrupt delivered on a GSI other than its IRQ number, or with other polarity
+    /// or trigger mode.
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    /// A GSI to be used as a non-maskable interrupt.
+    NmiSource {
+        gsi: u32,
+        flags: u16,
+    },
+    /// The local interrupt pin a processor's NMI is wired to. A UID of [`ALL_PROCESSORS`]
+    /// applies to every processor.
+    LocalApicNmi {
+        processor_uid: u32,
+        lint: u8,
+        flags: u16,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Madt {
+    /// The local APIC base, after any address override.
+    pub local_apic_address: u64,
+    pub flags: u32,
+    pub entries: Vec<MadtEntry>,
+    /// Problems met while decoding. The structures they concern are left out.
+    pub warnings: Vec<String>,
+}
+
+/// Decodes a structure of one of the types [`MadtEntry`] covers, or `None` if it is too short.
+fn decode_entry(typ: u8, entry: &[u8]) -> Option<MadtEntry> {
+    Some(match typ {
+        LOCAL_APIC => MadtEntry::LocalApic {
+            processor_uid: (*entry.get(2)?).into(),
+            apic_id: (*entry.get(3)?).into(),
+            flags: u32_at(entry, 4)?,
+            x2apic: false,
+        },
+        LOCAL_X2APIC => MadtEntry::LocalApic {
+            processor_uid: u32_at(entry, 12)?,
+            apic_id: u32_at(entry, 4)?,
+            flags: u32_at(entry, 8)?,
+            x2apic: true,
+        },
+        IO_APIC => MadtEntry::IoApic {
+            id: *entry.get(2)?,
+            address: u32_at(entry, 4)?,
+            gsi_base: u32_at(entry, 8)?,
+        },
+        INTERRUPT_SOURCE_OVERRIDE => MadtEntry::InterruptSourceOverride {
+            bus: *entry.get(2)?,
+            source: *entry.get(3)?,
+            gsi: u32_at(entry, 4)?,
+            flags: u16_at(entry, 8)?,
+        },
+        NMI_SOURCE => MadtEntry::NmiSource {
+            flags: u16_at(entry, 2)?,
+            gsi: u32_at(entry, 4)?,
+        },
+        LOCAL_APIC_NMI => MadtEntry::LocalApicNmi {
+            processor_uid: match *entry.get(2)? {
+                0xFF => ALL_PROCESSORS,
+                uid => uid.into(),
+            },
+            flags: u16_at(entry, 3)?,
+            lint: *entry.get(5)?,
+        },
+        LOCAL_X2APIC_NMI => MadtEntry::LocalApicNmi {
+            flags: u16_at(entry, 2)?,
+            processor_uid: u32_at(entry, 4)?,
+            lint: *entry.get(8)?,
+        },
+        _ => return None,
+    })
+}
+
+impl Madt {
+    /// Decodes an `APIC` table. Structures of types acpid does not know are skipped; a
+    /// structure running past the end of the table ends the list.
+    pub fn parse(sdt: &Sdt) -> Option<Self> {
+        if &sdt.signature != b"APIC" {
+            return None;
+        }
+
+        let data = sdt.data();
+        let mut madt = Self {
+            local_apic_address: u32_at(data, 0)?.into(),
+            flags: u32_at(data, 4)?,
+            entries: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        let mut rest = &data[8..];
+        while let [typ, len, ..] = *rest {
+            let len = usize::from(len);
+            let Some(entry) = rest.get(..len).filter(|_| len >= 2) else {
+                madt.warnings
+                    .push(format!("truncated MADT entry of type {}", typ));
+                break;
+            };
+            rest = &rest[len..];
+
+            let decoded = match typ {
+                LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    if let Some(address) = u64_at(entry, 4) {
+                        madt.local_apic_address = address;
+                    }
+                    continue;
+                }
+                LOCAL_APIC
+                | LOCAL_X2APIC
+                | IO_APIC
+                | INTERRUPT_SOURCE_OVERRIDE
+                | NMI_SOURCE
+                | LOCAL_APIC_NMI
+                | LOCAL_X2APIC_NMI => decode_entry(typ, entry),
+                _ => continue,
+            };
+
+            match decoded {
+                Some(decoded) => madt.entries.push(decoded),
+                None => madt
+                    .warnings
+                    .push(format!("short MADT entry of type {}", typ)),
+            }
+        }
+
+        Some(madt)
+    }
+
+    /// Renders the table as text, one structure per line: its kind followed by
+    /// `key=value` fields. Addresses and flags are in hex, everything else in decimal.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "local_apic_address={:#x}", self.local_apic_address).unwrap();
+        writeln!(out, "flags={:#x}", self.flags).unwrap();
+
+        for entry in &self.entries {
+            match *entry {
+                MadtEntry::LocalApic {
+                    processor_uid,
+                    apic_id,
+                    flags,
+                    x2apic,
+                } => writeln!(
+                    out,
+                    "{} uid={} id={} flags={:#x}",
+                    if x2apic { "x2apic" } else { "local_apic" },
+                    processor_uid,
+                    apic_id,
+                    flags
+                ),
+                MadtEntry::IoApic {
+                    id,
+                    address,
+                    gsi_base,
+                } => writeln!(
+                    out,
+                    "io_apic id={} address={:#x} gsi_base={}",
+                    id, address, gsi_base
+                ),
+                MadtEntry::InterruptSourceOverride {
+                    bus,
+                    source,
+                    gsi,
+                    flags,
+                } => writeln!(
+                    out,
+                    "override bus={} source={} gsi={} flags={:#x}",
+                    bus, source, gsi, flags
+                ),
+                MadtEntry::NmiSource { gsi, flags } => {
+                    writeln!(out, "nmi gsi={} flags={:#x}", gsi, flags)
+                }
+                MadtEntry::LocalApicNmi {
+                    processor_uid: ALL_PROCESSORS,
+                    lint,
+                    flags,
+                } => writeln!(out, "local_nmi uid=all lint={} flags={:#x}", lint, flags),
+                MadtEntry::LocalApicNmi {
+                    processor_uid,
+                    lint,
+                    flags,
+                } => writeln!(
+                    out,
+                    "local_nmi uid={} lint={} flags={:#x}",
+                    processor_uid, lint, flags
+                ),
+            }
+            .unwrap();
+        }
+
+        out
+    }
+}
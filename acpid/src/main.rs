@@ -11,7 +11,14 @@ use rstd::alloc::sync::Arc;
 extern crate rstd;
 
 pub mod acpi;
+mod aml_handler;
+mod devices;
+mod events;
 mod fs;
+mod namespace;
+mod power;
+mod register;
+mod report;
 
 #[unsafe(no_mangle)]
 extern "C" fn _start() -> ! {
@@ -30,16 +37,31 @@ extern "C" fn _start() -> ! {
     rstd::fs::read(fd, rxsdt_buf.as_mut_ptr() as usize, acpi_fsize as usize);
 
     let rxsdt_raw_data: Arc<[u8]> = Arc::from(rxsdt_buf.as_slice());
-    let sdt = self::acpi::Sdt::new(rxsdt_raw_data).expect("acpid: failed to parse [RX]SDT");
+    // Without the [RX]SDT there is nothing to load, but acpid still comes up so that its
+    // clients do not wait for it forever. A bad checksum is tolerated, as the alternative is
+    // no tables at all.
+    let sdt = match self::acpi::Sdt::new_ignoring_checksum(rxsdt_raw_data) {
+        Ok(sdt) => {
+            if !sdt.checksum_valid() {
+                println!("acpid: [RX]SDT has a bad checksum, using it anyway");
+            }
+            Some(sdt)
+        }
+        Err(error) => {
+            println!("acpid: failed to parse [RX]SDT: {}", error);
+            None
+        }
+    };
 
     let mut thirty_two_bit;
     let mut sixty_four_bit;
+    let mut no_tables = core::iter::empty();
 
-    let physaddrs_iter = match &sdt.signature {
-        b"RSDT" => {
+    let physaddrs_iter = match sdt.as_ref().map(|sdt| (&sdt.signature, sdt)) {
+        Some((b"RSDT", sdt)) => {
             thirty_two_bit = sdt
                 .data()
-                .chunks(core::mem::size_of::<u32>())
+                .chunks_exact(core::mem::size_of::<u32>())
                 // TODO: With const generics, the compiler has some way of doing this for static sizes.
                 .map(|chunk| <[u8; core::mem::size_of::<u32>()]>::try_from(chunk).unwrap())
                 .map(|chunk| u32::from_le_bytes(chunk))
@@ -47,16 +69,20 @@ extern "C" fn _start() -> ! {
 
             &mut thirty_two_bit as &mut dyn Iterator<Item = u64>
         }
-        b"XSDT" => {
+        Some((b"XSDT", sdt)) => {
             sixty_four_bit = sdt
                 .data()
-                .chunks(core::mem::size_of::<u64>())
+                .chunks_exact(core::mem::size_of::<u64>())
                 .map(|chunk| <[u8; core::mem::size_of::<u64>()]>::try_from(chunk).unwrap())
                 .map(|chunk| u64::from_le_bytes(chunk));
 
             &mut sixty_four_bit as &mut dyn Iterator<Item = u64>
         }
-        _ => panic!("acpid: expected [RX]SDT from kernel to be either of those"),
+        Some(_) => {
+            println!("acpid: expected [RX]SDT from kernel to be either of those");
+            &mut no_tables as &mut dyn Iterator<Item = u64>
+        }
+        None => &mut no_tables as &mut dyn Iterator<Item = u64>,
     };
 
     let acpi_context = self::acpi::AcpiContext::init(physaddrs_iter);
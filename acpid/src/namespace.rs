@@ -0,0 +1,198 @@
+//! Views of the AML namespace for `:acpi:namespace`, and method evaluation for
+//! `:acpi:evaluate`.
+
+use core::fmt::Write;
+
+use aml::{AmlContext, AmlError, AmlName, AmlValue, Args, LevelType};
+use rstd::alloc::{
+    collections::BTreeSet,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+/// Turns the part of an opened path after `namespace:` into an absolute AML name. The leading
+/// backslash may be left out, and short name segments are padded as usual: `_SB.PCI0` is
+/// `\_SB_.PCI0`.
+pub fn parse_path(path: &str) -> Option<AmlName> {
+    if path.is_empty() || path == "\\" {
+        return Some(AmlName::root());
+    }
+
+    if path.starts_with('\\') {
+        AmlName::from_str(path).ok()
+    } else {
+        let mut absolute = String::from("\\");
+        absolute.push_str(path);
+        AmlName::from_str(&absolute).ok()
+    }
+}
+
+/// The scope `name` opens, if it is one: the root, a device, a processor, a power resource
+/// or a thermal zone.
+fn level_type(aml_context: &mut AmlContext, name: &AmlName) -> Option<LevelType> {
+    let mut found = None;
+    aml_context
+        .namespace
+        .traverse(|level_name, level| {
+            if level_name == name {
+                found = Some(level.typ);
+            }
+            Ok(found.is_none())
+        })
+        .ok()?;
+    found
+}
+
+/// The names of the objects and scopes directly below `name`, or `None` if `name` does not
+/// open a scope.
+pub fn children(aml_context: &mut AmlContext, name: &AmlName) -> Option<Vec<String>> {
+    let mut children = None;
+    aml_context
+        .namespace
+        .traverse(|level_name, level| {
+            if level_name == name {
+                let names = level
+                    .children
+                    .keys()
+                    .chain(level.values.keys())
+                    .map(|seg| seg.as_str().to_string())
+                    .collect::<BTreeSet<_>>();
+                children = Some(names.into_iter().collect());
+            }
+            Ok(children.is_none())
+        })
+        .ok()?;
+    children
+}
+
+/// A text description of the object at `name`: its type and, for data objects, its value.
+/// Methods are not run.
+pub fn describe(aml_context: &mut AmlContext, name: &AmlName) -> Option<String> {
+    let mut out = String::new();
+
+    if let Ok(value) = aml_context.namespace.get_by_path(name) {
+        writeln!(out, "type: {:?}", value.type_of()).unwrap();
+        describe_value(&mut out, value);
+    } else {
+        let level_type = level_type(aml_context, name)?;
+        writeln!(out, "type: {:?}", level_type).unwrap();
+    }
+
+    Some(out)
+}
+
+fn describe_value(out: &mut String, value: &AmlValue) {
+    match value {
+        AmlValue::Method { flags, .. } => {
+            writeln!(out, "arguments: {}", flags.arg_count()).unwrap();
+        }
+        AmlValue::OpRegion {
+            region,
+            offset,
+            length,
+            ..
+        } => {
+            writeln!(out, "space: {:?}", region).unwrap();
+            writeln!(out, "offset: {:#x}", offset).unwrap();
+            writeln!(out, "length: {:#x}", length).unwrap();
+        }
+        AmlValue::Processor {
+            id,
+            pblk_address,
+            pblk_len,
+        } => {
+            writeln!(out, "id: {}", id).unwrap();
+            writeln!(out, "pblk: {:#x}+{}", pblk_address, pblk_len).unwrap();
+        }
+        AmlValue::Boolean(_)
+        | AmlValue::Integer(_)
+        | AmlValue::String(_)
+        | AmlValue::Buffer(_)
+        | AmlValue::Package(_) => {
+            out.push_str("value: ");
+            write_data(out, value);
+            out.push('\n');
+        }
+        _ => {}
+    }
+}
+
+/// Parses the arguments of an `:acpi:evaluate` path: a comma-separated list of integers,
+/// decimal or `0x`-prefixed hex, and double-quoted strings. Strings have no escapes, so they
+/// cannot contain a quote.
+pub fn parse_arguments(text: &str) -> Option<Vec<AmlValue>> {
+    let mut arguments = Vec::new();
+    let mut rest = text.trim();
+
+    while !rest.is_empty() {
+        let (argument, after) = if let Some(quoted) = rest.strip_prefix('"') {
+            let (string, after) = quoted.split_once('"')?;
+            (AmlValue::String(string.to_string()), after.trim_start())
+        } else {
+            let (integer, after) = rest.split_at(rest.find(',').unwrap_or(rest.len()));
+            let integer = integer.trim();
+            let value = match integer.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                None => integer.parse().ok()?,
+            };
+            (AmlValue::Integer(value), after)
+        };
+        arguments.push(argument);
+
+        rest = match after.strip_prefix(',') {
+            Some(next) if !next.trim().is_empty() => next.trim_start(),
+            Some(_) => return None,
+            None if after.is_empty() => "",
+            None => return None,
+        };
+    }
+
+    Some(arguments)
+}
+
+/// Evaluates the object at `name` with `arguments` and renders the result as [`write_data`]
+/// does, followed by a newline. Objects that are not methods evaluate to themselves, as
+/// firmware is free to declare e.g. `_STA` as a plain integer.
+pub fn evaluate(
+    aml_context: &mut AmlContext,
+    name: &AmlName,
+    arguments: Vec<AmlValue>,
+) -> Result<String, AmlError> {
+    let result = aml_context.invoke_method(name, Args::from_list(arguments)?)?;
+
+    let mut out = String::new();
+    write_data(&mut out, &result);
+    out.push('\n');
+    Ok(out)
+}
+
+/// Writes a data object on one line: integers in hex, strings quoted, buffers as hex bytes
+/// and packages as bracketed lists.
+pub fn write_data(out: &mut String, value: &AmlValue) {
+    match value {
+        AmlValue::Boolean(value) => write!(out, "{}", value).unwrap(),
+        AmlValue::Integer(value) => write!(out, "{:#x}", value).unwrap(),
+        AmlValue::String(value) => write!(out, "{:?}", value).unwrap(),
+        AmlValue::Buffer(bytes) => {
+            out.push('{');
+            for (i, byte) in bytes.lock().iter().enumerate() {
+                if i != 0 {
+                    out.push(' ');
+                }
+                write!(out, "{:02x}", byte).unwrap();
+            }
+            out.push('}');
+        }
+        AmlValue::Package(elements) => {
+            out.push('[');
+            for (i, element) in elements.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(", ");
+                }
+                write_data(out, element);
+            }
+            out.push(']');
+        }
+        other => write!(out, "<{:?}>", other.type_of()).unwrap(),
+    }
+}
@@ -0,0 +1,186 @@
+//! System shutdown and reboot for `:acpi:power`.
+
+use aml::{AmlName, AmlValue, Args, Handler};
+use rstd::alloc::{format, vec};
+use x86_64::instructions::port::Port;
+
+use crate::{acpi::AcpiContext, aml_handler::AmlHandler, register::Register};
+
+/// FADT flag: the reset register is supported.
+const RESET_REG_SUP: u32 = 1 << 10;
+
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// How long to wait for a reset or power-off to take effect before trying something else.
+const GRACE_PERIOD_US: u64 = 500_000;
+
+/// nvmed's ioctl that writes back and flushes a controller's disks, then shuts it down.
+const NVME_IOCTL_SHUTDOWN: usize = 24;
+/// nvmed's ioctl that brings a shut down controller back.
+const NVME_IOCTL_RESUME: usize = 25;
+/// What nvmed answers for a controller it does not have.
+const EBADF: isize = 9;
+
+/// Sends `cmd` to each of nvmed's controllers, numbered from 0, logging the ones that fail.
+fn control_storage(cmd: usize, action: &str) {
+    for controller in 0.. {
+        let fd = rstd::fs::open(&format!(":block:nvme:admin:{}", controller), 0) as usize;
+        if fd == usize::MAX {
+            return;
+        }
+
+        let ret = rstd::fs::ioctl(fd, cmd, 0);
+        rstd::fs::close(fd);
+        match ret {
+            ret if ret == -EBADF => return,
+            ret if ret < 0 => println!(
+                "acpid: NVMe controller {} failed to {}: {}",
+                controller, action, ret
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// Has nvmed shut down its controllers. Writes still in nvmed's cache or in a drive's volatile
+/// write cache would be lost when power goes.
+fn shut_down_storage() {
+    control_storage(NVME_IOCTL_SHUTDOWN, "shut down");
+}
+
+/// Brings the controllers back when the system is still running after a power-off or reset,
+/// so the disks do not stay offline.
+fn resume_storage() {
+    control_storage(NVME_IOCTL_RESUME, "resume");
+}
+
+/// The PM1a and PM1b control registers, preferring the extended FADT fields.
+fn pm1_control_blocks(acpi_ctx: &AcpiContext) -> (Option<Register>, Option<Register>) {
+    let Some(fadt) = acpi_ctx.fadt() else {
+        return (None, None);
+    };
+
+    let extended = fadt.acpi_2_struct();
+    let pm1a = extended
+        .and_then(|fadt2| Register::from_gas(&fadt2.x_pm1a_control_block))
+        .or_else(|| Register::from_port(fadt.pm1a_control_block));
+    let pm1b = extended
+        .and_then(|fadt2| Register::from_gas(&fadt2.x_pm1b_control_block))
+        .or_else(|| Register::from_port(fadt.pm1b_control_block));
+
+    (pm1a, pm1b)
+}
+
+/// The SLP_TYPa and SLP_TYPb values for S5, from the first two elements of `\_S5`.
+fn s5_sleep_types(acpi_ctx: &AcpiContext) -> Option<(u16, u16)> {
+    let aml_context = acpi_ctx.aml_context().lock();
+
+    let name = AmlName::from_str("\\_S5").ok()?;
+    let AmlValue::Package(elements) = aml_context.namespace.get_by_path(&name).ok()?.clone() else {
+        return None;
+    };
+
+    let slp_typa = elements.first()?.as_integer(&aml_context).ok()?;
+    let slp_typb = elements
+        .get(1)
+        .and_then(|element| element.as_integer(&aml_context).ok())
+        .unwrap_or(slp_typa);
+
+    Some((slp_typa as u16 & 0x7, slp_typb as u16 & 0x7))
+}
+
+/// Shuts the NVMe controllers down and puts the system in S5, soft-off. Only returns if the
+/// firmware has no `\_S5` or no PM1 control block, or if the system is still running after the
+/// write, in which case the controllers are brought back.
+pub fn shutdown(acpi_ctx: &AcpiContext) -> Result<(), ()> {
+    let Some((slp_typa, slp_typb)) = s5_sleep_types(acpi_ctx) else {
+        println!("acpid: no usable \\_S5 object, cannot shut down");
+        return Err(());
+    };
+    let (Some(pm1a), pm1b) = pm1_control_blocks(acpi_ctx) else {
+        println!("acpid: no PM1 control block, cannot shut down");
+        return Err(());
+    };
+
+    let pts_args = Args::from_list(vec![AmlValue::Integer(5)]).map_err(|_| ())?;
+
+    shut_down_storage();
+
+    // Let the firmware prepare for S5. It is optional, so a missing \_PTS is fine.
+    if let Ok(name) = AmlName::from_str("\\_PTS") {
+        let mut aml_context = acpi_ctx.aml_context().lock();
+        if aml_context.namespace.get_by_path(&name).is_ok() {
+            if let Err(error) = aml_context.invoke_method(&name, pts_args) {
+                println!("acpid: \\_PTS failed: {:?}", error);
+            }
+        }
+    }
+
+    let mut handler = AmlHandler::new();
+
+    println!("acpid: entering S5");
+
+    // SLP_TYP is written first and SLP_EN on its own, as some chipsets latch the type only
+    // when SLP_EN goes from 0 to 1.
+    let registers = [Some((pm1a, slp_typa)), pm1b.map(|pm1b| (pm1b, slp_typb))];
+    for (register, slp_typ) in registers.iter().flatten() {
+        let value = register.read_u16(&handler) & !(SLP_TYP_MASK | SLP_EN);
+        register.write_u16(&mut handler, value | (slp_typ << SLP_TYP_SHIFT));
+    }
+    for (register, _) in registers.iter().flatten() {
+        let value = register.read_u16(&handler);
+        register.write_u16(&mut handler, value | SLP_EN);
+    }
+
+    handler.stall(GRACE_PERIOD_US);
+    println!("acpid: still running after entering S5");
+    resume_storage();
+    Err(())
+}
+
+/// Shuts the NVMe controllers down and resets the system through the FADT reset register,
+/// falling back to the keyboard controller. Only returns if neither worked, with the controllers
+/// brought back.
+pub fn reboot(acpi_ctx: &AcpiContext) -> Result<(), ()> {
+    let mut handler = AmlHandler::new();
+
+    shut_down_storage();
+
+    let reset = acpi_ctx.fadt().and_then(|fadt| {
+        if fadt.flags & RESET_REG_SUP == 0 {
+            return None;
+        }
+        let fadt2 = fadt.acpi_2_struct()?;
+        Some((Register::from_gas(&fadt2.reset_reg)?, fadt2.reset_value))
+    });
+
+    if let Some((register, value)) = reset {
+        println!("acpid: resetting through the FADT reset register");
+        register.write_u8(&mut handler, value);
+        handler.stall(GRACE_PERIOD_US);
+    }
+
+    println!("acpid: resetting through the keyboard controller");
+    unsafe {
+        let mut status = Port::<u8>::new(KBC_STATUS);
+        for _ in 0..1000 {
+            if status.read() & KBC_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            handler.stall(10);
+        }
+        Port::<u8>::new(KBC_COMMAND).write(KBC_PULSE_RESET);
+    }
+    handler.stall(GRACE_PERIOD_US);
+
+    println!("acpid: still running after reset");
+    resume_storage();
+    Err(())
+}
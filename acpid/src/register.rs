@@ -0,0 +1,125 @@
+//! Fixed hardware registers the FADT points at.
+
+use aml::Handler;
+
+use crate::{acpi::GenericAddressStructure, aml_handler::AmlHandler};
+
+/// A fixed hardware register, as found in the FADT.
+#[derive(Clone, Copy, Debug)]
+pub enum Register {
+    Memory(usize),
+    Io(u16),
+    /// Configuration space of a function on bus 0 of segment 0.
+    Pci {
+        device: u8,
+        function: u8,
+        offset: u16,
+    },
+}
+
+impl Register {
+    /// Decodes a generic address, if it is present and in a space acpid can reach.
+    pub fn from_gas(gas: &GenericAddressStructure) -> Option<Self> {
+        let address = gas.address;
+        if address == 0 {
+            return None;
+        }
+
+        match gas.address_space {
+            0 => Some(Self::Memory(usize::try_from(address).ok()?)),
+            1 => Some(Self::Io(u16::try_from(address).ok()?)),
+            2 => Some(Self::Pci {
+                device: (address >> 32) as u8,
+                function: (address >> 16) as u8,
+                offset: address as u16,
+            }),
+            _ => None,
+        }
+    }
+
+    /// A register from an ACPI 1.0 field, which is always an I/O port.
+    pub fn from_port(port: u32) -> Option<Self> {
+        match port {
+            0 => None,
+            port => Some(Self::Io(u16::try_from(port).ok()?)),
+        }
+    }
+
+    /// The register `offset` bytes further, for blocks made of several registers, unless
+    /// that is past the end of the address space.
+    pub fn offset(self, offset: u16) -> Option<Self> {
+        Some(match self {
+            Self::Memory(address) => Self::Memory(address.checked_add(usize::from(offset))?),
+            Self::Io(port) => Self::Io(port.checked_add(offset)?),
+            Self::Pci {
+                device,
+                function,
+                offset: base,
+            } => Self::Pci {
+                device,
+                function,
+                offset: base.checked_add(offset)?,
+            },
+        })
+    }
+
+    pub fn read_u8(&self, handler: &AmlHandler) -> u8 {
+        match *self {
+            Self::Memory(address) => handler.read_u8(address),
+            Self::Io(port) => handler.read_io_u8(port),
+            Self::Pci {
+                device,
+                function,
+                offset,
+            } => handler.read_pci_u8(0, 0, device, function, offset),
+        }
+    }
+
+    pub fn read_u16(&self, handler: &AmlHandler) -> u16 {
+        match *self {
+            Self::Memory(address) => handler.read_u16(address),
+            Self::Io(port) => handler.read_io_u16(port),
+            Self::Pci {
+                device,
+                function,
+                offset,
+            } => handler.read_pci_u16(0, 0, device, function, offset),
+        }
+    }
+
+    pub fn read_u32(&self, handler: &AmlHandler) -> u32 {
+        match *self {
+            Self::Memory(address) => handler.read_u32(address),
+            Self::Io(port) => handler.read_io_u32(port),
+            Self::Pci {
+                device,
+                function,
+                offset,
+            } => handler.read_pci_u32(0, 0, device, function, offset),
+        }
+    }
+
+    pub fn write_u16(&self, handler: &mut AmlHandler, value: u16) {
+        match *self {
+            Self::Memory(address) => handler.write_u16(address, value),
+            Self::Io(port) => handler.write_io_u16(port, value),
+            Self::Pci {
+                device,
+                function,
+                offset,
+            } => handler.write_pci_u16(0, 0, device, function, offset, value),
+        }
+    }
+
+    pub fn write_u8(&self, handler: &mut AmlHandler, value: u8) {
+        match *self {
+            Self::Memory(address) => handler.write_u8(address, value),
+            Self::Io(port) => handler.write_io_u8(port, value),
+            Self::Pci {
+                device,
+                function,
+                offset,
+            } => handler.write_pci_u8(0, 0, device, function, offset, value),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! `:acpi:report`: what acpid made of the firmware's tables.
+
+use core::fmt::Write;
+
+use rstd::alloc::string::String;
+
+use crate::acpi::{AcpiContext, TableOrigin};
+
+/// Lists every table, quarantined ones included, with its address or override file,
+/// revision, length and checksum status, each followed by the problems found in it.
+/// Problems not tied to a table come last.
+pub fn render(acpi_ctx: &AcpiContext) -> String {
+    let mut out = String::new();
+    let warnings = acpi_ctx.warnings().lock();
+
+    let tables = acpi_ctx
+        .origins()
+        .iter()
+        .cloned()
+        .zip(acpi_ctx.tables())
+        .map(|(origin, sdt)| (origin, sdt, false))
+        .chain(
+            acpi_ctx
+                .quarantine()
+                .iter()
+                .map(|(address, sdt)| (TableOrigin::Physical(*address), sdt, true)),
+        );
+
+    for (origin, sdt, quarantined) in tables {
+        write!(out, "{} ", sdt.signature().file_name()).unwrap();
+        match origin {
+            TableOrigin::Physical(address) => write!(out, "address={:#x}", address),
+            TableOrigin::Override(path) => write!(out, "override={}", path),
+        }
+        .unwrap();
+        writeln!(
+            out,
+            " revision={} length={} checksum={}{}",
+            { sdt.revision },
+            sdt.length(),
+            if sdt.checksum_valid() { "ok" } else { "bad" },
+            if quarantined { " quarantined" } else { "" }
+        )
+        .unwrap();
+
+        for (_, warning) in warnings
+            .iter()
+            .filter(|(table, _)| *table == Some(sdt.signature()))
+        {
+            writeln!(out, "  warning: {}", warning).unwrap();
+        }
+    }
+
+    for (_, warning) in warnings.iter().filter(|(table, _)| table.is_none()) {
+        writeln!(out, "warning: {}", warning).unwrap();
+    }
+
+    out
+}
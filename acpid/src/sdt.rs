@@ -0,0 +1,426 @@
+//! System description tables: their common header, validation, and the FADT. Loading them
+//! from physical memory is the daemon's business; everything here works on bytes.
+
+use core::{fmt, ops::Deref};
+
+use alloc::{string::String, sync::Arc};
+
+/// The raw SDT header struct, as defined by the ACPI specification.
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> SdtSignature {
+        SdtSignature {
+            signature: self.signature,
+            oem_id: self.oem_id,
+            oem_table_id: self.oem_table_id,
+        }
+    }
+    pub fn length(&self) -> usize {
+        self.length
+            .try_into()
+            .expect("expected usize to be at least 32 bits")
+    }
+}
+
+unsafe impl plain::Plain for SdtHeader {}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SdtSignature {
+    pub signature: [u8; 4],
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+}
+
+impl SdtSignature {
+    /// The name the table has under `:acpi:tables`: the signature, then the OEM ID and OEM
+    /// table ID in hex.
+    pub fn file_name(&self) -> String {
+        let mut name = String::from_utf8_lossy(&self.signature).into_owned();
+        name.push('-');
+        for byte in self.oem_id.iter() {
+            fmt::write(&mut name, format_args!("{:>02X}", byte)).unwrap();
+        }
+        name.push('-');
+        for byte in self.oem_table_id.iter() {
+            fmt::write(&mut name, format_args!("{:>02X}", byte)).unwrap();
+        }
+        name
+    }
+}
+
+impl fmt::Display for SdtSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}",
+            String::from_utf8_lossy(&self.signature),
+            String::from_utf8_lossy(&self.oem_id),
+            String::from_utf8_lossy(&self.oem_table_id)
+        )
+    }
+}
+
+fn parse_hex_digit(hex: u8) -> Option<u8> {
+    let hex = hex.to_ascii_lowercase();
+
+    if hex >= b'a' && hex <= b'f' {
+        Some(hex - b'a' + 10)
+    } else if hex >= b'0' && hex <= b'9' {
+        Some(hex - b'0')
+    } else {
+        None
+    }
+}
+
+fn parse_hex_2digit(hex: &[u8]) -> Option<u8> {
+    parse_hex_digit(hex[0])
+        .and_then(|most_significant| Some((most_significant << 4) | parse_hex_digit(hex[1])?))
+}
+
+fn parse_oem_id(hex: [u8; 12]) -> Option<[u8; 6]> {
+    Some([
+        parse_hex_2digit(&hex[0..2])?,
+        parse_hex_2digit(&hex[2..4])?,
+        parse_hex_2digit(&hex[4..6])?,
+        parse_hex_2digit(&hex[6..8])?,
+        parse_hex_2digit(&hex[8..10])?,
+        parse_hex_2digit(&hex[10..12])?,
+    ])
+}
+fn parse_oem_table_id(hex: [u8; 16]) -> Option<[u8; 8]> {
+    Some([
+        parse_hex_2digit(&hex[0..2])?,
+        parse_hex_2digit(&hex[2..4])?,
+        parse_hex_2digit(&hex[4..6])?,
+        parse_hex_2digit(&hex[6..8])?,
+        parse_hex_2digit(&hex[8..10])?,
+        parse_hex_2digit(&hex[10..12])?,
+        parse_hex_2digit(&hex[12..14])?,
+        parse_hex_2digit(&hex[14..16])?,
+    ])
+}
+
+/// Parses a table's [`SdtSignature::file_name`] back into its signature.
+pub fn parse_table(table: &[u8]) -> Option<SdtSignature> {
+    let signature_part = table.get(..4)?;
+    let first_hyphen = table.get(4)?;
+    let oem_id_part = table.get(5..17)?;
+    let second_hyphen = table.get(17)?;
+    let oem_table_part = table.get(18..34)?;
+
+    if *first_hyphen != b'-' {
+        return None;
+    }
+    if *second_hyphen != b'-' {
+        return None;
+    }
+
+    if table.len() > 34 {
+        return None;
+    }
+
+    Some(SdtSignature {
+        signature: <[u8; 4]>::try_from(signature_part)
+            .expect("expected 4-byte slice to be convertible into [u8; 4]"),
+        oem_id: {
+            let hex = <[u8; 12]>::try_from(oem_id_part)
+                .expect("expected 12-byte slice to be convertible into [u8; 12]");
+            parse_oem_id(hex)?
+        },
+        oem_table_id: {
+            let hex = <[u8; 16]>::try_from(oem_table_part)
+                .expect("expected 16-byte slice to be convertible into [u8; 16]");
+            parse_oem_table_id(hex)?
+        },
+    })
+}
+
+#[derive(Debug)]
+pub enum InvalidSdtError {
+    InvalidSize,
+    BadChecksum,
+}
+
+impl fmt::Display for InvalidSdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSize => write!(f, "Invalid size of sdt"),
+            Self::BadChecksum => write!(f, "Bad checksum for sdt"),
+        }
+    }
+}
+
+/// No sane table comes close; a larger length is garbage, and copying it could exhaust memory.
+pub const MAX_TABLE_LENGTH: usize = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct Sdt(Arc<[u8]>);
+
+impl Sdt {
+    pub fn new(slice: Arc<[u8]>) -> Result<Self, InvalidSdtError> {
+        let sdt = Self::new_ignoring_checksum(slice)?;
+
+        if !sdt.checksum_valid() {
+            return Err(InvalidSdtError::BadChecksum);
+        }
+
+        Ok(sdt)
+    }
+
+    /// Like [`Self::new`], but accepts a table whose bytes do not sum to zero. Firmware gets
+    /// the checksum wrong more often than the contents.
+    pub fn new_ignoring_checksum(slice: Arc<[u8]>) -> Result<Self, InvalidSdtError> {
+        let header = match plain::from_bytes::<SdtHeader>(&slice) {
+            Ok(header) => header,
+            Err(plain::Error::TooShort) => return Err(InvalidSdtError::InvalidSize),
+            Err(plain::Error::BadAlignment) => panic!(
+                "plain::from_bytes failed due to alignment, but SdtHeader is #[repr(packed)]!"
+            ),
+        };
+
+        if header.length() != slice.len() {
+            return Err(InvalidSdtError::InvalidSize);
+        }
+
+        Ok(Self(slice))
+    }
+
+    pub fn checksum_valid(&self) -> bool {
+        let checksum = self
+            .0
+            .iter()
+            .copied()
+            .fold(0_u8, |current_sum, item| current_sum.wrapping_add(item));
+
+        checksum == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for Sdt {
+    type Target = SdtHeader;
+
+    fn deref(&self) -> &Self::Target {
+        plain::from_bytes::<SdtHeader>(&self.0)
+            .expect("expected already validated Sdt to be able to get its header")
+    }
+}
+
+impl Sdt {
+    pub fn data(&self) -> &[u8] {
+        &self.0[core::mem::size_of::<SdtHeader>()..]
+    }
+}
+
+impl fmt::Debug for Sdt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sdt")
+            .field("header", &*self as &SdtHeader)
+            .field("extra_len", &self.data().len())
+            .finish()
+    }
+}
+
+pub struct Dsdt(pub Sdt);
+pub struct Ssdt(pub Sdt);
+
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct FadtStruct {
+    pub header: SdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+
+    // field used in ACPI 1.0; no longer in use, for compatibility only
+    #[allow(dead_code)]
+    reserved: u8,
+
+    pub preferred_power_managament: u8,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4_bios_req: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_ength: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub c_state_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+
+    // reserved in ACPI 1.0; used since ACPI 2.0+
+    pub boot_architecture_flags: u16,
+
+    #[allow(dead_code)]
+    reserved2: u8,
+    pub flags: u32,
+}
+unsafe impl plain::Plain for FadtStruct {}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GenericAddressStructure {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct FadtAcpi2Struct {
+    // 12 byte structure; see below for details
+    pub reset_reg: GenericAddressStructure,
+
+    pub reset_value: u8,
+    #[allow(dead_code)]
+    reserved3: [u8; 3],
+
+    // 64bit pointers - Available on ACPI 2.0+
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+
+    pub x_pm1a_event_block: GenericAddressStructure,
+    pub x_pm1b_event_block: GenericAddressStructure,
+    pub x_pm1a_control_block: GenericAddressStructure,
+    pub x_pm1b_control_block: GenericAddressStructure,
+    pub x_pm2_control_block: GenericAddressStructure,
+    pub x_pm_timer_block: GenericAddressStructure,
+    pub x_gpe0_block: GenericAddressStructure,
+    pub x_gpe1_block: GenericAddressStructure,
+}
+unsafe impl plain::Plain for FadtAcpi2Struct {}
+
+#[derive(Clone)]
+pub struct Fadt(Sdt);
+
+impl Fadt {
+    pub fn acpi_2_struct(&self) -> Option<&FadtAcpi2Struct> {
+        let bytes = &self.0.0[core::mem::size_of::<FadtStruct>()..];
+
+        match plain::from_bytes::<FadtAcpi2Struct>(bytes) {
+            Ok(fadt2) => Some(fadt2),
+            Err(plain::Error::TooShort) => None,
+            Err(plain::Error::BadAlignment) => unreachable!(
+                "plain::from_bytes reported bad alignment, but FadtAcpi2Struct is #[repr(packed)]"
+            ),
+        }
+    }
+}
+
+impl Deref for Fadt {
+    type Target = FadtStruct;
+
+    fn deref(&self) -> &Self::Target {
+        plain::from_bytes::<FadtStruct>(&self.0.0)
+            .expect("expected FADT struct to already be validated in Deref impl")
+    }
+}
+
+impl Fadt {
+    pub fn new(sdt: Sdt) -> Option<Fadt> {
+        if sdt.signature != *b"FACP" || sdt.length() < core::mem::size_of::<FadtStruct>() {
+            return None;
+        }
+        Some(Fadt(sdt))
+    }
+}
+
+pub enum PossibleAmlTables {
+    Dsdt(Dsdt),
+    Ssdt(Ssdt),
+}
+impl PossibleAmlTables {
+    pub fn try_new(inner: Sdt) -> Option<Self> {
+        match &inner.signature {
+            b"DSDT" => Some(Self::Dsdt(Dsdt(inner))),
+            b"SSDT" => Some(Self::Ssdt(Ssdt(inner))),
+            _ => None,
+        }
+    }
+}
+impl AmlContainingTable for PossibleAmlTables {
+    fn aml(&self) -> &[u8] {
+        match self {
+            Self::Dsdt(dsdt) => dsdt.aml(),
+            Self::Ssdt(ssdt) => ssdt.aml(),
+        }
+    }
+    fn header(&self) -> &SdtHeader {
+        match self {
+            Self::Dsdt(dsdt) => dsdt.header(),
+            Self::Ssdt(ssdt) => ssdt.header(),
+        }
+    }
+}
+
+pub trait AmlContainingTable {
+    fn aml(&self) -> &[u8];
+    fn header(&self) -> &SdtHeader;
+}
+
+impl<T> AmlContainingTable for &T
+where
+    T: AmlContainingTable,
+{
+    fn aml(&self) -> &[u8] {
+        T::aml(*self)
+    }
+    fn header(&self) -> &SdtHeader {
+        T::header(*self)
+    }
+}
+
+impl AmlContainingTable for Dsdt {
+    fn aml(&self) -> &[u8] {
+        self.0.data()
+    }
+    fn header(&self) -> &SdtHeader {
+        &*self.0
+    }
+}
+impl AmlContainingTable for Ssdt {
+    fn aml(&self) -> &[u8] {
+        self.0.data()
+    }
+    fn header(&self) -> &SdtHeader {
+        &*self.0
+    }
+}
@@ -0,0 +1,70 @@
+//! The System Locality Distance Information Table, the relative memory latency between NUMA
+//! proximity domains, decoded for `:acpi:slit`.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{bytes::u64_at, sdt::Sdt};
+
+#[derive(Clone, Debug)]
+pub struct Slit {
+    pub localities: u64,
+    /// Row `i` holds the distances from domain `i` to every domain. A domain is 10 from
+    /// itself, and 255 from a domain it cannot reach.
+    pub distances: Vec<Vec<u8>>,
+    /// Problems met while decoding.
+    pub warnings: Vec<String>,
+}
+
+impl Slit {
+    /// Decodes a `SLIT` table. A matrix that does not fit the table is left out.
+    pub fn parse(sdt: &Sdt) -> Option<Self> {
+        if &sdt.signature != b"SLIT" {
+            return None;
+        }
+
+        let data = sdt.data();
+        let mut slit = Self {
+            localities: u64_at(data, 0)?,
+            distances: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        let matrix = usize::try_from(slit.localities)
+            .ok()
+            .and_then(|localities| Some((localities, localities.checked_mul(localities)?)))
+            .and_then(|(localities, len)| Some((localities, data.get(8..)?.get(..len)?)));
+        match matrix {
+            Some((localities, matrix)) if localities > 0 => {
+                slit.distances = matrix
+                    .chunks_exact(localities)
+                    .map(<[u8]>::to_vec)
+                    .collect();
+            }
+            Some(_) => {}
+            None => slit
+                .warnings
+                .push(format!("SLIT too short for {} localities", slit.localities)),
+        }
+
+        Some(slit)
+    }
+
+    /// Renders the table as text: the number of localities, then one line per domain with
+    /// its distances to every domain.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "localities={}", self.localities).unwrap();
+        for (domain, row) in self.distances.iter().enumerate() {
+            write!(out, "{}:", domain).unwrap();
+            for distance in row {
+                write!(out, " {}", distance).unwrap();
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+}
@@ -0,0 +1,145 @@
+//! The System Resource Affinity Table, which puts processors and memory in NUMA proximity
+//! domains, decoded for `:acpi:srat`.
+
+use core::fmt::Write;
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    bytes::{u32_at, u64_at},
+    sdt::Sdt,
+};
+
+const PROCESSOR_AFFINITY: u8 = 0;
+const MEMORY_AFFINITY: u8 = 1;
+const X2APIC_AFFINITY: u8 = 2;
+
+/// Flag of every affinity structure: the structure is in use. Firmware lists disabled ones for
+/// processors and memory that may be hot-added.
+pub const ENABLED: u32 = 1 << 0;
+
+/// Where the affinity structures start, past the reserved bytes.
+const STRUCTURES_OFFSET: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SratEntry {
+    /// The domain of the processor with a local APIC or x2APIC ID.
+    Processor {
+        apic_id: u32,
+        domain: u32,
+        flags: u32,
+        x2apic: bool,
+    },
+    /// The domain of a range of physical memory.
+    Memory {
+        domain: u32,
+        base: u64,
+        length: u64,
+        flags: u32,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Srat {
+    pub entries: Vec<SratEntry>,
+    /// Problems met while decoding. The structures they concern are left out.
+    pub warnings: Vec<String>,
+}
+
+fn decode_entry(typ: u8, entry: &[u8]) -> Option<SratEntry> {
+    Some(match typ {
+        PROCESSOR_AFFINITY => SratEntry::Processor {
+            apic_id: (*entry.get(3)?).into(),
+            // The low byte of the domain comes first, the three high bytes later on.
+            domain: u32::from(*entry.get(2)?) | (u32_at(entry, 8)? & !0xFF),
+            flags: u32_at(entry, 4)?,
+            x2apic: false,
+        },
+        X2APIC_AFFINITY => SratEntry::Processor {
+            domain: u32_at(entry, 4)?,
+            apic_id: u32_at(entry, 8)?,
+            flags: u32_at(entry, 12)?,
+            x2apic: true,
+        },
+        MEMORY_AFFINITY => SratEntry::Memory {
+            domain: u32_at(entry, 2)?,
+            base: u64_at(entry, 8)?,
+            length: u64_at(entry, 16)?,
+            flags: u32_at(entry, 28)?,
+        },
+        _ => return None,
+    })
+}
+
+impl Srat {
+    /// Decodes an `SRAT` table. Structures other than processor and memory affinities are
+    /// skipped; a structure running past the end of the table ends the list.
+    pub fn parse(sdt: &Sdt) -> Option<Self> {
+        if &sdt.signature != b"SRAT" {
+            return None;
+        }
+
+        let mut srat = Self {
+            entries: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        let mut rest = sdt.data().get(STRUCTURES_OFFSET..).unwrap_or_default();
+        while let [typ, len, ..] = *rest {
+            let len = usize::from(len);
+            let Some(entry) = rest.get(..len).filter(|_| len >= 2) else {
+                srat.warnings
+                    .push(format!("truncated SRAT structure of type {}", typ));
+                break;
+            };
+            rest = &rest[len..];
+
+            if let PROCESSOR_AFFINITY | MEMORY_AFFINITY | X2APIC_AFFINITY = typ {
+                match decode_entry(typ, entry) {
+                    Some(decoded) => srat.entries.push(decoded),
+                    None => srat
+                        .warnings
+                        .push(format!("short SRAT structure of type {}", typ)),
+                }
+            }
+        }
+
+        Some(srat)
+    }
+
+    /// Renders the table as text in the format of `:acpi:madt`, one structure per line.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        for entry in &self.entries {
+            match *entry {
+                SratEntry::Processor {
+                    apic_id,
+                    domain,
+                    flags,
+                    x2apic,
+                } => writeln!(
+                    out,
+                    "{} id={} domain={} flags={:#x}",
+                    if x2apic { "x2apic" } else { "local_apic" },
+                    apic_id,
+                    domain,
+                    flags
+                ),
+                SratEntry::Memory {
+                    domain,
+                    base,
+                    length,
+                    flags,
+                } => writeln!(
+                    out,
+                    "memory base={:#x} length={:#x} domain={} flags={:#x}",
+                    base, length, domain, flags
+                ),
+            }
+            .unwrap();
+        }
+
+        out
+    }
+}
@@ -0,0 +1,576 @@
+use std::{fs, path::PathBuf, sync::Arc};
+
+use acpid::{
+    dmar::{DRHD_INCLUDE_PCI_ALL, DeviceScope, Dmar, DmarEntry},
+    fpdt::{Fpdt, FpdtRecord},
+    hpet::Hpet,
+    ivrs::{IvhdDevice, Ivrs, IvrsEntry},
+    madt::{ALL_PROCESSORS, Madt, MadtEntry},
+    sdt::{
+        AmlContainingTable, Fadt, InvalidSdtError, PossibleAmlTables, Sdt, SdtSignature,
+        parse_table,
+    },
+    slit::Slit,
+    srat::{Srat, SratEntry},
+};
+
+fn corpus() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/tables")
+}
+
+/// The files of the dump in `dir`, by name.
+fn files(dir: &str) -> Vec<(String, Vec<u8>)> {
+    let mut files = fs::read_dir(corpus().join(dir))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "dat" || extension == "aml")
+        })
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).unwrap())
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// Every dump of valid tables.
+fn machines() -> Vec<String> {
+    let mut machines = fs::read_dir(corpus())
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().unwrap().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name != "broken")
+        .collect::<Vec<_>>();
+    machines.sort();
+    machines
+}
+
+fn tables(machine: &str) -> Vec<Sdt> {
+    files(machine)
+        .into_iter()
+        .map(|(name, bytes)| {
+            Sdt::new(Arc::from(bytes))
+                .unwrap_or_else(|error| panic!("{}/{}: {}", machine, name, error))
+        })
+        .collect()
+}
+
+fn table(machine: &str, signature: &[u8; 4]) -> Sdt {
+    tables(machine)
+        .into_iter()
+        .find(|sdt| &sdt.signature == signature)
+        .unwrap()
+}
+
+fn broken(name: &str) -> Arc<[u8]> {
+    Arc::from(fs::read(corpus().join("broken").join(name)).unwrap())
+}
+
+#[test]
+fn every_dump_loads() {
+    let machines = machines();
+    assert!(!machines.is_empty());
+
+    for machine in machines {
+        let tables = tables(&machine);
+
+        for sdt in &tables {
+            assert_eq!(sdt.length(), sdt.as_slice().len());
+
+            let name = sdt.signature().file_name();
+            assert_eq!(
+                parse_table(name.as_bytes()),
+                Some(sdt.signature()),
+                "{}",
+                name
+            );
+        }
+
+        let facp = tables.iter().find(|sdt| &sdt.signature == b"FACP");
+        assert!(
+            Fadt::new(facp.unwrap().clone()).is_some(),
+            "{}: no usable FADT",
+            machine
+        );
+        let dsdt = tables.iter().find(|sdt| &sdt.signature == b"DSDT");
+        let dsdt = PossibleAmlTables::try_new(dsdt.unwrap().clone()).unwrap();
+        assert!(!dsdt.aml().is_empty(), "{}: empty DSDT", machine);
+    }
+}
+
+#[test]
+fn every_dump_decodes_cleanly() {
+    for machine in machines() {
+        for sdt in tables(&machine) {
+            let warnings = match &sdt.signature {
+                b"APIC" => Madt::parse(&sdt).unwrap().warnings,
+                b"DMAR" => Dmar::parse(&sdt).unwrap().warnings,
+                b"IVRS" => Ivrs::parse(&sdt).unwrap().warnings,
+                b"SRAT" => Srat::parse(&sdt).unwrap().warnings,
+                b"SLIT" => Slit::parse(&sdt).unwrap().warnings,
+                b"FPDT" => Fpdt::parse(&sdt).unwrap().warnings,
+                b"HPET" => {
+                    Hpet::parse(&sdt).unwrap();
+                    Vec::new()
+                }
+                _ => continue,
+            };
+            assert_eq!(
+                warnings,
+                Vec::<String>::new(),
+                "{}: {}",
+                machine,
+                sdt.signature().file_name()
+            );
+        }
+    }
+}
+
+#[test]
+fn fadt_fields() {
+    let fadt = Fadt::new(table("synthetic", b"FACP")).unwrap();
+
+    assert_eq!({ fadt.dsdt }, 0x7FFE_0040);
+    assert_eq!({ fadt.sci_interrupt }, 9);
+    assert_eq!({ fadt.smi_command_port }, 0xB2);
+    assert_eq!((fadt.acpi_enable, fadt.acpi_disable), (0xF1, 0xF0));
+    assert_eq!({ fadt.pm1a_event_block }, 0x600);
+    assert_eq!({ fadt.pm1a_control_block }, 0x604);
+    assert_eq!({ fadt.gpe0_block }, 0x620);
+    assert_eq!(fadt.pm1_event_length, 4);
+    assert_eq!(fadt.gpe0_ength, 16);
+    assert_eq!({ fadt.flags }, (1 << 4) | (1 << 8) | (1 << 10));
+
+    let fadt2 = fadt.acpi_2_struct().unwrap();
+    assert_eq!({ fadt2.x_dsdt }, 0x7FFE_0040);
+    assert_eq!({ fadt2.reset_reg.address }, 0xCF9);
+    assert_eq!(fadt2.reset_reg.address_space, 1);
+    assert_eq!(fadt2.reset_value, 6);
+    assert_eq!({ fadt2.x_pm1a_control_block.address }, 0x604);
+    assert_eq!(fadt2.x_gpe0_block.bit_width, 128);
+}
+
+#[test]
+fn fadt_rejects_short_and_foreign_tables() {
+    let short = Sdt::new(broken("short-facp.dat")).unwrap();
+    assert!(Fadt::new(short).is_none());
+
+    assert!(Fadt::new(table("synthetic", b"APIC")).is_none());
+}
+
+#[test]
+fn firecracker_tables() {
+    let fadt = Fadt::new(table("firecracker", b"FACP")).unwrap();
+    // Hardware-reduced, so no PM or GPE blocks, and only the 64-bit DSDT pointer is set.
+    assert_eq!({ fadt.flags }, (1 << 4) | (1 << 5) | (1 << 20));
+    assert_eq!({ fadt.pm1a_event_block }, 0);
+    assert_eq!({ fadt.dsdt }, 0);
+    assert_eq!({ fadt.acpi_2_struct().unwrap().x_dsdt }, 0x9_FD30);
+
+    let madt = Madt::parse(&table("firecracker", b"APIC")).unwrap();
+    assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+    assert_eq!(
+        madt.entries,
+        [
+            MadtEntry::IoApic {
+                id: 0,
+                address: 0xFEC0_0000,
+                gsi_base: 0,
+            },
+            MadtEntry::LocalApic {
+                processor_uid: 0,
+                apic_id: 0,
+                flags: 1,
+                x2apic: false,
+            },
+        ]
+    );
+}
+
+#[test]
+fn aml_tables() {
+    let dsdt = PossibleAmlTables::try_new(table("synthetic", b"DSDT")).unwrap();
+    assert!(matches!(dsdt, PossibleAmlTables::Dsdt(_)));
+    // Name (_S5, Package () { 5, 5 })
+    assert_eq!(
+        dsdt.aml(),
+        [
+            0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0A, 0x05, 0x0A, 0x05
+        ]
+    );
+    assert_eq!(&dsdt.header().signature, b"DSDT");
+
+    let ssdts = tables("synthetic")
+        .into_iter()
+        .filter_map(PossibleAmlTables::try_new)
+        .filter(|table| matches!(table, PossibleAmlTables::Ssdt(_)))
+        .count();
+    assert_eq!(ssdts, 2);
+
+    assert!(PossibleAmlTables::try_new(table("synthetic", b"HPET")).is_none());
+}
+
+#[test]
+fn sdt_rejects_broken_tables() {
+    assert!(matches!(
+        Sdt::new(broken("bad-checksum.dat")),
+        Err(InvalidSdtError::BadChecksum)
+    ));
+    let sdt = Sdt::new_ignoring_checksum(broken("bad-checksum.dat")).unwrap();
+    assert!(!sdt.checksum_valid());
+
+    for name in ["truncated.dat", "short-header.dat"] {
+        assert!(
+            matches!(
+                Sdt::new_ignoring_checksum(broken(name)),
+                Err(InvalidSdtError::InvalidSize)
+            ),
+            "{}",
+            name
+        );
+    }
+    assert!(matches!(
+        Sdt::new_ignoring_checksum(Arc::from(Vec::new())),
+        Err(InvalidSdtError::InvalidSize)
+    ));
+}
+
+#[test]
+fn table_file_names() {
+    let signature = SdtSignature {
+        signature: *b"SSDT",
+        oem_id: *b"ACPID ",
+        oem_table_id: *b"CPUS    ",
+    };
+    let name = signature.file_name();
+    assert_eq!(name, "SSDT-414350494420-4350555320202020");
+    assert_eq!(parse_table(name.as_bytes()), Some(signature));
+    assert_eq!(
+        parse_table(name.to_ascii_lowercase().replace("ssdt", "SSDT").as_bytes()),
+        Some(signature)
+    );
+
+    for bad in [
+        "",
+        "SSDT",
+        "SSDT-414350494420",
+        "SSDT_414350494420-4350555320202020",
+        "SSDT-414350494420_4350555320202020",
+        "SSDT-41435049442G-4350555320202020",
+        "SSDT-414350494420-43505553202020200",
+    ] {
+        assert_eq!(parse_table(bad.as_bytes()), None, "{}", bad);
+    }
+}
+
+#[test]
+fn madt_entries() {
+    let madt = Madt::parse(&table("synthetic", b"APIC")).unwrap();
+
+    assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+    assert_eq!(madt.flags, 1);
+    assert_eq!(
+        madt.entries,
+        [
+            MadtEntry::LocalApic {
+                processor_uid: 0,
+                apic_id: 0,
+                flags: 1,
+                x2apic: false,
+            },
+            MadtEntry::LocalApic {
+                processor_uid: 1,
+                apic_id: 1,
+                flags: 1,
+                x2apic: false,
+            },
+            MadtEntry::IoApic {
+                id: 0,
+                address: 0xFEC0_0000,
+                gsi_base: 0,
+            },
+            MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source: 0,
+                gsi: 2,
+                flags: 0,
+            },
+            MadtEntry::InterruptSourceOverride {
+                bus: 0,
+                source: 9,
+                gsi: 9,
+                flags: 0xD,
+            },
+            MadtEntry::LocalApicNmi {
+                processor_uid: ALL_PROCESSORS,
+                lint: 1,
+                flags: 0,
+            },
+        ]
+    );
+}
+
+#[test]
+fn madt_truncated_entry() {
+    let madt = Madt::parse(&Sdt::new(broken("truncated-madt-entry.dat")).unwrap()).unwrap();
+
+    assert_eq!(madt.entries.len(), 1);
+    assert_eq!(madt.warnings, ["truncated MADT entry of type 1"]);
+}
+
+#[test]
+fn hpet_fields() {
+    let hpet = Hpet::parse(&table("synthetic", b"HPET")).unwrap();
+
+    assert_eq!(hpet.address, 0xFED0_0000);
+    assert_eq!(hpet.address_space, 0);
+    assert_eq!(hpet.comparators, 3);
+    assert!(hpet.counter_64bit);
+    assert!(hpet.legacy_replacement);
+    assert_eq!(hpet.pci_vendor_id, 0x8086);
+    assert_eq!(hpet.hardware_revision, 1);
+    assert_eq!(hpet.minimum_tick, 128);
+}
+
+#[test]
+fn srat_and_slit() {
+    let srat = Srat::parse(&table("synthetic", b"SRAT")).unwrap();
+    assert_eq!(
+        srat.entries,
+        [
+            SratEntry::Processor {
+                apic_id: 0,
+                domain: 0,
+                flags: 1,
+                x2apic: false,
+            },
+            SratEntry::Processor {
+                apic_id: 1,
+                domain: 1,
+                flags: 1,
+                x2apic: false,
+            },
+            SratEntry::Memory {
+                domain: 0,
+                base: 0,
+                length: 0xA_0000,
+                flags: 1,
+            },
+            SratEntry::Memory {
+                domain: 0,
+                base: 0x10_0000,
+                length: 0x3FF0_0000,
+                flags: 1,
+            },
+            SratEntry::Memory {
+                domain: 1,
+                base: 0x4000_0000,
+                length: 0x4000_0000,
+                flags: 3,
+            },
+            SratEntry::Processor {
+                apic_id: 0x100,
+                domain: 1,
+                flags: 1,
+                x2apic: true,
+            },
+        ]
+    );
+
+    let slit = Slit::parse(&table("synthetic", b"SLIT")).unwrap();
+    assert_eq!(slit.localities, 2);
+    assert_eq!(slit.distances, [[10, 20], [20, 10]]);
+    assert_eq!(slit.to_text(), "localities=2\n0: 10 20\n1: 20 10\n");
+}
+
+#[test]
+fn fpdt_records() {
+    let mut fpdt = Fpdt::parse(&table("synthetic", b"FPDT")).unwrap();
+    assert_eq!(fpdt.fbpt, Some(0x7FFE_1000));
+    assert_eq!(fpdt.s3pt, None);
+
+    // The FBPT lives in firmware memory rather than in the dump.
+    let mut fbpt = b"FBPT".to_vec();
+    fbpt.extend_from_slice(&56_u32.to_le_bytes());
+    fbpt.extend_from_slice(&[2, 0, 48, 2, 0, 0, 0, 0]);
+    for time in [1_000_u64, 2_000, 3_000, 4_000, 5_000] {
+        fbpt.extend_from_slice(&time.to_le_bytes());
+    }
+    fpdt.add_fbpt(&fbpt);
+
+    assert_eq!(
+        fpdt.records,
+        [FpdtRecord::BasicBoot {
+            reset_end: 1_000,
+            load_image_start: 2_000,
+            start_image_start: 3_000,
+            exit_boot_services_entry: 4_000,
+            exit_boot_services_exit: 5_000,
+        }]
+    );
+    assert!(fpdt.warnings.is_empty());
+
+    fpdt.add_s3pt(&fbpt);
+    assert_eq!(fpdt.warnings, ["bad S3PT header"]);
+}
+
+fn scope(typ: u8, enumeration_id: u8, start_bus: u8, device: u8) -> DeviceScope {
+    DeviceScope {
+        typ,
+        enumeration_id,
+        start_bus,
+        path: vec![(device, 0)],
+    }
+}
+
+#[test]
+fn dmar_entries() {
+    let dmar = Dmar::parse(&table("synthetic", b"DMAR")).unwrap();
+
+    assert_eq!(dmar.host_address_width, 38);
+    assert_eq!(dmar.flags, 1);
+    assert_eq!(
+        dmar.entries,
+        [
+            DmarEntry::Drhd {
+                flags: 0,
+                segment: 0,
+                register_base: 0xFED9_0000,
+                scopes: vec![scope(1, 0, 0, 0x02)],
+            },
+            DmarEntry::Drhd {
+                flags: DRHD_INCLUDE_PCI_ALL,
+                segment: 0,
+                register_base: 0xFED9_1000,
+                scopes: vec![scope(3, 2, 0xF0, 0x1F), scope(4, 0, 0, 0x1F)],
+            },
+            DmarEntry::Rmrr {
+                segment: 0,
+                base: 0x7D00_0000,
+                limit: 0x7D7F_FFFF,
+                scopes: vec![scope(1, 0, 0, 0x14)],
+            },
+            DmarEntry::Atsr {
+                flags: 0,
+                segment: 0,
+                scopes: vec![scope(2, 0, 0, 0x1C)],
+            },
+        ]
+    );
+    assert!(dmar.to_text().starts_with(concat!(
+        "host_address_width=39\n",
+        "flags=0x1\n",
+        "drhd segment=0 base=0xfed90000 flags=0x0\n",
+        "  scope type=1 id=0 bus=0 path=02.0\n",
+    )));
+}
+
+#[test]
+fn dmar_broken_lengths() {
+    for (name, entries, warning) in [
+        (
+            "dmar-zero-length.dat",
+            1,
+            "truncated DMAR structure of type 1",
+        ),
+        (
+            "dmar-oversized.dat",
+            0,
+            "truncated DMAR structure of type 0",
+        ),
+        (
+            "dmar-zero-length-scope.dat",
+            1,
+            "truncated DMAR device scope of type 1",
+        ),
+    ] {
+        let dmar = Dmar::parse(&Sdt::new(broken(name)).unwrap()).unwrap();
+        assert_eq!(dmar.entries.len(), entries, "{}", name);
+        assert_eq!(dmar.warnings, [warning], "{}", name);
+    }
+}
+
+#[test]
+fn ivrs_entries() {
+    let ivrs = Ivrs::parse(&table("synthetic", b"IVRS")).unwrap();
+
+    assert_eq!(ivrs.info, 0x0020_3041);
+    assert_eq!(
+        ivrs.entries,
+        [
+            IvrsEntry::Ivhd {
+                typ: 0x10,
+                flags: 0xB0,
+                device_id: 0x0002,
+                segment: 0,
+                base: 0xFEB8_0000,
+                devices: vec![
+                    IvhdDevice::Range {
+                        first: 0x0000,
+                        last: 0x00FF,
+                        setting: 0,
+                    },
+                    IvhdDevice::Range {
+                        first: 0x0100,
+                        last: 0x0100,
+                        setting: 0xD7,
+                    },
+                    IvhdDevice::Alias {
+                        first: 0x0300,
+                        last: 0x0300,
+                        source: 0x0208,
+                        setting: 0,
+                    },
+                    IvhdDevice::Special {
+                        variety: 1,
+                        handle: 0x21,
+                        device_id: 0x00A0,
+                        setting: 0xD7,
+                    },
+                    IvhdDevice::AcpiDevice {
+                        hid: *b"AMDI0020",
+                        device_id: 0x00A5,
+                        setting: 0x40,
+                    },
+                ],
+            },
+            IvrsEntry::Ivmd {
+                typ: 0x21,
+                flags: 0x07,
+                first: 0x0010,
+                last: 0x0010,
+                start: 0xC000_0000,
+                length: 0x10_0000,
+            },
+        ]
+    );
+    assert!(
+        ivrs.to_text()
+            .contains("  special variety=1 handle=33 device=00:14.0 setting=0xd7\n")
+    );
+}
+
+#[test]
+fn ivrs_broken_lengths() {
+    for (name, entries, warning) in [
+        (
+            "ivrs-zero-length.dat",
+            1,
+            "truncated IVRS block of type 0x10",
+        ),
+        ("ivrs-oversized.dat", 0, "truncated IVRS block of type 0x10"),
+        (
+            "ivrs-oversized-device.dat",
+            1,
+            "bad IVHD device entry of type 0xf0",
+        ),
+    ] {
+        let ivrs = Ivrs::parse(&Sdt::new(broken(name)).unwrap()).unwrap();
+        assert_eq!(ivrs.entries.len(), entries, "{}", name);
+        assert_eq!(ivrs.warnings, [warning], "{}", name);
+    }
+}
@@ -3,6 +3,9 @@ use spin::RwLock;
 
 use crate::inode::{Inode, InodeRef};
 
+/// nvmed's ioctl committing a disk's write cache to media.
+const NVME_IOCTL_FLUSH: usize = 1;
+
 pub struct DevInode {
     path: String,
     inner: usize,
@@ -36,11 +39,18 @@ impl Inode for DevInode {
         return len as usize;
     }
 
-    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, ()> {
         rstd::fs::lseek(self.inner, offset);
         let len = rstd::fs::write(self.inner, buf.as_ptr() as usize, buf.len());
         rstd::fs::lseek(self.inner, 0);
-        return len as usize;
+        usize::try_from(len).map_err(|_| ())
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        if rstd::fs::ioctl(self.inner, NVME_IOCTL_FLUSH, 0) < 0 {
+            return Err(());
+        }
+        Ok(())
     }
 
     fn size(&self) -> usize {
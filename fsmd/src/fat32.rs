@@ -34,7 +34,7 @@ impl Read for InodeRefIO {
 
 impl Write for InodeRefIO {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        self.inode.read().write_at(self.offset, buf);
+        self.inode.read().write_at(self.offset, buf)?;
         self.seek(SeekFrom::Current(buf.len() as i64))?;
         Ok(buf.len())
     }
@@ -60,17 +60,19 @@ impl Seek for InodeRefIO {
 
 pub struct Fat32Volume {
     vol: &'static mut FileSystem<InodeRefIO, NullTimeProvider, LossyOemCpConverter>,
+    dev: InodeRef,
     virtual_inodes: BTreeMap<String, InodeRef>,
     path: String,
 }
 
 impl Fat32Volume {
     pub fn new(dev: InodeRef) -> Option<InodeRef> {
-        let io = InodeRefIO::new(dev);
+        let io = InodeRefIO::new(dev.clone());
         let vol = FileSystem::new(io, FsOptions::new()).ok()?;
 
         let inode = Self {
             vol: Box::leak(Box::new(vol)),
+            dev,
             virtual_inodes: BTreeMap::new(),
             path: String::new(),
         };
@@ -112,13 +114,17 @@ impl Inode for Fat32Volume {
         if let Some(inode) = self.virtual_inodes.get(&name) {
             return Some(inode.clone());
         } else if let Ok(dir) = dir.open_dir(name.as_str()) {
-            let inode = Fat32Dir::new(Arc::new(dir), cluster_size);
+            let inode = Fat32Dir::new(Arc::new(dir), self.dev.clone(), cluster_size);
             inode
                 .write()
                 .when_mounted(self.get_path() + name.as_str() + "/", self_inode);
             return Some(inode);
         } else if let Ok(file) = dir.open_file(name.as_str()) {
-            let inode = Arc::new(RwLock::new(Fat32File::new(Arc::new(file), cluster_size)));
+            let inode = Arc::new(RwLock::new(Fat32File::new(
+                Arc::new(file),
+                self.dev.clone(),
+                cluster_size,
+            )));
             inode
                 .write()
                 .when_mounted(self.get_path() + name.as_str(), self_inode);
@@ -167,15 +173,17 @@ impl Inode for Fat32Volume {
 
 pub struct Fat32Dir {
     dir: Arc<FatDir>,
+    dev: InodeRef,
     path: String,
     cluster_size: usize,
     virtual_inodes: BTreeMap<String, InodeRef>,
 }
 
 impl Fat32Dir {
-    pub(self) fn new(dir: Arc<FatDir>, cluster_size: usize) -> InodeRef {
+    pub(self) fn new(dir: Arc<FatDir>, dev: InodeRef, cluster_size: usize) -> InodeRef {
         let inode = Self {
             dir,
+            dev,
             path: String::new(),
             cluster_size,
             virtual_inodes: BTreeMap::new(),
@@ -215,7 +223,7 @@ impl Inode for Fat32Dir {
         if let Some(inode) = self.virtual_inodes.get(&name) {
             return Some(inode.clone());
         } else if let Ok(dir) = self.dir.open_dir(name.as_str()) {
-            let inode = Fat32Dir::new(Arc::new(dir), self.cluster_size);
+            let inode = Fat32Dir::new(Arc::new(dir), self.dev.clone(), self.cluster_size);
             inode
                 .write()
                 .when_mounted(self.get_path() + name.as_str() + "/", self_inode);
@@ -223,6 +231,7 @@ impl Inode for Fat32Dir {
         } else if let Ok(file) = self.dir.open_file(name.as_str()) {
             let inode = Arc::new(RwLock::new(Fat32File::new(
                 Arc::new(file),
+                self.dev.clone(),
                 self.cluster_size,
             )));
             inode
@@ -275,14 +284,17 @@ impl Inode for Fat32Dir {
 
 pub struct Fat32File {
     file: Arc<FatFile>,
+    /// The device the volume is on, flushed on sync.
+    dev: InodeRef,
     path: String,
     cluster_size: usize,
 }
 
 impl Fat32File {
-    pub(self) fn new(file: Arc<FatFile>, cluster_size: usize) -> Self {
+    pub(self) fn new(file: Arc<FatFile>, dev: InodeRef, cluster_size: usize) -> Self {
         Self {
             file,
+            dev,
             path: String::new(),
             cluster_size,
         }
@@ -328,11 +340,11 @@ impl Inode for Fat32File {
         size
     }
 
-    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, ()> {
         let mut size = 0;
         ref_to_mut(self.file.as_ref())
             .seek(SeekFrom::Start(offset as u64))
-            .unwrap();
+            .map_err(|_| ())?;
         let write_size = buf.len();
 
         let write_cnt = write_size / self.cluster_size;
@@ -341,18 +353,24 @@ impl Inode for Fat32File {
             let offset = self.cluster_size * i;
             size += ref_to_mut(self.file.as_ref())
                 .write(&buf[offset..offset + self.cluster_size])
-                .unwrap();
+                .map_err(|_| ())?;
         }
 
         let remaining = write_size % self.cluster_size;
         if remaining > 0 {
             size += ref_to_mut(self.file.as_ref())
                 .write(&buf[write_cnt * self.cluster_size..])
-                .unwrap();
+                .map_err(|_| ())?;
         }
 
-        ref_to_mut(self.file.as_ref()).flush().unwrap();
-        size
+        // Writes the directory entry out. The device's write cache is only flushed on sync.
+        ref_to_mut(self.file.as_ref()).flush().map_err(|_| ())?;
+        Ok(size)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        ref_to_mut(self.file.as_ref()).flush().map_err(|_| ())?;
+        self.dev.read().flush()
     }
 
     fn size(&self) -> usize {
@@ -3,13 +3,17 @@ use rstd::{
         collections::btree_map::BTreeMap,
         string::{String, ToString},
     },
-    fs::{USER_LIST, USER_OPEN, USER_READ, USER_SIZE, USER_WRITE, UserCommand},
+    fs::{USER_IOCTL, USER_LIST, USER_OPEN, USER_READ, USER_SIZE, USER_WRITE, UserCommand},
     println,
 };
 use spin::Mutex;
 
 use crate::inode::{InodeRef, user_open};
 
+/// Writes the file's pending metadata out and commits the device's write cache to media.
+/// Writes alone leave data in the device's volatile cache.
+pub const FSM_IOCTL_SYNC: usize = 1;
+
 enum FSHandle {
     ErrHandle,
     RwHandle(InodeRef),
@@ -96,6 +100,18 @@ impl FsmFS {
                             self.user_command.ret_val = 0;
                         }
                     }
+                    USER_IOCTL => {
+                        if let Ok(ret) = self.ioctl(unsafe {
+                            core::slice::from_raw_parts(
+                                self.user_command.buf_addr as *const usize,
+                                self.user_command.buf_size,
+                            )
+                        }) {
+                            self.user_command.ret_val = ret as isize;
+                        } else {
+                            self.user_command.ret_val = -1;
+                        }
+                    }
                     _ => println!("fsmd: unknown command: {}", cmd),
                 }
 
@@ -155,7 +171,7 @@ impl FsmFS {
             match handle {
                 FSHandle::ErrHandle => return Err(()),
                 FSHandle::RwHandle(inode) => {
-                    inode.read().write_at(offset, buf);
+                    inode.read().write_at(offset, buf)?;
                 }
             }
         }
@@ -184,6 +200,27 @@ impl FsmFS {
         Err(())
     }
 
+    fn ioctl(&mut self, buf: &[usize]) -> Result<usize, ()> {
+        let _guard = self.lock.lock();
+
+        let cmd = buf[0];
+
+        let path_addr = self.user_command.ret_val as *const u8;
+        let path_len = self.user_command.ret_val2 as usize;
+        let str =
+            unsafe { str::from_utf8(core::slice::from_raw_parts(path_addr, path_len)).unwrap() };
+
+        let handle = self.handles.get(&str.to_string());
+        if let Some(FSHandle::RwHandle(inode)) = handle {
+            if cmd == FSM_IOCTL_SYNC {
+                inode.read().flush()?;
+                return Ok(0);
+            }
+        }
+
+        Err(())
+    }
+
     fn list(&mut self) -> Result<(usize, usize, usize), ()> {
         let _guard = self.lock.lock();
 
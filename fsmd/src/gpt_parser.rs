@@ -46,10 +46,14 @@ impl Inode for PartitionInode {
         self.drive.read().read_at(offset, buf)
     }
 
-    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, ()> {
         let offset = self.offset + offset;
         self.drive.read().write_at(offset, buf)
     }
+
+    fn flush(&self) -> Result<(), ()> {
+        self.drive.read().flush()
+    }
 }
 
 struct InodeRefIO {
@@ -91,7 +95,10 @@ impl BlockIo for InodeRefIO {
         start_lba: gpt_disk_io::gpt_disk_types::Lba,
         src: &[u8],
     ) -> Result<(), Self::Error> {
-        self.inode.read().write_at(start_lba.0 as usize * 512, src);
+        self.inode
+            .read()
+            .write_at(start_lba.0 as usize * 512, src)
+            .map_err(|()| start_lba.0 as usize)?;
         Ok(())
     }
 }
@@ -24,11 +24,15 @@ pub trait Inode: Sync + Send {
     fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> usize {
         0
     }
-    fn write_at(&self, _offset: usize, _buf: &[u8]) -> usize {
-        0
+    /// Writes `buf` at `offset` and returns how much was written, or `Err` if the write or
+    /// the metadata update that goes with it failed.
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize, ()> {
+        Ok(0)
     }
-    fn flush(&self) {
-        unimplemented!()
+    /// Commits writes buffered below this inode to the device. Inodes that buffer nothing
+    /// have nothing to do.
+    fn flush(&self) -> Result<(), ()> {
+        Ok(())
     }
 
     fn open(&self, _name: String) -> Option<InodeRef> {
@@ -0,0 +1,238 @@
+use crate::error::NvmeStatus;
+
+/// Optional Admin Command Support bits (OACS).
+pub const OACS_FORMAT_NVM: u16 = 1 << 1;
+pub const OACS_NAMESPACE_MANAGEMENT: u16 = 1 << 3;
+
+/// Optional NVM Command Support bits (ONCS).
+pub const ONCS_DATASET_MANAGEMENT: u16 = 1 << 2;
+pub const ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
+/// The Identify Controller data structure (CNS 01h), kept as the raw 4 KiB page.
+pub struct IdentifyController([u8; 4096]);
+
+impl IdentifyController {
+    pub fn new(data: &[u8]) -> Self {
+        let mut raw = [0u8; 4096];
+        raw.copy_from_slice(&data[..4096]);
+        Self(raw)
+    }
+
+    pub fn serial_number(&self) -> &str {
+        ascii_field(&self.0[4..24])
+    }
+
+    pub fn model_number(&self) -> &str {
+        ascii_field(&self.0[24..64])
+    }
+
+    pub fn firmware_revision(&self) -> &str {
+        ascii_field(&self.0[64..72])
+    }
+
+    /// Maximum Data Transfer Size, as a power of two in units of CAP.MPSMIN. 0 means no limit.
+    pub fn mdts(&self) -> u8 {
+        self.0[77]
+    }
+
+    /// Controller ID, which Namespace Attachment uses to name this controller.
+    pub fn controller_id(&self) -> u16 {
+        u16::from_le_bytes([self.0[78], self.0[79]])
+    }
+
+    /// Optional Admin Command Support.
+    pub fn oacs(&self) -> u16 {
+        u16::from_le_bytes([self.0[256], self.0[257]])
+    }
+
+    /// Number of entries the controller keeps in its Error Information log.
+    pub fn error_log_page_entries(&self) -> usize {
+        usize::from(self.0[262]) + 1
+    }
+
+    /// Number of power states the controller supports.
+    pub fn power_state_count(&self) -> usize {
+        usize::from(self.0[263]) + 1
+    }
+
+    /// Whether the controller supports Autonomous Power State Transitions.
+    pub fn apst_supported(&self) -> bool {
+        self.0[265] & 1 != 0
+    }
+
+    /// The power state descriptors, from power state 0 (the highest power) downwards.
+    pub fn power_states(&self) -> impl Iterator<Item = PowerState> + '_ {
+        self.0[2048..]
+            .chunks_exact(32)
+            .take(self.power_state_count())
+            .map(PowerState::new)
+    }
+
+    /// Optional NVM Command Support.
+    pub fn oncs(&self) -> u16 {
+        u16::from_le_bytes([self.0[520], self.0[521]])
+    }
+
+    /// Whether a volatile write cache is present, making Flush meaningful.
+    pub fn volatile_write_cache(&self) -> bool {
+        self.0[525] & 1 != 0
+    }
+}
+
+/// A power state descriptor from Identify Controller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerState {
+    /// Maximum power drawn in this state, in units of 0.0001 W.
+    pub max_power: u32,
+    /// Whether the controller processes no I/O in this state. It leaves the state by itself
+    /// when a command arrives.
+    pub non_operational: bool,
+    /// Time to enter and to leave the state, in microseconds.
+    pub entry_latency: u32,
+    pub exit_latency: u32,
+}
+
+impl PowerState {
+    fn new(data: &[u8]) -> Self {
+        let max_power = u32::from(u16::from_le_bytes([data[0], data[1]]));
+        // Max Power Scale: the field counts 0.01 W when clear, 0.0001 W when set.
+        let max_power = if data[3] & 1 != 0 {
+            max_power
+        } else {
+            max_power * 100
+        };
+
+        Self {
+            max_power,
+            non_operational: data[3] & (1 << 1) != 0,
+            entry_latency: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            exit_latency: u32::from_le_bytes(data[8..12].try_into().unwrap()),
+        }
+    }
+}
+
+/// The parts of the Identify Namespace data structure (CNS 00h) nvmed cares about.
+#[derive(Clone, Copy, Debug)]
+pub struct IdentifyNamespace {
+    /// Namespace size, in logical blocks.
+    pub blocks: u64,
+    /// Logical block size of the formatted LBA format, in bytes.
+    pub block_size: usize,
+}
+
+impl IdentifyNamespace {
+    pub fn new(data: &[u8]) -> Option<Self> {
+        let nsze = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        let flbas = data[26];
+
+        let lbaf_offset = 128 + usize::from(flbas & 0xF) * 4;
+        let lbaf = u32::from_le_bytes(data[lbaf_offset..lbaf_offset + 4].try_into().unwrap());
+        let lbads = (lbaf >> 16) & 0xFF;
+
+        if !(9..32).contains(&lbads) {
+            return None;
+        }
+
+        Some(Self {
+            blocks: nsze,
+            block_size: 1 << lbads,
+        })
+    }
+}
+
+/// The SMART / Health Information log page (Log Identifier 02h).
+#[derive(Clone, Copy, Debug)]
+pub struct SmartLog {
+    /// Critical Warning bits: spare below threshold, temperature, reliability, read only,
+    /// volatile backup failed.
+    pub critical_warning: u8,
+    /// Composite temperature, in Kelvin.
+    pub temperature: u16,
+    /// Remaining spare capacity, in percent.
+    pub available_spare: u8,
+    pub available_spare_threshold: u8,
+    /// Vendor estimate of the life used, in percent. May exceed 100.
+    pub percentage_used: u8,
+    /// Data read and written, in thousands of 512-byte units.
+    pub data_units_read: u128,
+    pub data_units_written: u128,
+    pub power_cycles: u128,
+    pub power_on_hours: u128,
+    pub unsafe_shutdowns: u128,
+    pub media_errors: u128,
+    pub error_log_entries: u128,
+}
+
+impl SmartLog {
+    pub const LOG_ID: u8 = 0x02;
+    pub const LEN: usize = 512;
+
+    pub fn new(data: &[u8]) -> Self {
+        let u128_at =
+            |offset: usize| u128::from_le_bytes(data[offset..offset + 16].try_into().unwrap());
+
+        Self {
+            critical_warning: data[0],
+            temperature: u16::from_le_bytes([data[1], data[2]]),
+            available_spare: data[3],
+            available_spare_threshold: data[4],
+            percentage_used: data[5],
+            data_units_read: u128_at(32),
+            data_units_written: u128_at(48),
+            power_cycles: u128_at(112),
+            power_on_hours: u128_at(128),
+            unsafe_shutdowns: u128_at(144),
+            media_errors: u128_at(160),
+            error_log_entries: u128_at(176),
+        }
+    }
+}
+
+/// An entry of the Error Information log page (Log Identifier 01h).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorLogEntry {
+    /// Number of errors the controller had met when this one was logged. Each entry has its
+    /// own; 0 marks an unused entry.
+    pub error_count: u64,
+    /// The submission queue and command identifier of the failed command.
+    pub sqid: u16,
+    pub cid: u16,
+    /// Status of the failed command's completion.
+    pub status: Option<NvmeStatus>,
+    /// Byte (bits 7:0) and bit (bits 10:8) of the command that caused the error, 0xFFFF when
+    /// the error is not tied to a field.
+    pub parameter_error_location: u16,
+    pub lba: u64,
+    pub nsid: u32,
+}
+
+impl ErrorLogEntry {
+    pub const LOG_ID: u8 = 0x01;
+    pub const LEN: usize = 64;
+
+    pub fn new(data: &[u8]) -> Self {
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+
+        Self {
+            error_count: u64::from_le_bytes(data[0..8].try_into().unwrap()),
+            sqid: u16_at(8),
+            cid: u16_at(10),
+            // Laid out like a completion's status, bit 0 being the phase tag.
+            status: NvmeStatus::from_completion(u16_at(12)),
+            parameter_error_location: u16_at(14),
+            lba: u64::from_le_bytes(data[16..24].try_into().unwrap()),
+            nsid: u32::from_le_bytes(data[24..28].try_into().unwrap()),
+        }
+    }
+}
+
+/// Parses an active namespace ID list (CNS 02h).
+pub fn namespace_list(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .take_while(|&nsid| nsid != 0)
+}
+
+fn ascii_field(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("").trim_end()
+}
@@ -0,0 +1,422 @@
+//! An LRU cache of logical blocks shared by all of nvmed's disks.
+//!
+//! Writes are held in the cache and only reach the disk when they are flushed or evicted.
+//! Reads continuing where the previous read on the same disk stopped also fetch the blocks
+//! after them, so sequential scans are served from memory.
+
+use core::ops::Range;
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    vec::Vec,
+};
+
+use crate::{
+    controller::NvmeController,
+    disk::{IoError, NvmeDisk},
+    error::NvmeError,
+    println,
+};
+
+/// Bytes the cache holds when nvmed starts.
+pub const DEFAULT_CACHE_SIZE: usize = 4 << 20;
+
+/// Bytes fetched past the end of a sequential read.
+const READ_AHEAD: usize = 128 << 10;
+
+/// Requests larger than `capacity / BYPASS_FRACTION` go straight to the disk, so one big
+/// transfer does not push out everything else.
+const BYPASS_FRACTION: usize = 4;
+
+/// Cached blocks are keyed by disk index and LBA.
+type Key = (usize, u64);
+
+struct Entry {
+    data: Vec<u8>,
+    dirty: bool,
+    /// When the block was last used, for finding the least recently used one.
+    used: u64,
+}
+
+pub struct BlockCache {
+    /// Upper bound on the bytes held. 0 turns the cache off.
+    capacity: usize,
+    size: usize,
+    clock: u64,
+    entries: BTreeMap<Key, Entry>,
+    /// The entries ordered by `used`, least recently used first.
+    lru: BTreeMap<u64, Key>,
+    /// Dirty blocks whose write back failed during eviction. Eviction skips them until a
+    /// write back through `flush` or `evict` succeeds.
+    failed: BTreeSet<Key>,
+    /// Per disk, the offset just past its last read.
+    next_read: BTreeMap<usize, usize>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            clock: 0,
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            failed: BTreeSet::new(),
+            next_read: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the cache size, writing back whatever no longer fits.
+    pub fn set_capacity(
+        &mut self,
+        disks: &[NvmeDisk],
+        controllers: &mut [NvmeController],
+        capacity: usize,
+    ) {
+        self.capacity = capacity;
+        self.shrink(disks, controllers)
+    }
+
+    fn bypass(&self, len: usize) -> bool {
+        len > self.capacity / BYPASS_FRACTION
+    }
+
+    fn touch(&mut self, key: Key) {
+        let entry = self.entries.get_mut(&key).unwrap();
+        self.lru.remove(&entry.used);
+        self.clock += 1;
+        entry.used = self.clock;
+        self.lru.insert(self.clock, key);
+    }
+
+    fn insert(&mut self, key: Key, data: Vec<u8>, dirty: bool) {
+        self.clock += 1;
+        self.size += data.len();
+        self.lru.insert(self.clock, key);
+        let old = self.entries.insert(
+            key,
+            Entry {
+                data,
+                dirty,
+                used: self.clock,
+            },
+        );
+        debug_assert!(old.is_none());
+    }
+
+    fn remove(&mut self, key: Key) -> Option<Entry> {
+        let entry = self.entries.remove(&key)?;
+        self.lru.remove(&entry.used);
+        self.size -= entry.data.len();
+        self.failed.remove(&key);
+        Some(entry)
+    }
+
+    /// Fails requests reaching past the end of the disk, and every request once the disk's
+    /// controller is shut down: cached blocks could still serve them, but writes would be lost.
+    fn check_request(
+        disk: &NvmeDisk,
+        controllers: &[NvmeController],
+        offset: usize,
+        len: usize,
+    ) -> Result<(), IoError> {
+        if controllers[disk.controller].is_shut_down() {
+            return Err(IoError {
+                done: 0,
+                error: NvmeError::ShutDown,
+            });
+        }
+        disk.check_range(offset, len)
+            .map_err(|error| IoError { done: 0, error })
+    }
+
+    /// Evicts least recently used blocks until the cache fits its capacity.
+    ///
+    /// A dirty block that cannot be written back keeps the cache over capacity rather than
+    /// losing its data. It is logged once and left to `flush` to report, so it does not fail
+    /// the unrelated request that happened to trigger the eviction.
+    fn shrink(&mut self, disks: &[NvmeDisk], controllers: &mut [NvmeController]) {
+        let mut used = 0;
+        while self.size > self.capacity {
+            let Some((&next, &key)) = self.lru.range(used..).next() else {
+                // Only blocks that failed to write back are left.
+                break;
+            };
+            used = next + 1;
+            if self.failed.contains(&key) {
+                continue;
+            }
+
+            let entry = &self.entries[&key];
+            if entry.dirty {
+                let disk = &disks[key.0];
+                let result = controllers[disk.controller].write_blocks(
+                    disk.nsid,
+                    disk.block_size,
+                    key.1,
+                    &entry.data,
+                    false,
+                );
+                if let Err(error) = result {
+                    // Keep the data around, it is the only copy.
+                    println!(
+                        "nvmed: failed to write back block {} of disk {}: {}",
+                        key.1, key.0, error
+                    );
+                    self.failed.insert(key);
+                    continue;
+                }
+            }
+            self.remove(key);
+        }
+    }
+
+    /// Copies the part of cached block `lba` that falls inside `offset..offset + buf.len()`
+    /// into `buf`.
+    fn copy_out(block_size: usize, lba: u64, data: &[u8], offset: usize, buf: &mut [u8]) {
+        let range = overlap(block_size, lba, offset, buf.len());
+        let block_start = lba as usize * block_size;
+        buf[range.start - offset..range.end - offset]
+            .copy_from_slice(&data[range.start - block_start..range.end - block_start]);
+    }
+
+    /// The other direction of `copy_out`.
+    fn copy_in(block_size: usize, lba: u64, data: &mut [u8], offset: usize, buf: &[u8]) {
+        let range = overlap(block_size, lba, offset, buf.len());
+        let block_start = lba as usize * block_size;
+        data[range.start - block_start..range.end - block_start]
+            .copy_from_slice(&buf[range.start - offset..range.end - offset]);
+    }
+
+    pub fn read(
+        &mut self,
+        disk_idx: usize,
+        disks: &[NvmeDisk],
+        controllers: &mut [NvmeController],
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, IoError> {
+        let disk = &disks[disk_idx];
+        Self::check_request(disk, controllers, offset, buf.len())?;
+
+        let end = offset + buf.len();
+        let block_size = disk.block_size;
+        let blocks = block_range(block_size, offset, buf.len());
+
+        if self.bypass(buf.len()) {
+            // Blocks written since the last flush are newer than what the disk holds.
+            self.write_back(disk_idx, disks, controllers, blocks)
+                .map_err(|error| IoError { done: 0, error })?;
+            return disk.read(&mut controllers[disk.controller], offset, buf);
+        }
+
+        let sequential = self.next_read.insert(disk_idx, end) == Some(offset);
+
+        let mut lba = blocks.start;
+        while lba < blocks.end {
+            if let Some(entry) = self.entries.get(&(disk_idx, lba)) {
+                Self::copy_out(block_size, lba, &entry.data, offset, buf);
+                self.touch((disk_idx, lba));
+                lba += 1;
+                continue;
+            }
+
+            // Fetch the whole run of missing blocks with one request.
+            let mut miss_end = lba + 1;
+            while miss_end < blocks.end && !self.entries.contains_key(&(disk_idx, miss_end)) {
+                miss_end += 1;
+            }
+            let mut fetch_end = miss_end;
+            if sequential && miss_end == blocks.end {
+                let limit = (miss_end + (READ_AHEAD / block_size) as u64).min(disk.blocks);
+                while fetch_end < limit && !self.entries.contains_key(&(disk_idx, fetch_end)) {
+                    fetch_end += 1;
+                }
+            }
+
+            let fetch_start = lba as usize * block_size;
+            let mut tmp = alloc::vec![0u8; (fetch_end - lba) as usize * block_size];
+            disk.read(&mut controllers[disk.controller], fetch_start, &mut tmp)
+                .map_err(|error| IoError {
+                    done: (fetch_start + error.done)
+                        .saturating_sub(offset)
+                        .min(buf.len()),
+                    error: error.error,
+                })?;
+
+            for (i, data) in tmp.chunks(block_size).enumerate() {
+                let block = lba + i as u64;
+                if block < miss_end {
+                    Self::copy_out(block_size, block, data, offset, buf);
+                }
+                self.insert((disk_idx, block), data.to_vec(), false);
+            }
+
+            lba = miss_end;
+        }
+
+        self.shrink(disks, controllers);
+
+        Ok(buf.len())
+    }
+
+    /// Writes `buf` at `offset`. The data stays in the cache until it is flushed or evicted,
+    /// unless `fua` asks for it to be durable on return.
+    pub fn write(
+        &mut self,
+        disk_idx: usize,
+        disks: &[NvmeDisk],
+        controllers: &mut [NvmeController],
+        offset: usize,
+        buf: &[u8],
+        fua: bool,
+    ) -> Result<usize, IoError> {
+        let disk = &disks[disk_idx];
+        Self::check_request(disk, controllers, offset, buf.len())?;
+
+        let block_size = disk.block_size;
+        let blocks = block_range(block_size, offset, buf.len());
+
+        if fua || self.bypass(buf.len()) {
+            // Partial blocks are read back from the disk, which has to be up to date first.
+            self.write_back(disk_idx, disks, controllers, blocks.clone())
+                .map_err(|error| IoError { done: 0, error })?;
+            disk.write(&mut controllers[disk.controller], offset, buf, fua)?;
+
+            for lba in blocks {
+                if let Some(entry) = self.entries.get_mut(&(disk_idx, lba)) {
+                    Self::copy_in(block_size, lba, &mut entry.data, offset, buf);
+                }
+            }
+
+            return Ok(buf.len());
+        }
+
+        for lba in blocks {
+            let block_start = lba as usize * block_size;
+            let done = block_start.saturating_sub(offset);
+
+            if let Some(entry) = self.entries.get_mut(&(disk_idx, lba)) {
+                Self::copy_in(block_size, lba, &mut entry.data, offset, buf);
+                entry.dirty = true;
+                self.touch((disk_idx, lba));
+                continue;
+            }
+
+            let mut data = alloc::vec![0u8; block_size];
+            let covered = overlap(block_size, lba, offset, buf.len());
+            if covered.len() != block_size {
+                controllers[disk.controller]
+                    .read_blocks(disk.nsid, block_size, lba, &mut data)
+                    .map_err(|error| IoError { done, error })?;
+            }
+            Self::copy_in(block_size, lba, &mut data, offset, buf);
+            self.insert((disk_idx, lba), data, true);
+        }
+
+        self.shrink(disks, controllers);
+
+        Ok(buf.len())
+    }
+
+    /// Writes the dirty blocks of `disk_idx` inside `blocks` to the disk, merging neighbouring
+    /// blocks into as few commands as the transfer limit allows.
+    pub fn write_back(
+        &mut self,
+        disk_idx: usize,
+        disks: &[NvmeDisk],
+        controllers: &mut [NvmeController],
+        blocks: Range<u64>,
+    ) -> Result<(), NvmeError> {
+        let disk = &disks[disk_idx];
+        let controller = &mut controllers[disk.controller];
+        let max_blocks = (controller.max_transfer() / disk.block_size) as u64;
+
+        let dirty = self
+            .entries
+            .range((disk_idx, blocks.start)..(disk_idx, blocks.end))
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&(_, lba), _)| lba)
+            .collect::<Vec<_>>();
+
+        for run in dirty.chunk_by(|a, b| a + 1 == *b) {
+            for run in run.chunks(max_blocks as usize) {
+                let mut tmp = Vec::with_capacity(run.len() * disk.block_size);
+                for lba in run {
+                    tmp.extend_from_slice(&self.entries[&(disk_idx, *lba)].data);
+                }
+
+                controller.write_blocks(disk.nsid, disk.block_size, run[0], &tmp, false)?;
+
+                for lba in run {
+                    self.entries.get_mut(&(disk_idx, *lba)).unwrap().dirty = false;
+                    self.failed.remove(&(disk_idx, *lba));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes back every dirty block of `disk_idx`, then flushes the disk's own cache.
+    pub fn flush(
+        &mut self,
+        disk_idx: usize,
+        disks: &[NvmeDisk],
+        controllers: &mut [NvmeController],
+    ) -> Result<(), NvmeError> {
+        self.write_back(disk_idx, disks, controllers, 0..u64::MAX)?;
+
+        let disk = &disks[disk_idx];
+        disk.flush(&mut controllers[disk.controller])
+    }
+
+    /// Writes back and drops the blocks covering `offset..offset + len`, before the disk
+    /// changes them behind the cache's back.
+    pub fn evict(
+        &mut self,
+        disk_idx: usize,
+        disks: &[NvmeDisk],
+        controllers: &mut [NvmeController],
+        offset: usize,
+        len: usize,
+    ) -> Result<(), NvmeError> {
+        let disk = &disks[disk_idx];
+        disk.check_range(offset, len)?;
+
+        let blocks = block_range(disk.block_size, offset, len);
+        self.write_back(disk_idx, disks, controllers, blocks.clone())?;
+        self.invalidate(disk_idx, blocks);
+
+        Ok(())
+    }
+
+    /// Drops the blocks of `disk_idx` inside `blocks`, dirty or not.
+    pub fn invalidate(&mut self, disk_idx: usize, blocks: Range<u64>) {
+        let keys = self
+            .entries
+            .range((disk_idx, blocks.start)..(disk_idx, blocks.end))
+            .map(|(&key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            self.remove(key);
+        }
+        self.next_read.remove(&disk_idx);
+    }
+}
+
+/// The blocks covering `offset..offset + len`.
+fn block_range(block_size: usize, offset: usize, len: usize) -> Range<u64> {
+    (offset / block_size) as u64..(offset + len).div_ceil(block_size) as u64
+}
+
+/// The bytes of block `lba` inside `offset..offset + len`.
+fn overlap(block_size: usize, lba: u64, offset: usize, len: usize) -> Range<usize> {
+    let block_start = lba as usize * block_size;
+    offset.max(block_start)..(offset + len).min(block_start + block_size)
+}
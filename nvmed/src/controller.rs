@@ -0,0 +1,912 @@
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use nvme::{
+    cmd::NvmeCommand,
+    memory::{Dma, PAGE_SIZE},
+    queues::{NvmeCompletion, QUEUE_LENGTH},
+};
+
+use crate::{
+    admin::{
+        self, ErrorLogEntry, IdentifyController, IdentifyNamespace, OACS_FORMAT_NVM,
+        OACS_NAMESPACE_MANAGEMENT, ONCS_DATASET_MANAGEMENT, ONCS_WRITE_ZEROES, PowerState,
+        SmartLog,
+    },
+    dma::NvmeAllocator,
+    error::NvmeError,
+    println,
+    queue::{Deadline, QueuePair},
+};
+
+const REG_CAP: usize = 0x00;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+
+const CC_ENABLE: u32 = 1 << 0;
+const CC_SHN_MASK: u32 = 0x3 << 14;
+const CC_SHN_NORMAL: u32 = 0x1 << 14;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+const CSTS_SHST_MASK: u32 = 0x3 << 2;
+const CSTS_SHST_COMPLETE: u32 = 0x2 << 2;
+
+/// Number of data pages one PRP list page can point to.
+const PRP_LIST_ENTRIES: usize = PAGE_SIZE / core::mem::size_of::<u64>();
+
+/// Largest transfer a single command can describe: PRP1 plus one full PRP list page.
+const PRP_LIST_MAX_TRANSFER: usize = PRP_LIST_ENTRIES * PAGE_SIZE;
+
+const IO_QUEUE_ID: u16 = 1;
+
+const OPCODE_FLUSH: u8 = 0x00;
+const OPCODE_GET_LOG_PAGE: u8 = 0x02;
+const OPCODE_DATASET_MANAGEMENT: u8 = 0x09;
+
+const OPCODE_SET_FEATURES: u8 = 0x09;
+const OPCODE_GET_FEATURES: u8 = 0x0A;
+const OPCODE_NAMESPACE_MANAGEMENT: u8 = 0x0D;
+const OPCODE_NAMESPACE_ATTACHMENT: u8 = 0x15;
+const OPCODE_FORMAT_NVM: u8 = 0x80;
+
+const FEATURE_POWER_MANAGEMENT: u8 = 0x02;
+const FEATURE_INTERRUPT_COALESCING: u8 = 0x08;
+const FEATURE_APST: u8 = 0x0C;
+
+/// Interrupt coalescing: an I/O completion interrupt is raised once this many completions are
+/// posted, or this many 100 microsecond units after the first, whichever comes first.
+const COALESCING_THRESHOLD: u8 = 4;
+const COALESCING_TIME: u8 = 1;
+
+/// Entries in the APST table, one per possible power state.
+const APST_ENTRIES: usize = 32;
+
+/// APST moves to a non-operational state after staying idle for this many times the state's
+/// entry plus exit latency, as Linux does.
+const APST_IDLE_FACTOR: u64 = 50;
+
+/// Largest idle time an APST entry can hold, in milliseconds.
+const APST_MAX_IDLE_MS: u64 = (1 << 24) - 1;
+
+/// Non-operational states taking longer than this to leave are not used by APST by default,
+/// in microseconds.
+pub const APST_DEFAULT_MAX_LATENCY: u32 = 100_000;
+
+/// Namespace ID addressing the controller as a whole.
+const NSID_ALL: u32 = 0xFFFF_FFFF;
+
+/// Force Unit Access: the write completes only once the data is on non-volatile media.
+const RW_FUA: u32 = 1 << 30;
+
+/// Dataset Management attribute: deallocate the ranges.
+const DSM_DEALLOCATE: u32 = 1 << 2;
+
+/// Ranges one Dataset Management command can carry; the list fills exactly one page.
+pub const DSM_MAX_RANGES: usize = 256;
+
+/// Blocks one Write Zeroes command can cover, as NLB is a 0's based 16-bit field.
+pub const WRITE_ZEROES_MAX_BLOCKS: u64 = 1 << 16;
+
+/// Secure Erase Settings for Format NVM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecureErase {
+    None = 0,
+    UserData = 1,
+    Cryptographic = 2,
+}
+
+/// How many times a command that failed with a transient error is resubmitted.
+const MAX_RETRIES: u8 = 3;
+
+/// How many failed commands each controller remembers.
+const ERROR_LOG_LEN: usize = 64;
+
+/// How long a normal shutdown may take. Controllers only optionally report their own bound.
+const SHUTDOWN_TIMEOUT_MS: u64 = 10_000;
+
+/// Access to a controller's register block. [`Mmio`] is the real BAR; host tests put an
+/// emulated controller behind it instead.
+pub trait RegisterIo {
+    fn read32(&self, reg: usize) -> u32;
+    fn write32(&self, reg: usize, value: u32);
+
+    fn read64(&self, reg: usize) -> u64 {
+        u64::from(self.read32(reg)) | (u64::from(self.read32(reg + 4)) << 32)
+    }
+
+    fn write64(&self, reg: usize, value: u64) {
+        self.write32(reg, value as u32);
+        self.write32(reg + 4, (value >> 32) as u32);
+    }
+}
+
+/// The controller's memory-mapped register block (BAR0).
+pub struct Mmio {
+    base: usize,
+    len: usize,
+}
+
+impl Mmio {
+    pub fn new(base: usize, len: usize) -> Self {
+        Self { base, len }
+    }
+}
+
+impl RegisterIo for Mmio {
+    fn read32(&self, reg: usize) -> u32 {
+        assert!(reg + 4 <= self.len, "NVMe register access out of bounds");
+        unsafe { core::ptr::read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write32(&self, reg: usize, value: u32) {
+        assert!(reg + 4 <= self.len, "NVMe register access out of bounds");
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn read64(&self, reg: usize) -> u64 {
+        assert!(reg + 8 <= self.len, "NVMe register access out of bounds");
+        unsafe { core::ptr::read_volatile((self.base + reg) as *const u64) }
+    }
+
+    fn write64(&self, reg: usize, value: u64) {
+        assert!(reg + 8 <= self.len, "NVMe register access out of bounds");
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u64, value) }
+    }
+}
+
+pub struct Registers {
+    io: Box<dyn RegisterIo>,
+    /// Doorbell stride, from CAP.DSTRD.
+    dstrd: usize,
+}
+
+impl Registers {
+    pub fn new(io: impl RegisterIo + 'static) -> Self {
+        let dstrd = ((io.read64(REG_CAP) >> 32) & 0xF) as usize;
+        Self {
+            io: Box::new(io),
+            dstrd,
+        }
+    }
+
+    pub fn read32(&self, reg: usize) -> u32 {
+        self.io.read32(reg)
+    }
+
+    pub fn write32(&self, reg: usize, value: u32) {
+        self.io.write32(reg, value)
+    }
+
+    pub fn read64(&self, reg: usize) -> u64 {
+        self.io.read64(reg)
+    }
+
+    pub fn write64(&self, reg: usize, value: u64) {
+        self.io.write64(reg, value)
+    }
+
+    fn doorbell(&self, index: usize) -> usize {
+        0x1000 + index * (4 << self.dstrd)
+    }
+
+    /// Register offset of submission queue `qid`'s tail doorbell.
+    pub fn sq_tail_doorbell(&self, qid: u16) -> usize {
+        self.doorbell(2 * usize::from(qid))
+    }
+
+    /// Register offset of completion queue `qid`'s head doorbell.
+    pub fn cq_head_doorbell(&self, qid: u16) -> usize {
+        self.doorbell(2 * usize::from(qid) + 1)
+    }
+
+    /// Minimum memory page size supported by the controller, from CAP.MPSMIN.
+    pub fn min_page_size(&self) -> usize {
+        1 << (12 + ((self.read64(REG_CAP) >> 48) & 0xF))
+    }
+
+    /// Worst-case time for CSTS.RDY to follow CC.EN, from CAP.TO in units of 500 ms.
+    fn ready_timeout_ms(&self) -> u64 {
+        ((self.read64(REG_CAP) >> 24) & 0xFF).max(1) * 500
+    }
+
+    /// Polls CSTS until `done` holds, giving up after `timeout_ms` milliseconds.
+    fn wait_status(&self, timeout_ms: u64, done: impl Fn(u32) -> bool) -> Result<(), NvmeError> {
+        let deadline = Deadline::after_ms(timeout_ms);
+        loop {
+            if done(self.read32(REG_CSTS)) {
+                return Ok(());
+            }
+            if deadline.passed() {
+                return Err(NvmeError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn wait_ready(&self, ready: bool) -> Result<(), NvmeError> {
+        self.wait_status(self.ready_timeout_ms(), |csts| {
+            (csts & CSTS_READY != 0) == ready
+        })
+    }
+
+    /// Waits for the controller to report that a shutdown started through CC.SHN is done.
+    fn wait_shutdown(&self) -> Result<(), NvmeError> {
+        self.wait_status(SHUTDOWN_TIMEOUT_MS, |csts| {
+            csts & CSTS_SHST_MASK == CSTS_SHST_COMPLETE
+        })
+    }
+
+    /// Whether the controller has hit a fatal error (CSTS.CFS) and needs a reset.
+    pub fn is_fatal(&self) -> bool {
+        self.read32(REG_CSTS) & CSTS_FATAL != 0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Queue {
+    Admin,
+    Io,
+}
+
+/// A command that failed, as kept in a controller's error log.
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorRecord {
+    pub opcode: u8,
+    pub nsid: u32,
+    /// Starting LBA, for I/O commands.
+    pub lba: Option<u64>,
+    pub error: NvmeError,
+    /// How many times the command had already been resubmitted when this failure happened.
+    pub retries: u8,
+}
+
+pub struct NvmeController {
+    regs: Registers,
+    admin_queue: QueuePair,
+    io_queue: QueuePair,
+    /// Data page for admin commands.
+    scratch: Dma<u8>,
+    /// Bounce buffer for I/O commands, `max_transfer` bytes long.
+    bounce: Dma<u8>,
+    /// PRP list describing every page of `bounce` after the first.
+    prp_list: Dma<[u64; PRP_LIST_ENTRIES]>,
+    max_transfer: usize,
+    identify: IdentifyController,
+    error_log: VecDeque<ErrorRecord>,
+    /// The APST setting last applied, to restore after a reset.
+    apst_max_latency: Option<u32>,
+    /// Set once the controller has been shut down. It takes no more commands.
+    shut_down: bool,
+}
+
+impl NvmeController {
+    pub fn init(regs: Registers) -> Result<Self, NvmeError> {
+        let mut admin_queue = Self::enable(&regs)?;
+
+        let scratch: Dma<u8> = Dma::allocate(&NvmeAllocator, PAGE_SIZE);
+
+        admin_queue.submit_and_complete(&regs, |c_id| {
+            NvmeCommand::identify_controller(c_id, scratch.phys)
+        })?;
+        let identify = IdentifyController::new(&scratch[..]);
+
+        // MDTS is a power of two in units of the minimum memory page size; 0 means no limit.
+        let max_transfer = match identify.mdts() {
+            0 => PRP_LIST_MAX_TRANSFER,
+            mdts => (regs.min_page_size() << mdts).min(PRP_LIST_MAX_TRANSFER),
+        };
+
+        let io_queue = Self::create_io_queue(&regs, &mut admin_queue)?;
+        Self::configure_interrupt_coalescing(&regs, &mut admin_queue);
+
+        let bounce: Dma<u8> = Dma::allocate(&NvmeAllocator, max_transfer);
+        let mut prp_list: Dma<[u64; PRP_LIST_ENTRIES]> = Dma::allocate(&NvmeAllocator, PAGE_SIZE);
+        for (i, entry) in prp_list.iter_mut().enumerate() {
+            *entry = (bounce.phys + (i + 1) * PAGE_SIZE) as u64;
+        }
+
+        println!(
+            "NVMe {} ({}), firmware {}, max transfer {} bytes",
+            identify.model_number(),
+            identify.serial_number(),
+            identify.firmware_revision(),
+            max_transfer
+        );
+
+        Ok(Self {
+            regs,
+            admin_queue,
+            io_queue,
+            scratch,
+            bounce,
+            prp_list,
+            max_transfer,
+            identify,
+            error_log: VecDeque::new(),
+            apst_max_latency: None,
+            shut_down: false,
+        })
+    }
+
+    /// Resets the controller and brings it up with a fresh admin queue.
+    fn enable(regs: &Registers) -> Result<QueuePair, NvmeError> {
+        // Queues, PRPs and the bounce buffer are all laid out in 4 KiB pages, so CC.MPS stays
+        // at 4 KiB and a controller whose smallest page is larger cannot be driven.
+        if regs.min_page_size() > PAGE_SIZE {
+            return Err(NvmeError::Unsupported);
+        }
+
+        regs.write32(REG_CC, regs.read32(REG_CC) & !CC_ENABLE);
+        regs.wait_ready(false)?;
+
+        let admin_queue = QueuePair::new(regs, 0);
+        let admin_queue_len = QUEUE_LENGTH as u32 - 1;
+
+        regs.write64(REG_ASQ, admin_queue.sub_queue_addr() as u64);
+        regs.write64(REG_ACQ, admin_queue.comp_queue_addr() as u64);
+        regs.write32(REG_AQA, (admin_queue_len << 16) | admin_queue_len);
+
+        // I/O completion queue entries are 2^4 bytes, submission queue entries 2^6 bytes. The
+        // memory page size (CC.MPS) is left at 4 KiB, checked above against CAP.MPSMIN.
+        let cc = (regs.read32(REG_CC) & 0xFF00_000F) | (4 << 20) | (6 << 16);
+        regs.write32(REG_CC, cc);
+        regs.write32(REG_CC, cc | CC_ENABLE);
+        regs.wait_ready(true)?;
+
+        Ok(admin_queue)
+    }
+
+    fn create_io_queue(
+        regs: &Registers,
+        admin_queue: &mut QueuePair,
+    ) -> Result<QueuePair, NvmeError> {
+        let io_queue = QueuePair::new(regs, IO_QUEUE_ID);
+        let io_queue_len = (QUEUE_LENGTH - 1) as u16;
+
+        admin_queue.submit_and_complete(regs, |c_id| {
+            NvmeCommand::create_io_completion_queue(
+                c_id,
+                io_queue.id,
+                io_queue.comp_queue_addr(),
+                io_queue_len,
+            )
+        })?;
+        admin_queue.submit_and_complete(regs, |c_id| {
+            NvmeCommand::create_io_submission_queue(
+                c_id,
+                io_queue.id,
+                io_queue.sub_queue_addr(),
+                io_queue_len,
+                io_queue.id,
+            )
+        })?;
+
+        Ok(io_queue)
+    }
+
+    /// Asks the controller to coalesce I/O completion interrupts. nvmed polls its queues for
+    /// now, as rstd cannot wait for an interrupt, so a failure only costs a message.
+    fn configure_interrupt_coalescing(regs: &Registers, admin_queue: &mut QueuePair) {
+        let result = admin_queue.submit_and_complete(regs, |c_id| NvmeCommand {
+            opcode: OPCODE_SET_FEATURES,
+            c_id,
+            cdw10: u32::from(FEATURE_INTERRUPT_COALESCING),
+            cdw11: (u32::from(COALESCING_TIME) << 8) | u32::from(COALESCING_THRESHOLD - 1),
+            ..Default::default()
+        });
+        if let Err(error) = result {
+            println!("nvmed: failed to configure interrupt coalescing: {}", error);
+        }
+    }
+
+    /// Resets the controller and recreates its queues. Commands that were in flight are lost;
+    /// the memory of the old queues is not reused, as the controller may still write to it.
+    pub fn reset(&mut self) -> Result<(), NvmeError> {
+        println!(
+            "nvmed: resetting controller {}",
+            self.identify.serial_number()
+        );
+
+        let mut admin_queue = Self::enable(&self.regs)?;
+        let io_queue = Self::create_io_queue(&self.regs, &mut admin_queue)?;
+        Self::configure_interrupt_coalescing(&self.regs, &mut admin_queue);
+
+        self.admin_queue = admin_queue;
+        self.io_queue = io_queue;
+
+        // Features are back at their defaults. Losing APST only costs power, so a failure to
+        // restore it does not fail the reset.
+        if let Some(max_latency) = self.apst_max_latency {
+            let cmd_init = self.apst_command(max_latency);
+            if let Err(error) = self.admin_queue.submit_and_complete(&self.regs, cmd_init) {
+                println!("nvmed: failed to restore APST: {}", error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Performs a normal shutdown, after which the controller expects to lose power. Data
+    /// still in its volatile write cache is committed, but callers should flush their own
+    /// caches first. Later commands fail with [`NvmeError::ShutDown`].
+    pub fn shutdown(&mut self) -> Result<(), NvmeError> {
+        // Commands complete before `submit` returns, so deleting the I/O queues only has to
+        // stop new ones from arriving.
+        self.submit(Queue::Admin, |c_id| {
+            NvmeCommand::delete_io_submission_queue(c_id, IO_QUEUE_ID)
+        })?;
+        self.submit(Queue::Admin, |c_id| {
+            NvmeCommand::delete_io_completion_queue(c_id, IO_QUEUE_ID)
+        })?;
+
+        let cc = self.regs.read32(REG_CC);
+        self.regs
+            .write32(REG_CC, (cc & !CC_SHN_MASK) | CC_SHN_NORMAL);
+        self.regs.wait_shutdown()?;
+
+        self.shut_down = true;
+        println!(
+            "nvmed: controller {} shut down",
+            self.identify.serial_number()
+        );
+
+        Ok(())
+    }
+
+    /// Brings the controller back up after [`Self::shutdown`], for when the power-off it was
+    /// prepared for did not happen.
+    pub fn resume(&mut self) -> Result<(), NvmeError> {
+        if !self.shut_down {
+            return Ok(());
+        }
+
+        self.reset()?;
+        self.shut_down = false;
+
+        Ok(())
+    }
+
+    /// Whether [`Self::shutdown`] has completed. The controller takes no more commands.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    pub fn identify(&self) -> &IdentifyController {
+        &self.identify
+    }
+
+    pub fn max_transfer(&self) -> usize {
+        self.max_transfer
+    }
+
+    /// Failed commands, oldest first.
+    pub fn error_log(&self) -> impl Iterator<Item = &ErrorRecord> {
+        self.error_log.iter()
+    }
+
+    fn log_error(&mut self, queue: Queue, cmd: &NvmeCommand, error: NvmeError, retries: u8) {
+        let record = ErrorRecord {
+            opcode: cmd.opcode,
+            nsid: cmd.ns_id,
+            lba: (queue == Queue::Io).then(|| u64::from(cmd.cdw10) | (u64::from(cmd.cdw11) << 32)),
+            error,
+            retries,
+        };
+
+        println!(
+            "nvmed: opcode {:#04x} on namespace {} (LBA {:?}) failed: {}",
+            record.opcode, record.nsid, record.lba, record.error
+        );
+
+        if self.error_log.len() == ERROR_LOG_LEN {
+            self.error_log.pop_front();
+        }
+        self.error_log.push_back(record);
+    }
+
+    /// Submits a command, resubmitting it while it fails with a transient error. A timeout or
+    /// a fatal controller status resets the controller before the command is retried.
+    fn submit(
+        &mut self,
+        queue: Queue,
+        cmd_init: impl Fn(u16) -> NvmeCommand,
+    ) -> Result<NvmeCompletion, NvmeError> {
+        if self.shut_down {
+            return Err(NvmeError::ShutDown);
+        }
+
+        let mut retries = 0;
+
+        loop {
+            let queue_pair = match queue {
+                Queue::Admin => &mut self.admin_queue,
+                Queue::Io => &mut self.io_queue,
+            };
+
+            let error = match queue_pair.submit_and_complete(&self.regs, &cmd_init) {
+                Ok(entry) => return Ok(entry),
+                Err(error) => error,
+            };
+
+            self.log_error(queue, &cmd_init(0), error, retries);
+
+            if error == NvmeError::Timeout || self.regs.is_fatal() {
+                if self.reset().is_err() {
+                    return Err(NvmeError::ControllerFatal);
+                }
+            } else if !error.is_transient() {
+                return Err(error);
+            }
+
+            if retries == MAX_RETRIES {
+                return Err(error);
+            }
+            retries += 1;
+        }
+    }
+
+    pub fn namespace_list(&mut self) -> Result<Vec<u32>, NvmeError> {
+        let scratch = self.scratch.phys;
+        self.submit(Queue::Admin, |c_id| {
+            NvmeCommand::identify_namespace_list(c_id, scratch, 0)
+        })?;
+
+        Ok(admin::namespace_list(&self.scratch[..]).collect())
+    }
+
+    /// Identifies namespace `nsid`, or returns `None` if its LBA format is not one nvmed can
+    /// use: a block must fit in one transfer.
+    pub fn identify_namespace(
+        &mut self,
+        nsid: u32,
+    ) -> Result<Option<IdentifyNamespace>, NvmeError> {
+        let scratch = self.scratch.phys;
+        self.submit(Queue::Admin, |c_id| {
+            NvmeCommand::identify_namespace(c_id, scratch, nsid)
+        })?;
+
+        Ok(IdentifyNamespace::new(&self.scratch[..])
+            .filter(|namespace| namespace.block_size <= self.max_transfer))
+    }
+
+    fn require_admin(&self, oacs: u16) -> Result<(), NvmeError> {
+        if self.identify.oacs() & oacs == 0 {
+            return Err(NvmeError::Unsupported);
+        }
+        Ok(())
+    }
+
+    /// Creates a namespace of `blocks` blocks using LBA format `lba_format`, returning its ID.
+    /// The new namespace is not attached to any controller yet.
+    pub fn create_namespace(&mut self, blocks: u64, lba_format: u8) -> Result<u32, NvmeError> {
+        self.require_admin(OACS_NAMESPACE_MANAGEMENT)?;
+
+        // The host-settable fields of the Identify Namespace structure: size, capacity and
+        // formatted LBA size.
+        self.scratch[..].fill(0);
+        self.scratch[0..8].copy_from_slice(&blocks.to_le_bytes());
+        self.scratch[8..16].copy_from_slice(&blocks.to_le_bytes());
+        self.scratch[26..27].fill(lba_format & 0xF);
+
+        let scratch = self.scratch.phys as u64;
+        let entry = self.submit(Queue::Admin, |c_id| NvmeCommand {
+            opcode: OPCODE_NAMESPACE_MANAGEMENT,
+            c_id,
+            d_ptr: [scratch, 0],
+            ..Default::default()
+        })?;
+
+        Ok(entry.command_specific)
+    }
+
+    pub fn delete_namespace(&mut self, nsid: u32) -> Result<(), NvmeError> {
+        self.require_admin(OACS_NAMESPACE_MANAGEMENT)?;
+
+        self.submit(Queue::Admin, |c_id| NvmeCommand {
+            opcode: OPCODE_NAMESPACE_MANAGEMENT,
+            c_id,
+            ns_id: nsid,
+            cdw10: 1,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    /// Attaches namespace `nsid` to this controller, or detaches it.
+    pub fn attach_namespace(&mut self, nsid: u32, attach: bool) -> Result<(), NvmeError> {
+        self.require_admin(OACS_NAMESPACE_MANAGEMENT)?;
+
+        // A controller list holding only this controller.
+        self.scratch[..].fill(0);
+        self.scratch[0..2].copy_from_slice(&1u16.to_le_bytes());
+        self.scratch[2..4].copy_from_slice(&self.identify.controller_id().to_le_bytes());
+
+        let scratch = self.scratch.phys as u64;
+        self.submit(Queue::Admin, |c_id| NvmeCommand {
+            opcode: OPCODE_NAMESPACE_ATTACHMENT,
+            c_id,
+            ns_id: nsid,
+            d_ptr: [scratch, 0],
+            cdw10: if attach { 0 } else { 1 },
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    /// Low-level formats namespace `nsid` with LBA format `lba_format`, destroying its data.
+    pub fn format(
+        &mut self,
+        nsid: u32,
+        lba_format: u8,
+        secure_erase: SecureErase,
+    ) -> Result<(), NvmeError> {
+        self.require_admin(OACS_FORMAT_NVM)?;
+
+        self.submit(Queue::Admin, |c_id| NvmeCommand {
+            opcode: OPCODE_FORMAT_NVM,
+            c_id,
+            ns_id: nsid,
+            cdw10: u32::from(lba_format & 0xF) | ((secure_erase as u32) << 9),
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    /// Reads `len` bytes of log page `lid` into the scratch page.
+    fn get_log_page(&mut self, lid: u8, nsid: u32, len: usize) -> Result<&[u8], NvmeError> {
+        assert!(len <= PAGE_SIZE && len.is_multiple_of(4));
+
+        let scratch = self.scratch.phys as u64;
+        let numd = (len / 4 - 1) as u32;
+        self.submit(Queue::Admin, |c_id| {
+            let mut cmd = NvmeCommand::get_log_page(c_id, numd, scratch, 0, lid, 0);
+            cmd.opcode = OPCODE_GET_LOG_PAGE;
+            cmd.ns_id = nsid;
+            cmd
+        })?;
+
+        Ok(&self.scratch[..len])
+    }
+
+    /// Fetches the controller-wide SMART / health log.
+    pub fn smart_log(&mut self) -> Result<SmartLog, NvmeError> {
+        self.get_log_page(SmartLog::LOG_ID, NSID_ALL, SmartLog::LEN)
+            .map(SmartLog::new)
+    }
+
+    /// Fetches the controller's Error Information log, newest entry first. Unused entries are
+    /// left out.
+    pub fn error_information_log(&mut self) -> Result<Vec<ErrorLogEntry>, NvmeError> {
+        let len = (self.identify.error_log_page_entries() * ErrorLogEntry::LEN).min(PAGE_SIZE);
+        self.get_log_page(ErrorLogEntry::LOG_ID, NSID_ALL, len)
+            .map(|data| {
+                data.chunks_exact(ErrorLogEntry::LEN)
+                    .map(ErrorLogEntry::new)
+                    .filter(|entry| entry.error_count != 0)
+                    .collect()
+            })
+    }
+
+    /// The power state the controller is in.
+    pub fn power_state(&mut self) -> Result<u8, NvmeError> {
+        let entry = self.submit(Queue::Admin, |c_id| NvmeCommand {
+            opcode: OPCODE_GET_FEATURES,
+            c_id,
+            cdw10: u32::from(FEATURE_POWER_MANAGEMENT),
+            ..Default::default()
+        })?;
+
+        Ok((entry.command_specific & 0x1F) as u8)
+    }
+
+    /// Moves the controller to power state `state`. With APST enabled the controller may
+    /// leave it again on its own.
+    pub fn set_power_state(&mut self, state: u8) -> Result<(), NvmeError> {
+        if usize::from(state) >= self.identify.power_state_count() {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        self.submit(Queue::Admin, |c_id| NvmeCommand {
+            opcode: OPCODE_SET_FEATURES,
+            c_id,
+            cdw10: u32::from(FEATURE_POWER_MANAGEMENT),
+            cdw11: u32::from(state),
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    /// Sets up Autonomous Power State Transitions, letting the controller drop into
+    /// non-operational states whose exit latency is at most `max_latency` microseconds once
+    /// it has been idle for a while. A `max_latency` of 0 turns APST off.
+    pub fn configure_apst(&mut self, max_latency: u32) -> Result<(), NvmeError> {
+        if !self.identify.apst_supported() {
+            return Err(NvmeError::Unsupported);
+        }
+
+        let cmd_init = self.apst_command(max_latency);
+        self.submit(Queue::Admin, cmd_init)?;
+        self.apst_max_latency = Some(max_latency);
+
+        Ok(())
+    }
+
+    /// Fills the scratch page with the APST table for `max_latency` and returns the Set
+    /// Features command loading it.
+    fn apst_command(&mut self, max_latency: u32) -> impl Fn(u16) -> NvmeCommand + use<> {
+        let power_states = self.identify.power_states().collect::<Vec<_>>();
+        let table = apst_table(&power_states, max_latency);
+        let enable = table.iter().any(|&entry| entry != 0);
+
+        self.scratch[..].fill(0);
+        for (bytes, entry) in self.scratch[..].chunks_exact_mut(8).zip(table) {
+            bytes.copy_from_slice(&entry.to_le_bytes());
+        }
+
+        let scratch = self.scratch.phys as u64;
+        move |c_id| NvmeCommand {
+            opcode: OPCODE_SET_FEATURES,
+            c_id,
+            d_ptr: [scratch, 0],
+            cdw10: u32::from(FEATURE_APST),
+            cdw11: u32::from(enable),
+            ..Default::default()
+        }
+    }
+
+    /// PRP entries describing the first `len` bytes of the bounce buffer.
+    fn bounce_prps(&self, len: usize) -> (u64, u64) {
+        let prp1 = self.bounce.phys as u64;
+        let prp2 = match len.div_ceil(PAGE_SIZE) {
+            0 | 1 => 0,
+            2 => prp1 + PAGE_SIZE as u64,
+            _ => self.prp_list.phys as u64,
+        };
+
+        (prp1, prp2)
+    }
+
+    /// Reads `buf.len() / block_size` blocks starting at `lba`. `buf` must be a whole number of
+    /// blocks and no longer than [`Self::max_transfer`].
+    pub fn read_blocks(
+        &mut self,
+        nsid: u32,
+        block_size: usize,
+        lba: u64,
+        buf: &mut [u8],
+    ) -> Result<(), NvmeError> {
+        assert!(buf.len() <= self.max_transfer && buf.len().is_multiple_of(block_size));
+
+        let blocks = (buf.len() / block_size) as u16;
+        let (prp1, prp2) = self.bounce_prps(buf.len());
+
+        self.submit(Queue::Io, |c_id| {
+            NvmeCommand::io_read(c_id, nsid, lba, blocks - 1, prp1, prp2)
+        })?;
+
+        buf.copy_from_slice(&self.bounce[..buf.len()]);
+
+        Ok(())
+    }
+
+    /// Writes `buf` starting at block `lba`, with the same constraints as [`Self::read_blocks`].
+    /// With `fua` set, the write completes only once the data is durable.
+    pub fn write_blocks(
+        &mut self,
+        nsid: u32,
+        block_size: usize,
+        lba: u64,
+        buf: &[u8],
+        fua: bool,
+    ) -> Result<(), NvmeError> {
+        assert!(buf.len() <= self.max_transfer && buf.len().is_multiple_of(block_size));
+
+        let blocks = (buf.len() / block_size) as u16;
+        let (prp1, prp2) = self.bounce_prps(buf.len());
+
+        self.bounce[..buf.len()].copy_from_slice(buf);
+
+        self.submit(Queue::Io, |c_id| {
+            let mut cmd = NvmeCommand::io_write(c_id, nsid, lba, blocks - 1, prp1, prp2);
+            if fua {
+                cmd.cdw12 |= RW_FUA;
+            }
+            cmd
+        })?;
+
+        Ok(())
+    }
+
+    /// Commits everything in the volatile write cache of namespace `nsid` to media.
+    pub fn flush(&mut self, nsid: u32) -> Result<(), NvmeError> {
+        // Without a volatile write cache every completed write is already durable.
+        if !self.identify.volatile_write_cache() {
+            return Ok(());
+        }
+
+        self.submit(Queue::Io, |c_id| NvmeCommand {
+            opcode: OPCODE_FLUSH,
+            c_id,
+            ns_id: nsid,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    /// Deallocates the given `(lba, blocks)` ranges with a Dataset Management command. At most
+    /// [`DSM_MAX_RANGES`] ranges fit in one command, each no longer than `u32::MAX` blocks.
+    pub fn deallocate(&mut self, nsid: u32, ranges: &[(u64, u32)]) -> Result<(), NvmeError> {
+        assert!(!ranges.is_empty() && ranges.len() <= DSM_MAX_RANGES);
+
+        if self.identify.oncs() & ONCS_DATASET_MANAGEMENT == 0 {
+            return Err(NvmeError::Unsupported);
+        }
+
+        // Each range is 16 bytes: context attributes, length in blocks, starting LBA.
+        self.scratch[..ranges.len() * 16].fill(0);
+        for (entry, &(lba, blocks)) in self.scratch[..].chunks_exact_mut(16).zip(ranges) {
+            entry[4..8].copy_from_slice(&blocks.to_le_bytes());
+            entry[8..16].copy_from_slice(&lba.to_le_bytes());
+        }
+
+        let scratch = self.scratch.phys as u64;
+        let nr = ranges.len() as u32 - 1;
+        self.submit(Queue::Io, |c_id| NvmeCommand {
+            opcode: OPCODE_DATASET_MANAGEMENT,
+            c_id,
+            ns_id: nsid,
+            d_ptr: [scratch, 0],
+            cdw10: nr,
+            cdw11: DSM_DEALLOCATE,
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    /// Zeroes `blocks` blocks starting at `lba` without transferring data. `blocks` must be
+    /// between 1 and [`WRITE_ZEROES_MAX_BLOCKS`].
+    pub fn write_zeroes(&mut self, nsid: u32, lba: u64, blocks: u64) -> Result<(), NvmeError> {
+        assert!(blocks > 0 && blocks <= WRITE_ZEROES_MAX_BLOCKS);
+
+        if self.identify.oncs() & ONCS_WRITE_ZEROES == 0 {
+            return Err(NvmeError::Unsupported);
+        }
+
+        self.submit(Queue::Io, |c_id| {
+            NvmeCommand::write_zeroes(c_id, nsid, lba, (blocks - 1) as u16, false)
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Builds the APST table. Every power state transitions to the next deeper non-operational
+/// state that can be left within `max_latency` microseconds, after an idle time proportional
+/// to that state's latency.
+fn apst_table(power_states: &[PowerState], max_latency: u32) -> [u64; APST_ENTRIES] {
+    let mut table = [0; APST_ENTRIES];
+    let mut target = 0;
+
+    for (state, power_state) in power_states.iter().enumerate().rev() {
+        table[state] = target;
+
+        if !power_state.non_operational || power_state.exit_latency > max_latency {
+            continue;
+        }
+
+        let latency = u64::from(power_state.entry_latency) + u64::from(power_state.exit_latency);
+        let idle_ms = (latency * APST_IDLE_FACTOR)
+            .div_ceil(1000)
+            .min(APST_MAX_IDLE_MS);
+        target = ((state as u64) << 3) | (idle_ms << 8);
+    }
+
+    table
+}
@@ -0,0 +1,297 @@
+use core::ops::Range;
+
+use alloc::vec::Vec;
+
+use crate::{
+    controller::{DSM_MAX_RANGES, NvmeController, WRITE_ZEROES_MAX_BLOCKS},
+    error::NvmeError,
+    println,
+};
+
+/// A transfer that failed part way through. The first `done` bytes of the caller's buffer
+/// were transferred before the failing command.
+#[derive(Debug)]
+pub struct IoError {
+    pub done: usize,
+    pub error: NvmeError,
+}
+
+/// A namespace on one of nvmed's controllers, addressed in bytes. Requests reaching past
+/// `len()` are rejected before anything is sent to the controller.
+pub struct NvmeDisk {
+    pub controller: usize,
+    pub nsid: u32,
+    pub block_size: usize,
+    pub blocks: u64,
+}
+
+impl NvmeDisk {
+    pub fn len(&self) -> usize {
+        self.blocks as usize * self.block_size
+    }
+
+    /// Whether the disk has no blocks, as when its namespace went away.
+    pub fn is_empty(&self) -> bool {
+        self.blocks == 0
+    }
+
+    /// Fails unless `offset..offset + len` lies within the disk.
+    pub(crate) fn check_range(&self, offset: usize, len: usize) -> Result<(), NvmeError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => Ok(()),
+            _ => Err(NvmeError::OutOfRange),
+        }
+    }
+
+    /// Splits the blocks covering `start..end` into runs no longer than one command may
+    /// transfer.
+    fn chunks(
+        &self,
+        controller: &NvmeController,
+        start: usize,
+        end: usize,
+    ) -> impl Iterator<Item = Range<usize>> + use<> {
+        let start_block = start / self.block_size;
+        let end_block = end.div_ceil(self.block_size);
+        let max_blocks = controller.max_transfer() / self.block_size;
+
+        (start_block..end_block)
+            .step_by(max_blocks)
+            .map(move |block| block..(block + max_blocks).min(end_block))
+    }
+
+    pub fn read(
+        &self,
+        controller: &mut NvmeController,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, IoError> {
+        self.check_range(offset, buf.len())
+            .map_err(|error| IoError { done: 0, error })?;
+
+        let start = offset;
+        let end = start + buf.len();
+
+        for blocks in self.chunks(controller, start, end) {
+            let chunk_start = blocks.start * self.block_size;
+            let chunk_end = blocks.end * self.block_size;
+
+            let copy_start = start.max(chunk_start);
+            let copy_end = end.min(chunk_end);
+
+            let done = copy_start - start;
+
+            let mut tmp = alloc::vec![0u8; chunk_end - chunk_start];
+
+            controller
+                .read_blocks(self.nsid, self.block_size, blocks.start as u64, &mut tmp)
+                .map_err(|error| IoError { done, error })?;
+
+            buf[copy_start - start..copy_end - start]
+                .copy_from_slice(&tmp[copy_start - chunk_start..copy_end - chunk_start]);
+        }
+
+        Ok(buf.len())
+    }
+
+    pub fn write(
+        &self,
+        controller: &mut NvmeController,
+        offset: usize,
+        buf: &[u8],
+        fua: bool,
+    ) -> Result<usize, IoError> {
+        self.check_range(offset, buf.len())
+            .map_err(|error| IoError { done: 0, error })?;
+
+        let start = offset;
+        let end = start + buf.len();
+
+        for blocks in self.chunks(controller, start, end) {
+            let chunk_start = blocks.start * self.block_size;
+            let chunk_end = blocks.end * self.block_size;
+
+            let copy_start = start.max(chunk_start);
+            let copy_end = end.min(chunk_end);
+
+            let done = copy_start - start;
+
+            let mut tmp = alloc::vec![0u8; chunk_end - chunk_start];
+
+            // Only the first and last chunk can cover a partial block, which has to be read
+            // back before it is overwritten.
+            if copy_start != chunk_start || copy_end != chunk_end {
+                controller
+                    .read_blocks(self.nsid, self.block_size, blocks.start as u64, &mut tmp)
+                    .map_err(|error| IoError { done, error })?;
+            }
+
+            tmp[copy_start - chunk_start..copy_end - chunk_start]
+                .copy_from_slice(&buf[copy_start - start..copy_end - start]);
+
+            controller
+                .write_blocks(self.nsid, self.block_size, blocks.start as u64, &tmp, fua)
+                .map_err(|error| IoError { done, error })?;
+        }
+
+        Ok(buf.len())
+    }
+
+    pub fn flush(&self, controller: &mut NvmeController) -> Result<(), NvmeError> {
+        controller.flush(self.nsid)
+    }
+
+    /// The whole blocks inside `offset..offset + len`.
+    fn inner_blocks(&self, offset: usize, len: usize) -> Range<u64> {
+        let start = offset.div_ceil(self.block_size) as u64;
+        let end = ((offset + len) / self.block_size) as u64;
+        start..end.max(start)
+    }
+
+    /// Tells the controller the data in `offset..offset + len` is no longer needed. Blocks
+    /// only partly inside the range are left alone, so this may discard nothing at all.
+    pub fn discard(
+        &self,
+        controller: &mut NvmeController,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), NvmeError> {
+        self.check_range(offset, len)?;
+
+        let blocks = self.inner_blocks(offset, len);
+        let max_blocks = u64::from(u32::MAX);
+
+        let ranges = blocks
+            .clone()
+            .step_by(max_blocks as usize)
+            .map(|lba| (lba, (blocks.end - lba).min(max_blocks) as u32))
+            .collect::<alloc::vec::Vec<_>>();
+
+        for ranges in ranges.chunks(DSM_MAX_RANGES) {
+            controller.deallocate(self.nsid, ranges)?;
+        }
+
+        Ok(())
+    }
+
+    /// Zeroes `offset..offset + len`. Whole blocks use Write Zeroes where the controller has
+    /// it; partial blocks at either end, and everything on controllers without it, are written
+    /// out as zero buffers.
+    pub fn write_zeroes(
+        &self,
+        controller: &mut NvmeController,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), NvmeError> {
+        self.check_range(offset, len)?;
+
+        let end = offset + len;
+        let blocks = self.inner_blocks(offset, len);
+        let inner_start = blocks.start as usize * self.block_size;
+        let inner_end = blocks.end as usize * self.block_size;
+
+        if blocks.is_empty() {
+            return self.write_zero_buffer(controller, offset, end);
+        }
+
+        self.write_zero_buffer(controller, offset, inner_start)?;
+
+        for lba in blocks.clone().step_by(WRITE_ZEROES_MAX_BLOCKS as usize) {
+            let count = (blocks.end - lba).min(WRITE_ZEROES_MAX_BLOCKS);
+            match controller.write_zeroes(self.nsid, lba, count) {
+                Ok(()) => {}
+                Err(NvmeError::Unsupported) => {
+                    let start = lba as usize * self.block_size;
+                    self.write_zero_buffer(controller, start, inner_end)?;
+                    break;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        self.write_zero_buffer(controller, inner_end, end)
+    }
+
+    fn write_zero_buffer(
+        &self,
+        controller: &mut NvmeController,
+        start: usize,
+        end: usize,
+    ) -> Result<(), NvmeError> {
+        let step = controller.max_transfer();
+        let zeroes = alloc::vec![0u8; step.min(end - start)];
+
+        for chunk_start in (start..end).step_by(step) {
+            let chunk_end = (chunk_start + step).min(end);
+            self.write(
+                controller,
+                chunk_start,
+                &zeroes[..chunk_end - chunk_start],
+                false,
+            )
+            .map_err(|error| error.error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Brings the disks of controller `controller` in line with its active namespaces. Existing
+/// disks keep their index: a namespace that went away is left with no blocks, and new ones
+/// are added at the end.
+pub fn scan_namespaces(
+    nvme_controller: &mut NvmeController,
+    controller: usize,
+    nvme_disks: &mut Vec<NvmeDisk>,
+) -> Result<(), NvmeError> {
+    let list = nvme_controller.namespace_list()?;
+
+    for nvme_disk in nvme_disks
+        .iter_mut()
+        .filter(|nvme_disk| nvme_disk.controller == controller)
+    {
+        let namespace = match list.contains(&nvme_disk.nsid) {
+            true => nvme_controller.identify_namespace(nvme_disk.nsid)?,
+            false => None,
+        };
+        match namespace {
+            Some(namespace) => {
+                nvme_disk.block_size = namespace.block_size;
+                nvme_disk.blocks = namespace.blocks;
+            }
+            None => nvme_disk.blocks = 0,
+        }
+    }
+
+    for nsid in list {
+        let known = nvme_disks
+            .iter()
+            .any(|nvme_disk| nvme_disk.controller == controller && nvme_disk.nsid == nsid);
+        if known {
+            continue;
+        }
+
+        match nvme_controller.identify_namespace(nsid) {
+            Ok(Some(namespace)) => {
+                println!(
+                    "Namespace {}: disk {}, {} blocks of {} bytes",
+                    nsid,
+                    nvme_disks.len(),
+                    namespace.blocks,
+                    namespace.block_size
+                );
+
+                nvme_disks.push(NvmeDisk {
+                    controller,
+                    nsid,
+                    block_size: namespace.block_size,
+                    blocks: namespace.blocks,
+                });
+            }
+            Ok(None) => println!("Namespace {}: unsupported LBA format", nsid),
+            Err(error) => println!("Namespace {}: identify failed: {}", nsid, error),
+        }
+    }
+
+    Ok(())
+}
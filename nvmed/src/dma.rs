@@ -0,0 +1,21 @@
+use nvme::memory::Allocator;
+
+pub struct NvmeAllocator;
+
+impl Allocator for NvmeAllocator {
+    #[cfg(feature = "rstd")]
+    unsafe fn allocate(&self, size: usize) -> (usize, usize) {
+        let (phys, virt) = rstd::dma::DmaManager::allocate(size);
+        (phys.as_u64() as usize, virt.as_u64() as usize)
+    }
+
+    /// Host builds talk to an emulated controller, which reaches the buffer through the same
+    /// address nvmed does, so the "physical" address is just the virtual one.
+    #[cfg(not(feature = "rstd"))]
+    unsafe fn allocate(&self, size: usize) -> (usize, usize) {
+        let layout =
+            core::alloc::Layout::from_size_align(size.max(1), nvme::memory::PAGE_SIZE).unwrap();
+        let virt = alloc::alloc::alloc_zeroed(layout) as usize;
+        (virt, virt)
+    }
+}
@@ -0,0 +1,161 @@
+use core::fmt;
+
+// Values handed back to callers through `ret_val`, negated. They follow the usual errno
+// numbering so a caller can tell a media error from a bad request without knowing NVMe.
+const EIO: isize = 5;
+const EBADF: isize = 9;
+const EAGAIN: isize = 11;
+const ENODEV: isize = 19;
+const EINVAL: isize = 22;
+const ENOSPC: isize = 28;
+const EROFS: isize = 30;
+const ERANGE: isize = 34;
+const EOPNOTSUPP: isize = 95;
+const ETIMEDOUT: isize = 110;
+
+const SCT_GENERIC: u8 = 0x0;
+const SCT_COMMAND_SPECIFIC: u8 = 0x1;
+const SCT_MEDIA: u8 = 0x2;
+const SCT_PATH: u8 = 0x3;
+
+/// The status field of a completion queue entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NvmeStatus {
+    /// Status Code Type.
+    pub sct: u8,
+    /// Status Code.
+    pub sc: u8,
+    /// Do Not Retry: the controller expects the command to fail again if resubmitted.
+    pub dnr: bool,
+}
+
+impl NvmeStatus {
+    /// Decodes the completion's status field, returning `None` on success. Bit 0 of `status`
+    /// is the phase tag.
+    pub fn from_completion(status: u16) -> Option<Self> {
+        let status = status >> 1;
+        let sc = (status & 0xFF) as u8;
+        let sct = ((status >> 8) & 0x7) as u8;
+
+        if sc == 0 && sct == 0 {
+            return None;
+        }
+
+        Some(Self {
+            sct,
+            sc,
+            dnr: status & (1 << 14) != 0,
+        })
+    }
+
+    fn description(&self) -> &'static str {
+        match (self.sct, self.sc) {
+            (SCT_GENERIC, 0x01) => "invalid command opcode",
+            (SCT_GENERIC, 0x02) => "invalid field in command",
+            (SCT_GENERIC, 0x04) => "data transfer error",
+            (SCT_GENERIC, 0x05) => "aborted due to power loss",
+            (SCT_GENERIC, 0x06) => "internal error",
+            (SCT_GENERIC, 0x07) => "aborted by request",
+            (SCT_GENERIC, 0x0B) => "invalid namespace or format",
+            (SCT_GENERIC, 0x80) => "LBA out of range",
+            (SCT_GENERIC, 0x81) => "capacity exceeded",
+            (SCT_GENERIC, 0x82) => "namespace not ready",
+            (SCT_COMMAND_SPECIFIC, 0x0A) => "invalid format",
+            (SCT_COMMAND_SPECIFIC, 0x82) => "attempted write to read only range",
+            (SCT_MEDIA, 0x80) => "write fault",
+            (SCT_MEDIA, 0x81) => "unrecovered read error",
+            (SCT_MEDIA, 0x82) => "end-to-end guard check error",
+            (SCT_MEDIA, 0x85) => "compare failure",
+            (SCT_MEDIA, 0x86) => "access denied",
+            (SCT_MEDIA, 0x87) => "deallocated or unwritten logical block",
+            (SCT_PATH, _) => "path error",
+            _ => "unknown status",
+        }
+    }
+}
+
+impl fmt::Display for NvmeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (SCT {:#x}, SC {:#04x}{})",
+            self.description(),
+            self.sct,
+            self.sc,
+            if self.dnr { ", DNR" } else { "" }
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NvmeError {
+    /// The command completed with an error status.
+    Status(NvmeStatus),
+    /// The command, or a controller state change, did not complete in time.
+    Timeout,
+    /// The controller failed and could not be brought back by a reset.
+    ControllerFatal,
+    /// The request was not made on a handle opened to a disk.
+    NoHandle,
+    /// The controller does not implement the optional command, or needs a memory page size
+    /// nvmed does not use.
+    Unsupported,
+    /// The request itself is malformed, e.g. an unknown ioctl.
+    InvalidArgument,
+    /// The request reaches past the end of the disk.
+    OutOfRange,
+    /// The controller has been shut down.
+    ShutDown,
+}
+
+impl NvmeError {
+    /// Whether resubmitting the same command may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Status(status) => !status.dnr,
+            Self::Timeout => true,
+            Self::ControllerFatal
+            | Self::NoHandle
+            | Self::Unsupported
+            | Self::InvalidArgument
+            | Self::OutOfRange
+            | Self::ShutDown => false,
+        }
+    }
+
+    /// The value reported to the caller through `ret_val`.
+    pub fn ret_val(&self) -> isize {
+        -match self {
+            Self::Status(status) => match (status.sct, status.sc) {
+                (SCT_GENERIC, 0x80) => ERANGE,
+                (SCT_GENERIC, 0x81) => ENOSPC,
+                (SCT_GENERIC, 0x82) => EAGAIN,
+                (SCT_GENERIC, 0x01 | 0x02 | 0x0B) => EINVAL,
+                (SCT_COMMAND_SPECIFIC, 0x82) => EROFS,
+                _ => EIO,
+            },
+            Self::Timeout => ETIMEDOUT,
+            Self::ControllerFatal => ENODEV,
+            Self::NoHandle => EBADF,
+            Self::Unsupported => EOPNOTSUPP,
+            Self::InvalidArgument => EINVAL,
+            Self::OutOfRange => ERANGE,
+            Self::ShutDown => ENODEV,
+        }
+    }
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "{}", status),
+            Self::Timeout => write!(f, "Command timed out"),
+            Self::ControllerFatal => write!(f, "Controller fatal status"),
+            Self::NoHandle => write!(f, "No disk opened"),
+            Self::Unsupported => write!(f, "Command not supported by controller"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+            Self::OutOfRange => write!(f, "Beyond the end of the disk"),
+            Self::ShutDown => write!(f, "Controller shut down"),
+        }
+    }
+}
@@ -1,33 +1,112 @@
-use nvme::nvme::NvmeDevice;
+//! The `:block:nvme` scheme: disks, their info files and the controllers' admin handles.
+//!
+//! The kernel does not give `USER_OPEN` a way to return a handle ID: it tags every later
+//! request with the path it was opened with, and that is all nvmed sees. Handles are
+//! therefore per path, not per open. Clients opening the same path share its handle,
+//! including the Force Unit Access setting of a disk, and opening a path again replaces its
+//! handle for everyone who opened it.
+
 use rstd::{
-    alloc::vec::Vec,
+    alloc::{
+        collections::btree_map::BTreeMap,
+        string::{String, ToString},
+        vec::Vec,
+    },
     fs::{USER_IOCTL, USER_OPEN, USER_READ, USER_SIZE, USER_WRITE, UserCommand},
     println,
 };
 use spin::Mutex;
 
-use crate::nvme::NvmeAllocator;
+use nvmed::{
+    cache::{BlockCache, DEFAULT_CACHE_SIZE},
+    controller::{NvmeController, SecureErase},
+    disk::{IoError, NvmeDisk, scan_namespaces},
+    error::NvmeError,
+    info,
+};
+
+/// Writes the disk's blocks held in nvmed's cache back and commits the disk's volatile write
+/// cache to media.
+pub const NVME_IOCTL_FLUSH: usize = 1;
+/// Deallocates `arg` bytes starting at the file offset. Partial blocks are left alone.
+pub const NVME_IOCTL_DISCARD: usize = 2;
+/// Zeroes `arg` bytes starting at the file offset.
+pub const NVME_IOCTL_WRITE_ZEROES: usize = 3;
+/// Turns Force Unit Access on (`arg != 0`) or off for later writes through this path, by
+/// every client that opened it.
+pub const NVME_IOCTL_SET_FUA: usize = 4;
+/// Returns the number of logical blocks on the disk. USER_SIZE reports the size in bytes.
+pub const NVME_IOCTL_BLOCK_COUNT: usize = 5;
+/// Returns the disk's logical block size in bytes.
+pub const NVME_IOCTL_BLOCK_SIZE: usize = 6;
+/// Resizes nvmed's block cache, which all disks share, to `arg` bytes. 0 turns it off.
+pub const NVME_IOCTL_SET_CACHE_SIZE: usize = 7;
+
+// Ioctls on a controller's admin handle, `nvme:admin:<controller>`. Each takes one packed
+// argument and rescans the controller's namespaces afterwards.
+
+/// Creates a namespace and returns its ID. Bits 0-55 of `arg` are the size in blocks, bits
+/// 56-59 the LBA format index.
+pub const NVME_IOCTL_NS_CREATE: usize = 16;
+/// Deletes namespace `arg`.
+pub const NVME_IOCTL_NS_DELETE: usize = 17;
+/// Attaches namespace `arg` to the controller.
+pub const NVME_IOCTL_NS_ATTACH: usize = 18;
+/// Detaches namespace `arg` from the controller.
+pub const NVME_IOCTL_NS_DETACH: usize = 19;
+/// Formats a namespace. Bits 0-31 of `arg` are the namespace ID, bits 32-35 the LBA format
+/// index and bits 36-37 the secure erase setting: 0 none, 1 user data, 2 cryptographic.
+pub const NVME_IOCTL_FORMAT: usize = 20;
+
+// Power management on the admin handle. These leave the namespaces alone.
+
+/// Returns the controller's current power state.
+pub const NVME_IOCTL_GET_POWER_STATE: usize = 21;
+/// Moves the controller to power state `arg`.
+pub const NVME_IOCTL_SET_POWER_STATE: usize = 22;
+/// Reconfigures Autonomous Power State Transitions to use the non-operational states that
+/// can be left within `arg` microseconds. 0 turns APST off.
+pub const NVME_IOCTL_SET_APST: usize = 23;
+
+/// Writes back everything cached for the controller's disks, flushes them and shuts the
+/// controller down, ahead of power-off. The controller's disks fail all I/O afterwards, until
+/// `NVME_IOCTL_RESUME`.
+pub const NVME_IOCTL_SHUTDOWN: usize = 24;
+/// Brings the controller back after `NVME_IOCTL_SHUTDOWN` if power-off failed, so its disks
+/// take I/O again.
+pub const NVME_IOCTL_RESUME: usize = 25;
 
 enum NvmeHandle {
-    NoHandle,
-    RwHandle(usize),
+    Rw {
+        disk: usize,
+        fua: bool,
+    },
+    /// One of the text files next to a disk, rendered when it was opened.
+    Info(String),
+    /// Namespace management and formatting for a whole controller.
+    Admin(usize),
 }
 
 pub struct NvmeFS {
     lock: Mutex<()>,
-    nvme_devices: Vec<NvmeDevice<NvmeAllocator>>,
-    nvme_lens: Vec<usize>,
-    current_handle: NvmeHandle,
+    nvme_controllers: Vec<NvmeController>,
+    nvme_disks: Vec<NvmeDisk>,
+    cache: BlockCache,
+    /// Open handles, keyed by the path they were opened with, which is how the kernel tags
+    /// every request. Clients using different disks or files do not disturb each other;
+    /// clients using the same path share a handle.
+    handles: BTreeMap<String, NvmeHandle>,
     user_command: UserCommand,
 }
 
 impl NvmeFS {
-    pub fn new(nvme_devices: Vec<NvmeDevice<NvmeAllocator>>, nvme_lens: Vec<usize>) -> Self {
+    pub fn new(nvme_controllers: Vec<NvmeController>, nvme_disks: Vec<NvmeDisk>) -> Self {
         Self {
             lock: Mutex::new(()),
-            nvme_devices,
-            nvme_lens,
-            current_handle: NvmeHandle::NoHandle,
+            nvme_controllers,
+            nvme_disks,
+            cache: BlockCache::new(DEFAULT_CACHE_SIZE),
+            handles: BTreeMap::new(),
             user_command: UserCommand::default(),
         }
     }
@@ -42,44 +121,45 @@ impl NvmeFS {
             if cmd != 0 {
                 match cmd {
                     USER_OPEN => {
-                        self.open(
-                            str::from_utf8(unsafe {
-                                core::slice::from_raw_parts(
-                                    self.user_command.buf_addr as *const u8,
-                                    self.user_command.buf_size,
-                                )
-                            })
-                            .unwrap(),
-                        );
+                        // No handle is opened for a path that is not UTF-8, so requests on it
+                        // fail with NoHandle.
+                        if let Ok(path) = str::from_utf8(unsafe {
+                            core::slice::from_raw_parts(
+                                self.user_command.buf_addr as *const u8,
+                                self.user_command.buf_size,
+                            )
+                        }) {
+                            self.open(path);
+                        }
                     }
                     USER_READ => {
-                        if self
-                            .read(self.user_command.offset, unsafe {
-                                core::slice::from_raw_parts_mut(
-                                    self.user_command.buf_addr as *mut u8,
-                                    self.user_command.buf_size,
-                                )
-                            })
-                            .is_ok()
-                        {
-                            self.user_command.ret_val = 0;
-                        } else {
-                            self.user_command.ret_val = -1;
+                        match self.read(self.user_command.offset, unsafe {
+                            core::slice::from_raw_parts_mut(
+                                self.user_command.buf_addr as *mut u8,
+                                self.user_command.buf_size,
+                            )
+                        }) {
+                            Ok(_) => self.user_command.ret_val = 0,
+                            Err(IoError { done, error }) => {
+                                // Let the caller know how much of a split transfer made it.
+                                self.user_command.ret_val = error.ret_val();
+                                self.user_command.ret_val2 = done as isize;
+                            }
                         }
                     }
                     USER_WRITE => {
-                        if self
-                            .write(self.user_command.offset, unsafe {
-                                core::slice::from_raw_parts(
-                                    self.user_command.buf_addr as *const u8,
-                                    self.user_command.buf_size,
-                                )
-                            })
-                            .is_ok()
-                        {
-                            self.user_command.ret_val = 0;
-                        } else {
-                            self.user_command.ret_val = -1;
+                        match self.write(self.user_command.offset, unsafe {
+                            core::slice::from_raw_parts(
+                                self.user_command.buf_addr as *const u8,
+                                self.user_command.buf_size,
+                            )
+                        }) {
+                            Ok(_) => self.user_command.ret_val = 0,
+                            Err(IoError { done, error }) => {
+                                // Let the caller know how much of a split transfer made it.
+                                self.user_command.ret_val = error.ret_val();
+                                self.user_command.ret_val2 = done as isize;
+                            }
                         }
                     }
                     USER_SIZE => {
@@ -90,15 +170,14 @@ impl NvmeFS {
                         }
                     }
                     USER_IOCTL => {
-                        if let Ok(ret) = self.ioctl(unsafe {
+                        match self.ioctl(unsafe {
                             core::slice::from_raw_parts_mut(
                                 self.user_command.buf_addr as *mut usize,
                                 self.user_command.buf_size,
                             )
                         }) {
-                            self.user_command.ret_val = ret as isize;
-                        } else {
-                            self.user_command.ret_val = -1;
+                            Ok(ret) => self.user_command.ret_val = ret as isize,
+                            Err(error) => self.user_command.ret_val = error.ret_val(),
                         }
                     }
                     _ => println!("nvmed: unknown command: {}", cmd),
@@ -112,109 +191,275 @@ impl NvmeFS {
         }
     }
 
+    /// The path the handle of the request being served was opened with, or `None` if it is not
+    /// UTF-8.
+    fn request_path(&self) -> Option<String> {
+        let path_addr = self.user_command.ret_val as *const u8;
+        let path_len = self.user_command.ret_val2 as usize;
+        unsafe { str::from_utf8(core::slice::from_raw_parts(path_addr, path_len)) }
+            .ok()
+            .map(str::to_string)
+    }
+
     fn open(&mut self, path: &str) {
         let _guard = self.lock.lock();
 
-        if let Some((nvme_device, idx)) = path.split_once(":") {
-            assert_eq!(nvme_device, "nvme");
-            let idx = idx.parse::<usize>().unwrap();
+        // A failed open must not leave an earlier handle for the same path behind.
+        self.handles.remove(path);
 
-            if idx < self.nvme_devices.len() {
-                self.current_handle = NvmeHandle::RwHandle(idx);
+        if let Some((nvme_device, rest)) = path.split_once(":") {
+            if nvme_device != "nvme" {
+                println!("nvmed: unknown device: {}", nvme_device);
+                return;
+            }
+            let (idx, file) = match rest.split_once(":") {
+                Some((idx, file)) => (idx, Some(file)),
+                None => (rest, None),
+            };
+
+            if idx == "admin" {
+                if let Some(controller) = file
+                    .and_then(|controller| controller.parse::<usize>().ok())
+                    .filter(|&controller| controller < self.nvme_controllers.len())
+                {
+                    self.handles
+                        .insert(path.to_string(), NvmeHandle::Admin(controller));
+                }
+                return;
             }
+
+            let Some(idx) = idx
+                .parse::<usize>()
+                .ok()
+                .filter(|&idx| idx < self.nvme_disks.len())
+            else {
+                return;
+            };
+
+            let nvme_disk = &self.nvme_disks[idx];
+            let controller = &mut self.nvme_controllers[nvme_disk.controller];
+
+            let handle = match file {
+                None => NvmeHandle::Rw {
+                    disk: idx,
+                    fua: false,
+                },
+                Some("identify") => NvmeHandle::Info(info::identify(controller, nvme_disk)),
+                Some("smart") => match controller.smart_log() {
+                    Ok(log) => NvmeHandle::Info(info::smart(&log)),
+                    Err(error) => {
+                        println!("nvmed: failed to read SMART log: {}", error);
+                        return;
+                    }
+                },
+                Some("error-log") => match controller.error_information_log() {
+                    Ok(entries) => NvmeHandle::Info(info::error_log(&entries)),
+                    Err(error) => {
+                        println!("nvmed: failed to read error log: {}", error);
+                        return;
+                    }
+                },
+                Some("failed-commands") => NvmeHandle::Info(info::failed_commands(controller)),
+                Some(file) => {
+                    println!("nvmed: unknown file: {}", file);
+                    return;
+                }
+            };
+            self.handles.insert(path.to_string(), handle);
         }
     }
 
-    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, ()> {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize, IoError> {
         let _guard = self.lock.lock();
 
-        match self.current_handle {
-            NvmeHandle::RwHandle(idx) => {
-                let nvme_device = &mut self.nvme_devices[idx];
-
-                let start = offset;
-                let end = start + buf.len();
-
-                let start_sector_read_start = start % 512;
-
-                let start_sector_id = start / 512;
-                let end_sector_id = (end - 1) / 512;
-
-                let buffer_size = (end_sector_id - start_sector_id + 1) * 512;
-                let mut tmp = rstd::alloc::vec![0u8; buffer_size];
-
-                nvme_device
-                    .read_copied(&mut tmp, start_sector_id as u64)
-                    .unwrap();
-
-                for i in 0..(end - start) {
-                    buf[i] = tmp[i + start_sector_read_start];
-                }
+        match self.request_path().and_then(|path| self.handles.get(&path)) {
+            Some(&NvmeHandle::Rw { disk, .. }) => self.cache.read(
+                disk,
+                &self.nvme_disks,
+                &mut self.nvme_controllers,
+                offset,
+                buf,
+            ),
+            Some(NvmeHandle::Info(text)) => {
+                let text = &text.as_bytes()[offset.min(text.len())..];
+                let len = text.len().min(buf.len());
+                buf[..len].copy_from_slice(&text[..len]);
+                Ok(len)
             }
-            _ => return Err(()),
+            _ => Err(IoError {
+                done: 0,
+                error: NvmeError::NoHandle,
+            }),
         }
-
-        Ok(buf.len())
     }
 
-    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<usize, ()> {
+    fn write(&mut self, offset: usize, buf: &[u8]) -> Result<usize, IoError> {
         let _guard = self.lock.lock();
 
-        match self.current_handle {
-            NvmeHandle::RwHandle(idx) => {
-                let nvme_device = &mut self.nvme_devices[idx];
+        match self.request_path().and_then(|path| self.handles.get(&path)) {
+            Some(&NvmeHandle::Rw { disk, fua }) => self.cache.write(
+                disk,
+                &self.nvme_disks,
+                &mut self.nvme_controllers,
+                offset,
+                buf,
+                fua,
+            ),
+            _ => Err(IoError {
+                done: 0,
+                error: NvmeError::NoHandle,
+            }),
+        }
+    }
 
-                let start = offset;
-                let end = start + buf.len();
+    fn size(&mut self) -> Result<usize, ()> {
+        let _guard = self.lock.lock();
 
-                let start_sector_read_start = start % 512;
+        match self.request_path().and_then(|path| self.handles.get(&path)) {
+            Some(&NvmeHandle::Rw { disk, .. }) => Ok(self.nvme_disks[disk].len()),
+            Some(NvmeHandle::Info(text)) => Ok(text.len()),
+            _ => Err(()),
+        }
+    }
 
-                let start_sector_id = start / 512;
-                let end_sector_id = (end - 1) / 512;
+    fn ioctl(&mut self, buf: &[usize]) -> Result<usize, NvmeError> {
+        let _guard = self.lock.lock();
 
-                let buffer_size = (end_sector_id - start_sector_id + 1) * 512;
-                let mut tmp = rstd::alloc::vec![0u8; buffer_size];
+        let cmd = buf[0];
+        let arg = buf[1];
 
-                nvme_device
-                    .read_copied(&mut tmp, start_sector_id as u64)
-                    .unwrap();
+        let Some(path) = self.request_path() else {
+            return Err(NvmeError::NoHandle);
+        };
+        match self.handles.get_mut(&path) {
+            Some(NvmeHandle::Rw { disk, fua }) => {
+                let disk = *disk;
+                let disks = &self.nvme_disks[..];
+                let controllers = &mut self.nvme_controllers[..];
+                let nvme_disk = &disks[disk];
+                let offset = self.user_command.offset;
 
-                for i in 0..(end - start) {
-                    tmp[i + start_sector_read_start] = buf[i];
+                match cmd {
+                    NVME_IOCTL_FLUSH => self.cache.flush(disk, disks, controllers)?,
+                    NVME_IOCTL_DISCARD => {
+                        self.cache.evict(disk, disks, controllers, offset, arg)?;
+                        nvme_disk.discard(&mut controllers[nvme_disk.controller], offset, arg)?;
+                    }
+                    NVME_IOCTL_WRITE_ZEROES => {
+                        self.cache.evict(disk, disks, controllers, offset, arg)?;
+                        nvme_disk.write_zeroes(
+                            &mut controllers[nvme_disk.controller],
+                            offset,
+                            arg,
+                        )?;
+                    }
+                    NVME_IOCTL_SET_FUA => *fua = arg != 0,
+                    NVME_IOCTL_BLOCK_COUNT => return Ok(nvme_disk.blocks as usize),
+                    NVME_IOCTL_BLOCK_SIZE => return Ok(nvme_disk.block_size),
+                    NVME_IOCTL_SET_CACHE_SIZE => self.cache.set_capacity(disks, controllers, arg),
+                    _ => return Err(NvmeError::InvalidArgument),
                 }
 
-                nvme_device
-                    .write_copied(&mut tmp, start_sector_id as u64)
-                    .unwrap();
+                Ok(0)
             }
-            _ => return Err(()),
-        }
+            Some(&mut NvmeHandle::Admin(controller)) => {
+                let nvme_controller = &mut self.nvme_controllers[controller];
+                match cmd {
+                    NVME_IOCTL_GET_POWER_STATE => {
+                        return Ok(nvme_controller.power_state()? as usize);
+                    }
+                    NVME_IOCTL_SET_POWER_STATE => {
+                        let state = u8::try_from(arg).map_err(|_| NvmeError::InvalidArgument)?;
+                        nvme_controller.set_power_state(state)?;
+                        return Ok(0);
+                    }
+                    NVME_IOCTL_SET_APST => {
+                        let max_latency =
+                            u32::try_from(arg).map_err(|_| NvmeError::InvalidArgument)?;
+                        nvme_controller.configure_apst(max_latency)?;
+                        return Ok(0);
+                    }
+                    NVME_IOCTL_SHUTDOWN => {
+                        for disk in 0..self.nvme_disks.len() {
+                            if self.nvme_disks[disk].controller == controller {
+                                self.cache.flush(
+                                    disk,
+                                    &self.nvme_disks,
+                                    &mut self.nvme_controllers,
+                                )?;
+                            }
+                        }
+                        self.nvme_controllers[controller].shutdown()?;
+                        for disk in 0..self.nvme_disks.len() {
+                            if self.nvme_disks[disk].controller == controller {
+                                self.cache.invalidate(disk, 0..u64::MAX);
+                            }
+                        }
+                        return Ok(0);
+                    }
+                    NVME_IOCTL_RESUME => {
+                        nvme_controller.resume()?;
+                        return Ok(0);
+                    }
+                    _ => {}
+                }
 
-        Ok(buf.len())
-    }
+                // Namespaces may change size or lose their data, so nothing cached for this
+                // controller's disks stays valid.
+                let disks = (0..self.nvme_disks.len())
+                    .filter(|&disk| self.nvme_disks[disk].controller == controller)
+                    .collect::<Vec<_>>();
+                for &disk in &disks {
+                    self.cache.write_back(
+                        disk,
+                        &self.nvme_disks,
+                        &mut self.nvme_controllers,
+                        0..u64::MAX,
+                    )?;
+                    self.cache.invalidate(disk, 0..u64::MAX);
+                }
 
-    fn size(&mut self) -> Result<usize, ()> {
-        let _guard = self.lock.lock();
+                let nvme_controller = &mut self.nvme_controllers[controller];
+                let ret = admin_ioctl(nvme_controller, cmd, arg)?;
 
-        match self.current_handle {
-            NvmeHandle::RwHandle(idx) => {
-                if let Some(&len) = self.nvme_lens.get(idx) {
-                    return Ok(len);
-                }
+                // Disks keep their index, so open handles stay valid across the rescan.
+                scan_namespaces(nvme_controller, controller, &mut self.nvme_disks)?;
+
+                Ok(ret)
             }
-            _ => return Err(()),
+            _ => Err(NvmeError::NoHandle),
         }
-
-        Err(())
     }
+}
 
-    #[allow(unused_variables)]
-    fn ioctl(&mut self, buf: &[usize]) -> Result<usize, ()> {
-        let _guard = self.lock.lock();
-
-        let cmd = buf[0];
-        let arg = buf[1];
-
-        Err(())
+fn admin_ioctl(
+    nvme_controller: &mut NvmeController,
+    cmd: usize,
+    arg: usize,
+) -> Result<usize, NvmeError> {
+    let nsid = arg as u32;
+
+    match cmd {
+        NVME_IOCTL_NS_CREATE => {
+            let blocks = (arg as u64) & ((1 << 56) - 1);
+            let lba_format = ((arg as u64 >> 56) & 0xF) as u8;
+            Ok(nvme_controller.create_namespace(blocks, lba_format)? as usize)
+        }
+        NVME_IOCTL_NS_DELETE => nvme_controller.delete_namespace(nsid).map(|()| 0),
+        NVME_IOCTL_NS_ATTACH => nvme_controller.attach_namespace(nsid, true).map(|()| 0),
+        NVME_IOCTL_NS_DETACH => nvme_controller.attach_namespace(nsid, false).map(|()| 0),
+        NVME_IOCTL_FORMAT => {
+            let lba_format = ((arg as u64 >> 32) & 0xF) as u8;
+            let secure_erase = match (arg as u64 >> 36) & 0x3 {
+                0 => SecureErase::None,
+                1 => SecureErase::UserData,
+                2 => SecureErase::Cryptographic,
+                _ => return Err(NvmeError::InvalidArgument),
+            };
+            nvme_controller
+                .format(nsid, lba_format, secure_erase)
+                .map(|()| 0)
+        }
+        _ => Err(NvmeError::InvalidArgument),
     }
 }
@@ -0,0 +1,156 @@
+//! The read-only text files nvmed serves next to each disk, for monitoring.
+
+use core::fmt::Write;
+
+use alloc::string::String;
+
+use crate::{
+    admin::{ErrorLogEntry, ONCS_DATASET_MANAGEMENT, ONCS_WRITE_ZEROES, SmartLog},
+    controller::NvmeController,
+    disk::NvmeDisk,
+};
+
+/// Kelvin to degrees Celsius, as NVMe reports temperatures in Kelvin.
+const KELVIN_OFFSET: i32 = 273;
+
+/// Bytes in one SMART data unit.
+const DATA_UNIT: u128 = 512 * 1000;
+
+pub fn identify(controller: &NvmeController, disk: &NvmeDisk) -> String {
+    let identify = controller.identify();
+    let oncs = identify.oncs();
+    let mut out = String::new();
+
+    writeln!(out, "model: {}", identify.model_number()).unwrap();
+    writeln!(out, "serial: {}", identify.serial_number()).unwrap();
+    writeln!(out, "firmware: {}", identify.firmware_revision()).unwrap();
+    writeln!(out, "max_transfer: {}", controller.max_transfer()).unwrap();
+    writeln!(
+        out,
+        "volatile_write_cache: {}",
+        identify.volatile_write_cache()
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "dataset_management: {}",
+        oncs & ONCS_DATASET_MANAGEMENT != 0
+    )
+    .unwrap();
+    writeln!(out, "write_zeroes: {}", oncs & ONCS_WRITE_ZEROES != 0).unwrap();
+    writeln!(out, "apst: {}", identify.apst_supported()).unwrap();
+    for (state, power_state) in identify.power_states().enumerate() {
+        writeln!(
+            out,
+            "power_state_{}: max_power={}.{:04}W entry_latency={}us exit_latency={}us{}",
+            state,
+            power_state.max_power / 10000,
+            power_state.max_power % 10000,
+            power_state.entry_latency,
+            power_state.exit_latency,
+            if power_state.non_operational {
+                " non_operational"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+    }
+    writeln!(out, "namespace: {}", disk.nsid).unwrap();
+    writeln!(out, "block_size: {}", disk.block_size).unwrap();
+    writeln!(out, "blocks: {}", disk.blocks).unwrap();
+    writeln!(out, "capacity: {}", disk.len()).unwrap();
+
+    out
+}
+
+pub fn smart(log: &SmartLog) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "critical_warning: {:#04x}", log.critical_warning).unwrap();
+    writeln!(
+        out,
+        "temperature: {}",
+        i32::from(log.temperature) - KELVIN_OFFSET
+    )
+    .unwrap();
+    writeln!(out, "available_spare: {}", log.available_spare).unwrap();
+    writeln!(
+        out,
+        "available_spare_threshold: {}",
+        log.available_spare_threshold
+    )
+    .unwrap();
+    writeln!(out, "percentage_used: {}", log.percentage_used).unwrap();
+    writeln!(
+        out,
+        "bytes_read: {}",
+        log.data_units_read.saturating_mul(DATA_UNIT)
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "bytes_written: {}",
+        log.data_units_written.saturating_mul(DATA_UNIT)
+    )
+    .unwrap();
+    writeln!(out, "power_cycles: {}", log.power_cycles).unwrap();
+    writeln!(out, "power_on_hours: {}", log.power_on_hours).unwrap();
+    writeln!(out, "unsafe_shutdowns: {}", log.unsafe_shutdowns).unwrap();
+    writeln!(out, "media_errors: {}", log.media_errors).unwrap();
+    writeln!(out, "error_log_entries: {}", log.error_log_entries).unwrap();
+
+    out
+}
+
+/// The controller's Error Information log, newest entry first. The log is per controller, so
+/// it also holds errors on the controller's other namespaces.
+pub fn error_log(entries: &[ErrorLogEntry]) -> String {
+    let mut out = String::new();
+
+    for entry in entries {
+        write!(
+            out,
+            "count={} sqid={} cid={} nsid={} lba={}",
+            entry.error_count, entry.sqid, entry.cid, entry.nsid, entry.lba
+        )
+        .unwrap();
+        if entry.parameter_error_location != 0xFFFF {
+            write!(
+                out,
+                " parameter={}:{}",
+                entry.parameter_error_location & 0xFF,
+                (entry.parameter_error_location >> 8) & 0x7
+            )
+            .unwrap();
+        }
+        match entry.status {
+            Some(status) => writeln!(out, " status=\"{}\"", status),
+            None => writeln!(out),
+        }
+        .unwrap();
+    }
+
+    out
+}
+
+/// The commands that failed on the disk's controller since nvmed started, oldest first, as
+/// nvmed saw them: with the opcode and how often it was retried.
+pub fn failed_commands(controller: &NvmeController) -> String {
+    let mut out = String::new();
+
+    for record in controller.error_log() {
+        write!(
+            out,
+            "opcode={:#04x} nsid={} retries={}",
+            record.opcode, record.nsid, record.retries
+        )
+        .unwrap();
+        if let Some(lba) = record.lba {
+            write!(out, " lba={}", lba).unwrap();
+        }
+        writeln!(out, " error=\"{}\"", record.error).unwrap();
+    }
+
+    out
+}
@@ -0,0 +1,28 @@
+#![no_std]
+#![allow(dead_code)]
+#![allow(unsafe_op_in_unsafe_fn)]
+
+extern crate alloc;
+
+pub mod admin;
+pub mod cache;
+pub mod controller;
+pub mod disk;
+pub mod dma;
+pub mod error;
+pub mod info;
+pub mod queue;
+
+// Console output only exists under rstd. Host builds, which run the tests against an emulated
+// controller, drop the messages.
+#[cfg(feature = "rstd")]
+pub(crate) use rstd::println;
+
+#[cfg(not(feature = "rstd"))]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+#[cfg(not(feature = "rstd"))]
+pub(crate) use println;
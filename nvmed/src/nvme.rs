@@ -1,20 +1,21 @@
-use nvme::{memory::Allocator, nvme::NvmeDevice};
+use nvmed::{
+    controller::{APST_DEFAULT_MAX_LATENCY, Mmio, NvmeController, Registers},
+    disk::scan_namespaces,
+    error::NvmeError,
+    queue::calibrate_tsc,
+};
 use rstd::{alloc::vec::Vec, println};
 
 use crate::fs::NvmeFS;
 
-pub struct NvmeAllocator;
-
-impl Allocator for NvmeAllocator {
-    unsafe fn allocate(&self, size: usize) -> (usize, usize) {
-        let (phys, virt) = rstd::dma::DmaManager::allocate(size);
-        (phys.as_u64() as usize, virt.as_u64() as usize)
+pub fn init() -> NvmeFS {
+    match calibrate_tsc() {
+        Some(ticks_per_ms) => println!("nvmed: TSC runs at {} kHz", ticks_per_ms),
+        None => println!("nvmed: no PIT to calibrate the TSC against, timeouts may run long"),
     }
-}
 
-pub fn init() -> NvmeFS {
-    let mut nvme_devices = Vec::new();
-    let mut nvme_lens = Vec::new();
+    let mut nvme_controllers = Vec::new();
+    let mut nvme_disks = Vec::new();
 
     let mut fd = usize::MAX;
     while fd == usize::MAX {
@@ -31,24 +32,23 @@ pub fn init() -> NvmeFS {
     let buffer = rstd::fs::ioctl(fd, 1, 0) as usize;
     rstd::mm::physmap(buffer, buffer, bar_fsize as usize);
 
-    let mut nvme_device = NvmeDevice::init(buffer, bar_fsize as usize, NvmeAllocator)
-        .expect("Failed to init NVMe device");
+    let mut nvme_controller =
+        NvmeController::init(Registers::new(Mmio::new(buffer, bar_fsize as usize)))
+            .expect("Failed to init NVMe device");
 
-    nvme_device
-        .identify_controller()
-        .expect("Failed to identify NVMe controller");
-
-    let list = nvme_device.identify_namespace_list(0);
-    println!("Namespace list: {:?}", list);
-
-    let namespace = nvme_device.identify_namespace(1);
-    println!("Namespace: {:?}", namespace);
+    match nvme_controller.configure_apst(APST_DEFAULT_MAX_LATENCY) {
+        Ok(()) | Err(NvmeError::Unsupported) => {}
+        Err(error) => println!("Failed to configure APST: {}", error),
+    }
 
-    let hd_size = namespace.1;
+    scan_namespaces(
+        &mut nvme_controller,
+        nvme_controllers.len(),
+        &mut nvme_disks,
+    )
+    .expect("Failed to list NVMe namespaces");
 
-    nvme_devices.push(nvme_device);
-    nvme_lens.push(hd_size as usize);
+    nvme_controllers.push(nvme_controller);
 
-    let fs = NvmeFS::new(nvme_devices, nvme_lens);
-    return fs;
+    NvmeFS::new(nvme_controllers, nvme_disks)
 }
@@ -0,0 +1,187 @@
+use core::{
+    arch::asm,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use nvme::{
+    cmd::NvmeCommand,
+    queues::{NvmeCompQueue, NvmeCompletion, NvmeSubQueue, QUEUE_LENGTH},
+};
+
+use crate::{
+    controller::Registers,
+    dma::NvmeAllocator,
+    error::{NvmeError, NvmeStatus},
+};
+
+/// How long a command may take before it is considered lost.
+const COMPLETION_TIMEOUT_MS: u64 = 30_000;
+
+/// TSC ticks per millisecond. rstd has no clock, so waits are timed with the TSC. Until
+/// [`calibrate_tsc`] has measured it, the rate is the fastest nvmed expects to run on: on a
+/// slower TSC a timeout lasts longer, never shorter.
+static TSC_TICKS_PER_MS: AtomicU64 = AtomicU64::new(5_000_000);
+
+/// The PIT's input clock, and how long the TSC is measured against it.
+const PIT_HZ: u64 = 1_193_182;
+const CALIBRATION_MS: u64 = 10;
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// System control port B, which gates PIT channel 2 and reads back its output.
+const PIT_GATE: u16 = 0x61;
+const GATE_2: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUT_2: u8 = 1 << 5;
+
+/// Polls of the PIT output before concluding that there is no PIT. A port read takes about a
+/// microsecond, so this gives up after about a second.
+const CALIBRATION_MAX_POLLS: usize = 1_000_000;
+/// Measured rates outside of this, in TSC ticks per millisecond, are taken for a failed
+/// measurement.
+const TSC_RATES: RangeInclusive<u64> = 100_000..=10_000_000;
+
+/// Bounds for how long a queue busy-polls before it starts yielding between polls.
+const MIN_SPIN_POLLS: usize = 64;
+const MAX_SPIN_POLLS: usize = 16_384;
+
+/// A point in time after which waiting is given up.
+#[derive(Clone, Copy)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    pub fn after_ms(ms: u64) -> Self {
+        let ticks_per_ms = TSC_TICKS_PER_MS.load(Ordering::Relaxed);
+        Self(tsc().saturating_add(ms.saturating_mul(ticks_per_ms)))
+    }
+
+    pub fn passed(&self) -> bool {
+        tsc() >= self.0
+    }
+}
+
+fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    let value;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Measures the TSC rate against PIT channel 2 and times later waits with it. Returns the
+/// rate in ticks per millisecond, or `None` if there is no PIT to measure against, in which
+/// case waits keep assuming the fastest rate. Being preempted while measuring makes the rate
+/// look faster, so timeouts err on the long side.
+pub fn calibrate_tsc() -> Option<u64> {
+    let latch = PIT_HZ * CALIBRATION_MS / 1000;
+    unsafe {
+        // Gate channel 2 on with the speaker off, and have it count `latch` ticks down in
+        // mode 0, whose output goes high once the count runs out.
+        outb(PIT_GATE, (inb(PIT_GATE) & !SPEAKER) | GATE_2);
+        outb(PIT_COMMAND, 0b1011_0000);
+        outb(PIT_CHANNEL_2, latch as u8);
+        outb(PIT_CHANNEL_2, (latch >> 8) as u8);
+    }
+
+    let start = tsc();
+    let fired = (0..CALIBRATION_MAX_POLLS).any(|_| unsafe { inb(PIT_GATE) } & OUT_2 != 0);
+    let ticks_per_ms = (tsc() - start) / CALIBRATION_MS;
+    if !fired || !TSC_RATES.contains(&ticks_per_ms) {
+        return None;
+    }
+
+    TSC_TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+    Some(ticks_per_ms)
+}
+
+/// Gives up the CPU while a command is outstanding. Host builds have no scheduler to yield to.
+fn yield_now() {
+    #[cfg(feature = "rstd")]
+    rstd::proc::r#yield();
+    #[cfg(not(feature = "rstd"))]
+    core::hint::spin_loop();
+}
+
+/// A submission queue and the completion queue it posts to, sharing one queue ID.
+pub struct QueuePair {
+    pub id: u16,
+    sub_queue: NvmeSubQueue,
+    comp_queue: NvmeCompQueue,
+    /// Polls to busy-wait for a completion before yielding the CPU, adapted to how quickly
+    /// recent commands completed.
+    spin_polls: usize,
+}
+
+impl QueuePair {
+    pub fn new(regs: &Registers, id: u16) -> Self {
+        let sub_queue = NvmeSubQueue::new(QUEUE_LENGTH, regs.sq_tail_doorbell(id), &NvmeAllocator)
+            .expect("Failed to allocate NVMe submission queue");
+        let comp_queue =
+            NvmeCompQueue::new(QUEUE_LENGTH, regs.cq_head_doorbell(id), &NvmeAllocator)
+                .expect("Failed to allocate NVMe completion queue");
+
+        Self {
+            id,
+            sub_queue,
+            comp_queue,
+            spin_polls: MIN_SPIN_POLLS,
+        }
+    }
+
+    pub fn sub_queue_addr(&self) -> usize {
+        self.sub_queue.get_addr()
+    }
+
+    pub fn comp_queue_addr(&self) -> usize {
+        self.comp_queue.get_addr()
+    }
+
+    /// Submits a single command and polls until its completion is posted. Polling spins for
+    /// a while, then yields between polls so a slow command does not hold the CPU.
+    pub fn submit_and_complete<F: FnOnce(u16) -> NvmeCommand>(
+        &mut self,
+        regs: &Registers,
+        cmd_init: F,
+    ) -> Result<NvmeCompletion, NvmeError> {
+        let c_id = self.sub_queue.tail as u16;
+        let tail = self.sub_queue.submit(cmd_init(c_id));
+        regs.write32(self.sub_queue.doorbell, tail as u32);
+
+        let deadline = Deadline::after_ms(COMPLETION_TIMEOUT_MS);
+        let mut polls = 0;
+        let (head, entry, _) = loop {
+            if let Some(completion) = self.comp_queue.complete() {
+                break completion;
+            }
+            if deadline.passed() {
+                return Err(NvmeError::Timeout);
+            }
+
+            if polls < self.spin_polls {
+                core::hint::spin_loop();
+            } else {
+                yield_now();
+            }
+            polls += 1;
+        };
+
+        // Spin about twice as long as this command took, so commands of similar latency
+        // complete without a yield while slow ones stop busy-waiting early.
+        self.spin_polls = (polls * 2).clamp(MIN_SPIN_POLLS, MAX_SPIN_POLLS);
+
+        regs.write32(self.comp_queue.doorbell, head as u32);
+        self.sub_queue.head = entry.sq_head as usize;
+
+        match NvmeStatus::from_completion(entry.status) {
+            Some(status) => Err(NvmeError::Status(status)),
+            None => Ok(entry),
+        }
+    }
+}
@@ -0,0 +1,283 @@
+mod emulator;
+
+use emulator::{Config, Emulator, Injection, OPCODE_READ, OPCODE_WRITE, io_commands};
+use nvmed::{cache::BlockCache, controller::NvmeController, disk::NvmeDisk, error::NvmeError};
+
+struct Setup {
+    emulator: Emulator,
+    controllers: Vec<NvmeController>,
+    disks: Vec<NvmeDisk>,
+}
+
+fn setup(config: Config) -> Setup {
+    let (emulator, controller, disk) = emulator::setup(config);
+
+    Setup {
+        emulator,
+        controllers: vec![controller],
+        disks: vec![disk],
+    }
+}
+
+#[test]
+fn repeated_reads_hit_the_cache() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    emulator.fill_namespace(disks[0].nsid, 0xAA);
+    let mut cache = BlockCache::new(64 * 1024);
+
+    let mut buf = [0u8; 4];
+    for _ in 0..10 {
+        cache
+            .read(0, &disks, &mut controllers, 8000, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0xAA; 4]);
+    }
+
+    assert_eq!(io_commands(&emulator, OPCODE_READ), 1);
+}
+
+#[test]
+fn sequential_reads_are_read_ahead() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    let mut cache = BlockCache::new(1024 * 1024);
+
+    let mut buf = [0u8; 512];
+    for block in 0..64 {
+        cache
+            .read(0, &disks, &mut controllers, block * 512, &mut buf)
+            .unwrap();
+    }
+
+    // The first read misses, the second is sequential and fetches the rest.
+    assert_eq!(io_commands(&emulator, OPCODE_READ), 2);
+}
+
+#[test]
+fn writes_are_held_until_flush() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    let mut cache = BlockCache::new(64 * 1024);
+
+    cache
+        .write(0, &disks, &mut controllers, 100, &[1, 2, 3], false)
+        .unwrap();
+    cache
+        .write(0, &disks, &mut controllers, 600, &[4; 1000], false)
+        .unwrap();
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE), 0);
+
+    let mut buf = [0u8; 3];
+    cache
+        .read(0, &disks, &mut controllers, 100, &mut buf)
+        .unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+
+    cache.flush(0, &disks, &mut controllers).unwrap();
+
+    // Blocks 0 to 3 are dirty and go out in one command.
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE), 1);
+    let namespace = emulator.namespace(disks[0].nsid);
+    assert_eq!(&namespace[100..103], &[1, 2, 3]);
+    assert!(namespace[600..1600].iter().all(|&byte| byte == 4));
+}
+
+#[test]
+fn eviction_writes_back_dirty_blocks() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    let mut cache = BlockCache::new(4 * 512);
+
+    for block in 0..8 {
+        cache
+            .write(
+                0,
+                &disks,
+                &mut controllers,
+                block * 512,
+                &[block as u8 + 1; 512],
+                false,
+            )
+            .unwrap();
+    }
+
+    // The four least recently used blocks had to make room.
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE), 4);
+    let namespace = emulator.namespace(disks[0].nsid);
+    assert!(namespace[..512].iter().all(|&byte| byte == 1));
+    assert!(namespace[4 * 512..].iter().all(|&byte| byte == 0));
+
+    cache.set_capacity(&disks, &mut controllers, 0);
+    let namespace = emulator.namespace(disks[0].nsid);
+    assert!(namespace[7 * 512..8 * 512].iter().all(|&byte| byte == 8));
+}
+
+#[test]
+fn large_transfers_bypass_the_cache() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    let mut cache = BlockCache::new(4096);
+
+    cache
+        .write(0, &disks, &mut controllers, 0, &[7; 16], false)
+        .unwrap();
+    cache
+        .write(0, &disks, &mut controllers, 16, &[9; 8192], false)
+        .unwrap();
+
+    // The dirty block went out before the large write read it back.
+    let namespace = emulator.namespace(disks[0].nsid);
+    assert!(namespace[..16].iter().all(|&byte| byte == 7));
+    assert!(namespace[16..8208].iter().all(|&byte| byte == 9));
+
+    let mut buf = [0u8; 32];
+    cache
+        .read(0, &disks, &mut controllers, 0, &mut buf)
+        .unwrap();
+    assert_eq!(&buf[..16], &[7; 16]);
+    assert_eq!(&buf[16..], &[9; 16]);
+}
+
+#[test]
+fn failed_eviction_does_not_fail_the_read() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    emulator.fill_namespace(disks[0].nsid, 0xAA);
+    let mut cache = BlockCache::new(4 * 512);
+
+    for block in 0..4 {
+        cache
+            .write(0, &disks, &mut controllers, block * 512, &[1; 512], false)
+            .unwrap();
+    }
+    emulator.inject(Injection {
+        opcode: OPCODE_WRITE,
+        io: true,
+        sct: 0,
+        sc: 0x06,
+        dnr: true,
+        count: 1,
+    });
+
+    // Making room for the block read evicts a dirty block, whose write back fails.
+    let mut buf = [0u8; 512];
+    let done = cache
+        .read(0, &disks, &mut controllers, 10 * 512, &mut buf)
+        .unwrap();
+    assert_eq!(done, 512);
+    assert_eq!(buf, [0xAA; 512]);
+
+    // The block stayed cached and goes out with the next flush.
+    cache.flush(0, &disks, &mut controllers).unwrap();
+    let namespace = emulator.namespace(disks[0].nsid);
+    assert!(namespace[..4 * 512].iter().all(|&byte| byte == 1));
+}
+
+#[test]
+fn failed_eviction_does_not_fail_the_write() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    let mut cache = BlockCache::new(4 * 512);
+
+    for block in 0..4 {
+        cache
+            .write(0, &disks, &mut controllers, block * 512, &[1; 512], false)
+            .unwrap();
+    }
+    emulator.inject(Injection {
+        opcode: OPCODE_WRITE,
+        io: true,
+        sct: 0,
+        sc: 0x06,
+        dnr: true,
+        count: 1,
+    });
+
+    // Block 0 fails to write back, so block 1 is evicted in its place.
+    let done = cache
+        .write(0, &disks, &mut controllers, 10 * 512, &[2; 512], false)
+        .unwrap();
+    assert_eq!(done, 512);
+    let namespace = emulator.namespace(disks[0].nsid);
+    assert!(namespace[..512].iter().all(|&byte| byte == 0));
+    assert!(namespace[512..2 * 512].iter().all(|&byte| byte == 1));
+
+    // Later writes skip the failed block instead of retrying it.
+    let writes = io_commands(&emulator, OPCODE_WRITE);
+    cache
+        .write(0, &disks, &mut controllers, 11 * 512, &[3; 512], false)
+        .unwrap();
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE), writes + 1);
+
+    // The flush reports the failure, and writes the block once the disk recovers.
+    emulator.inject(Injection {
+        opcode: OPCODE_WRITE,
+        io: true,
+        sct: 0,
+        sc: 0x06,
+        dnr: true,
+        count: 1,
+    });
+    assert!(cache.flush(0, &disks, &mut controllers).is_err());
+    cache.flush(0, &disks, &mut controllers).unwrap();
+    let namespace = emulator.namespace(disks[0].nsid);
+    assert!(namespace[..512].iter().all(|&byte| byte == 1));
+    assert!(namespace[10 * 512..11 * 512].iter().all(|&byte| byte == 2));
+}
+
+#[test]
+fn shutdown_stops_cached_io() {
+    let Setup {
+        emulator,
+        mut controllers,
+        disks,
+    } = setup(Config::default());
+    let mut cache = BlockCache::new(64 * 1024);
+
+    let mut buf = [0u8; 512];
+    cache
+        .read(0, &disks, &mut controllers, 0, &mut buf)
+        .unwrap();
+    cache.flush(0, &disks, &mut controllers).unwrap();
+    controllers[0].shutdown().unwrap();
+
+    // Block 0 is cached, but neither reads nor writes are served any more.
+    let error = cache
+        .read(0, &disks, &mut controllers, 0, &mut buf)
+        .unwrap_err();
+    assert_eq!((error.done, error.error), (0, NvmeError::ShutDown));
+
+    let error = cache
+        .write(0, &disks, &mut controllers, 0, &[1; 16], false)
+        .unwrap_err();
+    assert_eq!((error.done, error.error), (0, NvmeError::ShutDown));
+
+    assert!(
+        emulator
+            .namespace(disks[0].nsid)
+            .iter()
+            .all(|&byte| byte == 0)
+    );
+}
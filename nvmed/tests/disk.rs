@@ -0,0 +1,546 @@
+mod emulator;
+
+use emulator::{
+    Config, Emulator, Injection, OACS_FORMAT_NVM, OACS_NAMESPACE_MANAGEMENT, ONCS_WRITE_ZEROES,
+    OPCODE_READ, OPCODE_WRITE, OPCODE_WRITE_ZEROES, io_commands, power_state, setup,
+};
+use nvmed::{
+    admin::SmartLog,
+    controller::{APST_DEFAULT_MAX_LATENCY, NvmeController, Registers, SecureErase},
+    disk::{IoError, scan_namespaces},
+    error::{NvmeError, NvmeStatus},
+    info,
+};
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+#[test]
+fn identify_reports_geometry() {
+    let (_, controller, disk) = setup(Config {
+        block_size: 4096,
+        blocks: 300,
+        ..Config::default()
+    });
+
+    assert_eq!(disk.block_size, 4096);
+    assert_eq!(disk.blocks, 300);
+    assert_eq!(disk.len(), 300 * 4096);
+    assert_eq!(controller.identify().model_number(), "nvmed emulator");
+}
+
+#[test]
+fn unaligned_write_preserves_neighbours() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    emulator.fill_namespace(disk.nsid, 0xAA);
+
+    let data = pattern(3000);
+    assert_eq!(
+        disk.write(&mut controller, 700, &data, false).unwrap(),
+        3000
+    );
+
+    let namespace = emulator.namespace(disk.nsid);
+    assert!(namespace[..700].iter().all(|&byte| byte == 0xAA));
+    assert_eq!(&namespace[700..3700], &data[..]);
+    assert!(namespace[3700..4096].iter().all(|&byte| byte == 0xAA));
+
+    let mut buf = vec![0u8; 3000];
+    assert_eq!(disk.read(&mut controller, 700, &mut buf).unwrap(), 3000);
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn partial_block_write_on_large_blocks() {
+    let (emulator, mut controller, disk) = setup(Config {
+        block_size: 4096,
+        blocks: 64,
+        ..Config::default()
+    });
+    emulator.fill_namespace(disk.nsid, 0x55);
+
+    disk.write(&mut controller, 5000, &[1, 2, 3], false)
+        .unwrap();
+
+    let namespace = emulator.namespace(disk.nsid);
+    assert_eq!(&namespace[5000..5003], &[1, 2, 3]);
+    assert!(namespace[4096..5000].iter().all(|&byte| byte == 0x55));
+    assert!(namespace[5003..8192].iter().all(|&byte| byte == 0x55));
+}
+
+#[test]
+fn transfers_are_split_by_mdts() {
+    // 8 KiB per command.
+    let (emulator, mut controller, disk) = setup(Config {
+        mdts: 1,
+        ..Config::default()
+    });
+    assert_eq!(controller.max_transfer(), 8192);
+
+    let data = pattern(40 * 1024 + 300);
+    disk.write(&mut controller, 100, &data, false).unwrap();
+
+    let mut buf = vec![0u8; data.len()];
+    disk.read(&mut controller, 100, &mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    for cmd in emulator.io_commands() {
+        let blocks = (cmd.cdw12 & 0xFFFF) as usize + 1;
+        assert!(blocks * disk.block_size <= 8192);
+    }
+}
+
+#[test]
+fn blocks_larger_than_a_transfer_are_rejected() {
+    let emulator = Emulator::new(Config {
+        block_size: 16384,
+        blocks: 16,
+        mdts: 1,
+        ..Config::default()
+    });
+    let mut controller = NvmeController::init(Registers::new(emulator)).unwrap();
+
+    assert_eq!(controller.max_transfer(), 8192);
+    assert!(controller.identify_namespace(1).unwrap().is_none());
+}
+
+#[test]
+fn large_minimum_page_size_is_rejected() {
+    let emulator = Emulator::new(Config {
+        mpsmin: 1,
+        ..Config::default()
+    });
+
+    assert!(matches!(
+        NvmeController::init(Registers::new(emulator)),
+        Err(NvmeError::Unsupported)
+    ));
+}
+
+#[test]
+fn large_transfer_uses_prp_list() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+
+    let data = pattern(256 * 1024);
+    disk.write(&mut controller, 0, &data, false).unwrap();
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE), 1);
+    assert_eq!(&emulator.namespace(disk.nsid)[..data.len()], &data[..]);
+
+    let mut buf = vec![0u8; data.len()];
+    disk.read(&mut controller, 0, &mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn out_of_range_io_is_rejected() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+
+    let mut buf = vec![0u8; 1024];
+    let IoError { done, error } = disk
+        .read(&mut controller, disk.len() - 512, &mut buf)
+        .unwrap_err();
+    assert_eq!(done, 0);
+    assert_eq!(error, NvmeError::OutOfRange);
+
+    let error = disk
+        .write(&mut controller, usize::MAX, &[0; 2], false)
+        .unwrap_err();
+    assert_eq!(error.error, NvmeError::OutOfRange);
+
+    assert_eq!(
+        disk.write_zeroes(&mut controller, disk.len(), 1),
+        Err(NvmeError::OutOfRange)
+    );
+    assert_eq!(
+        disk.discard(&mut controller, 0, disk.len() + 512),
+        Err(NvmeError::OutOfRange)
+    );
+    assert!(emulator.io_commands().is_empty());
+
+    disk.read(&mut controller, disk.len() - 1024, &mut buf)
+        .unwrap();
+}
+
+#[test]
+fn shrunk_namespace_reports_partial_transfer() {
+    let (_, mut controller, mut disk) = setup(Config {
+        mdts: 1,
+        ..Config::default()
+    });
+    // Geometry from before the namespace lost 8 KiB.
+    disk.blocks += 16;
+
+    // The first 8 KiB command fits, the second one runs past the last block.
+    let mut buf = vec![0u8; 16384];
+    let IoError { done, error } = disk
+        .read(&mut controller, disk.len() - 20480, &mut buf)
+        .unwrap_err();
+
+    assert_eq!(done, 8192);
+    assert_eq!(error.ret_val(), -34);
+}
+
+#[test]
+fn transient_errors_are_retried() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    emulator.inject(Injection {
+        opcode: OPCODE_READ,
+        io: true,
+        sct: 0,
+        sc: 0x06,
+        dnr: false,
+        count: 2,
+    });
+
+    let mut buf = [0u8; 512];
+    disk.read(&mut controller, 0, &mut buf).unwrap();
+
+    assert_eq!(io_commands(&emulator, OPCODE_READ), 3);
+    assert_eq!(controller.error_log().count(), 2);
+}
+
+#[test]
+fn do_not_retry_errors_are_returned() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    emulator.inject(Injection {
+        opcode: OPCODE_WRITE,
+        io: true,
+        sct: 2,
+        sc: 0x80,
+        dnr: true,
+        count: 1,
+    });
+
+    let error = disk
+        .write(&mut controller, 0, &[0xFF; 512], false)
+        .unwrap_err();
+
+    assert_eq!(
+        error.error,
+        NvmeError::Status(NvmeStatus {
+            sct: 2,
+            sc: 0x80,
+            dnr: true
+        })
+    );
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE), 1);
+
+    let record = controller.error_log().next().unwrap();
+    assert_eq!(record.opcode, OPCODE_WRITE);
+    assert_eq!(record.lba, Some(0));
+}
+
+#[test]
+fn fua_sets_force_unit_access() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+
+    disk.write(&mut controller, 0, &[1; 512], true).unwrap();
+    disk.write(&mut controller, 512, &[1; 512], false).unwrap();
+
+    let writes = emulator.io_commands();
+    assert_ne!(writes[0].cdw12 & (1 << 30), 0);
+    assert_eq!(writes[1].cdw12 & (1 << 30), 0);
+}
+
+#[test]
+fn discard_leaves_partial_blocks() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    emulator.fill_namespace(disk.nsid, 0xAA);
+
+    disk.discard(&mut controller, 100, 2000).unwrap();
+
+    let namespace = emulator.namespace(disk.nsid);
+    assert!(namespace[..512].iter().all(|&byte| byte == 0xAA));
+    assert!(namespace[512..2048].iter().all(|&byte| byte == 0));
+    assert!(namespace[2048..].iter().all(|&byte| byte == 0xAA));
+}
+
+#[test]
+fn write_zeroes_covers_partial_blocks() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    emulator.fill_namespace(disk.nsid, 0xAA);
+
+    disk.write_zeroes(&mut controller, 100, 2000).unwrap();
+
+    let namespace = emulator.namespace(disk.nsid);
+    assert!(namespace[..100].iter().all(|&byte| byte == 0xAA));
+    assert!(namespace[100..2100].iter().all(|&byte| byte == 0));
+    assert!(namespace[2100..].iter().all(|&byte| byte == 0xAA));
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE_ZEROES), 1);
+}
+
+#[test]
+fn write_zeroes_falls_back_to_writes() {
+    let (emulator, mut controller, disk) = setup(Config {
+        oncs: 0,
+        ..Config::default()
+    });
+    emulator.fill_namespace(disk.nsid, 0xAA);
+
+    disk.write_zeroes(&mut controller, 0, 4096).unwrap();
+
+    let namespace = emulator.namespace(disk.nsid);
+    assert!(namespace[..4096].iter().all(|&byte| byte == 0));
+    assert!(namespace[4096..].iter().all(|&byte| byte == 0xAA));
+    assert_eq!(io_commands(&emulator, OPCODE_WRITE_ZEROES), 0);
+
+    assert_eq!(
+        disk.discard(&mut controller, 0, 4096),
+        Err(NvmeError::Unsupported)
+    );
+}
+
+#[test]
+fn flush_skipped_without_volatile_cache() {
+    let (emulator, mut controller, disk) = setup(Config {
+        volatile_write_cache: false,
+        oncs: ONCS_WRITE_ZEROES,
+        ..Config::default()
+    });
+
+    disk.flush(&mut controller).unwrap();
+    assert!(emulator.io_commands().is_empty());
+}
+
+#[test]
+fn smart_log_is_decoded() {
+    let (_, mut controller, _) = setup(Config::default());
+
+    let log = controller.smart_log().unwrap();
+    assert_eq!(log.temperature, 313);
+    assert_eq!(log.available_spare, 100);
+    assert_eq!(log.percentage_used, 3);
+    assert_eq!(log.power_on_hours, 1000);
+}
+
+#[test]
+fn smart_byte_counts_saturate() {
+    let text = info::smart(&SmartLog::new(&[0xFF; SmartLog::LEN]));
+
+    assert!(text.contains(&format!("bytes_read: {}\n", u128::MAX)));
+    assert!(text.contains(&format!("bytes_written: {}\n", u128::MAX)));
+}
+
+#[test]
+fn error_information_log_is_decoded() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    for opcode in [OPCODE_READ, OPCODE_WRITE] {
+        emulator.inject(Injection {
+            opcode,
+            io: true,
+            sct: 0,
+            sc: 0x04,
+            dnr: true,
+            count: 1,
+        });
+    }
+
+    let mut buf = [0u8; 512];
+    disk.read(&mut controller, 4096, &mut buf).unwrap_err();
+    disk.write(&mut controller, 0, &buf, false).unwrap_err();
+
+    let entries = controller.error_information_log().unwrap();
+    assert_eq!(entries.len(), 2);
+
+    // Newest first.
+    assert_eq!(entries[0].error_count, 2);
+    assert_eq!(entries[0].lba, 0);
+    assert_eq!(entries[1].error_count, 1);
+    assert_eq!(entries[1].sqid, 1);
+    assert_eq!(entries[1].nsid, disk.nsid);
+    assert_eq!(entries[1].lba, 8);
+    assert_eq!(
+        entries[1].status,
+        Some(NvmeStatus {
+            sct: 0,
+            sc: 0x04,
+            dnr: true
+        })
+    );
+
+    let text = info::error_log(&entries);
+    assert!(text.starts_with("count=2 sqid=1 "));
+    assert_eq!(text.lines().count(), 2);
+}
+
+#[test]
+fn format_changes_block_size() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    emulator.fill_namespace(disk.nsid, 0xAA);
+
+    controller
+        .format(disk.nsid, 1, SecureErase::UserData)
+        .unwrap();
+
+    let namespace = controller.identify_namespace(disk.nsid).unwrap().unwrap();
+    assert_eq!(namespace.block_size, 4096);
+    assert_eq!(namespace.blocks, 256);
+    assert!(emulator.namespace(disk.nsid).iter().all(|&byte| byte == 0));
+
+    assert!(matches!(
+        controller.format(disk.nsid, 5, SecureErase::None),
+        Err(NvmeError::Status(NvmeStatus {
+            sct: 1,
+            sc: 0x0A,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn namespace_management_needs_oacs() {
+    let (_, mut controller, disk) = setup(Config::default());
+
+    assert_eq!(
+        controller.create_namespace(1024, 0),
+        Err(NvmeError::Unsupported)
+    );
+    assert_eq!(
+        controller.attach_namespace(disk.nsid, false),
+        Err(NvmeError::Unsupported)
+    );
+}
+
+#[test]
+fn namespace_management_keeps_disk_indices() {
+    let (emulator, mut controller, disk) = setup(Config {
+        oacs: OACS_FORMAT_NVM | OACS_NAMESPACE_MANAGEMENT,
+        ..Config::default()
+    });
+    let first = disk.nsid;
+    let mut disks = vec![disk];
+
+    // A new namespace only becomes a disk once it is attached.
+    let nsid = controller.create_namespace(64, 1).unwrap();
+    scan_namespaces(&mut controller, 0, &mut disks).unwrap();
+    assert_eq!(disks.len(), 1);
+
+    controller.attach_namespace(nsid, true).unwrap();
+    scan_namespaces(&mut controller, 0, &mut disks).unwrap();
+    assert_eq!(disks.len(), 2);
+    assert_eq!(disks[1].nsid, nsid);
+    assert_eq!(disks[1].block_size, 4096);
+    assert_eq!(disks[1].blocks, 64);
+
+    let data = pattern(8192);
+    disks[1].write(&mut controller, 0, &data, false).unwrap();
+    assert_eq!(&emulator.namespace(nsid)[..8192], &data[..]);
+
+    // Detaching the first namespace empties its disk without moving the second one.
+    controller.attach_namespace(first, false).unwrap();
+    scan_namespaces(&mut controller, 0, &mut disks).unwrap();
+    assert_eq!(disks.len(), 2);
+    assert!(disks[0].is_empty());
+    assert_eq!(disks[1].nsid, nsid);
+    assert_eq!(
+        disks[0]
+            .read(&mut controller, 0, &mut [0; 512])
+            .unwrap_err()
+            .error,
+        NvmeError::OutOfRange
+    );
+
+    controller.attach_namespace(first, true).unwrap();
+    scan_namespaces(&mut controller, 0, &mut disks).unwrap();
+    assert_eq!(disks[0].blocks, 2048);
+
+    controller.format(nsid, 0, SecureErase::None).unwrap();
+    scan_namespaces(&mut controller, 0, &mut disks).unwrap();
+    assert_eq!(disks[1].block_size, 512);
+    assert_eq!(disks[1].blocks, 512);
+    assert!(emulator.namespace(nsid).iter().all(|&byte| byte == 0));
+
+    controller.attach_namespace(nsid, false).unwrap();
+    controller.delete_namespace(nsid).unwrap();
+    scan_namespaces(&mut controller, 0, &mut disks).unwrap();
+    assert_eq!(disks.len(), 2);
+    assert!(disks[1].is_empty());
+    assert!(matches!(
+        controller.delete_namespace(nsid),
+        Err(NvmeError::Status(NvmeStatus { sc: 0x0B, .. }))
+    ));
+}
+
+#[test]
+fn power_states_are_reported_and_set() {
+    let (emulator, mut controller, _) = setup(Config::default());
+
+    let power_states = controller.identify().power_states().collect::<Vec<_>>();
+    assert_eq!(power_states, Config::default().power_states);
+
+    controller.set_power_state(1).unwrap();
+    assert_eq!(emulator.power_state(), 1);
+    assert_eq!(controller.power_state().unwrap(), 1);
+
+    assert_eq!(
+        controller.set_power_state(4),
+        Err(NvmeError::InvalidArgument)
+    );
+}
+
+#[test]
+fn apst_skips_slow_states() {
+    let (emulator, mut controller, _) = setup(Config::default());
+
+    controller.configure_apst(APST_DEFAULT_MAX_LATENCY).unwrap();
+
+    // Power states 0 and 1 go to power state 2 after 50 times its 2.5 ms latency. Power
+    // state 3 takes 200 ms to leave and is not used.
+    let (enabled, table) = emulator.apst();
+    let entry = (2 << 3) | (125 << 8);
+    assert!(enabled);
+    assert_eq!(&table[..4], &[entry, entry, 0, 0]);
+
+    controller.configure_apst(0).unwrap();
+    assert_eq!(emulator.apst(), (false, [0; 32]));
+}
+
+#[test]
+fn apst_needs_support() {
+    let (_, mut controller, _) = setup(Config {
+        power_states: vec![power_state(50000, false, 0, 0)],
+        apst: false,
+        ..Config::default()
+    });
+
+    assert_eq!(
+        controller.configure_apst(APST_DEFAULT_MAX_LATENCY),
+        Err(NvmeError::Unsupported)
+    );
+}
+
+#[test]
+fn interrupt_coalescing_is_configured() {
+    let (emulator, _, _) = setup(Config::default());
+
+    // 100 microseconds, 4 completions.
+    assert_eq!(emulator.interrupt_coalescing(), (1 << 8) | 3);
+}
+
+#[test]
+fn shutdown_stops_io() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+
+    controller.shutdown().unwrap();
+    assert!(emulator.shut_down());
+
+    let mut buf = [0u8; 512];
+    let error = disk.read(&mut controller, 0, &mut buf).unwrap_err();
+    assert_eq!(error.error, NvmeError::ShutDown);
+}
+
+#[test]
+fn resume_after_shutdown_restores_io() {
+    let (emulator, mut controller, disk) = setup(Config::default());
+    emulator.fill_namespace(disk.nsid, 0xAA);
+
+    controller.shutdown().unwrap();
+    controller.resume().unwrap();
+    assert!(!emulator.shut_down());
+    assert!(!controller.is_shut_down());
+
+    let mut buf = [0u8; 512];
+    assert_eq!(disk.read(&mut controller, 0, &mut buf).unwrap(), 512);
+    assert_eq!(buf, [0xAA; 512]);
+}
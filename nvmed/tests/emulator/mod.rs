@@ -0,0 +1,881 @@
+//! A software NVMe controller for host tests.
+//!
+//! It implements the register block nvmed drives through [`RegisterIo`]. Writing a submission
+//! queue tail doorbell runs the new commands synchronously against in-memory namespaces and
+//! posts their completions. Queues, PRPs and data buffers are the ones nvmed allocated, which
+//! host builds place at identical "physical" and virtual addresses.
+
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    rc::Rc,
+};
+
+use nvme::cmd::NvmeCommand;
+use nvmed::{
+    admin::PowerState,
+    controller::{NvmeController, RegisterIo, Registers},
+    disk::NvmeDisk,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+/// Entries kept in the Error Information log.
+const ERROR_LOG_ENTRIES: usize = 4;
+
+pub const OPCODE_WRITE: u8 = 0x01;
+pub const OPCODE_READ: u8 = 0x02;
+pub const OPCODE_WRITE_ZEROES: u8 = 0x08;
+
+const SCT_GENERIC: u8 = 0x0;
+pub const SC_INVALID_OPCODE: u8 = 0x01;
+pub const SC_INVALID_FIELD: u8 = 0x02;
+pub const SC_INVALID_NAMESPACE: u8 = 0x0B;
+pub const SC_LBA_OUT_OF_RANGE: u8 = 0x80;
+
+const SCT_COMMAND_SPECIFIC: u8 = 0x1;
+pub const SC_INVALID_FORMAT: u8 = 0x0A;
+pub const SC_NAMESPACE_ALREADY_ATTACHED: u8 = 0x18;
+pub const SC_NAMESPACE_NOT_ATTACHED: u8 = 0x1A;
+pub const SC_CONTROLLER_LIST_INVALID: u8 = 0x1C;
+
+pub const OACS_FORMAT_NVM: u16 = 1 << 1;
+pub const OACS_NAMESPACE_MANAGEMENT: u16 = 1 << 3;
+
+pub const ONCS_DATASET_MANAGEMENT: u16 = 1 << 2;
+pub const ONCS_WRITE_ZEROES: u16 = 1 << 3;
+
+/// What the emulated controller looks like.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Logical block size of LBA format 0, which namespaces start out with. LBA format 1 is
+    /// always 4 KiB.
+    pub block_size: usize,
+    /// Blocks per namespace in LBA format 0.
+    pub blocks: u64,
+    pub namespaces: u32,
+    /// Maximum Data Transfer Size as a power of two in 4 KiB pages, 0 for no limit.
+    pub mdts: u8,
+    /// CAP.MPSMIN: the smallest memory page size, as a power of two in 4 KiB pages.
+    pub mpsmin: u8,
+    pub oacs: u16,
+    pub oncs: u16,
+    pub volatile_write_cache: bool,
+    pub power_states: Vec<PowerState>,
+    pub apst: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            block_size: 512,
+            blocks: 2048,
+            namespaces: 1,
+            mdts: 0,
+            mpsmin: 0,
+            oacs: OACS_FORMAT_NVM,
+            oncs: ONCS_DATASET_MANAGEMENT | ONCS_WRITE_ZEROES,
+            volatile_write_cache: true,
+            power_states: vec![
+                power_state(90000, false, 0, 0),
+                power_state(40000, false, 0, 0),
+                power_state(500, true, 1000, 1500),
+                power_state(50, true, 5000, 200_000),
+            ],
+            apst: true,
+        }
+    }
+}
+
+pub fn power_state(
+    max_power: u32,
+    non_operational: bool,
+    entry_latency: u32,
+    exit_latency: u32,
+) -> PowerState {
+    PowerState {
+        max_power,
+        non_operational,
+        entry_latency,
+        exit_latency,
+    }
+}
+
+/// Brings up a controller on a new emulator and opens its first namespace as disk 0.
+pub fn setup(config: Config) -> (Emulator, NvmeController, NvmeDisk) {
+    let emulator = Emulator::new(config);
+    let mut controller = NvmeController::init(Registers::new(emulator.clone())).unwrap();
+
+    let nsid = controller.namespace_list().unwrap()[0];
+    let namespace = controller.identify_namespace(nsid).unwrap().unwrap();
+    let disk = NvmeDisk {
+        controller: 0,
+        nsid,
+        block_size: namespace.block_size,
+        blocks: namespace.blocks,
+    };
+
+    (emulator, controller, disk)
+}
+
+/// How many commands with `opcode` the emulator has fetched from I/O queues.
+pub fn io_commands(emulator: &Emulator, opcode: u8) -> usize {
+    emulator
+        .io_commands()
+        .iter()
+        .filter(|cmd| cmd.opcode == opcode)
+        .count()
+}
+
+/// A status to complete matching commands with instead of running them.
+#[derive(Clone, Copy, Debug)]
+pub struct Injection {
+    pub opcode: u8,
+    /// Only commands on I/O queues match when set, only admin commands when clear.
+    pub io: bool,
+    pub sct: u8,
+    pub sc: u8,
+    pub dnr: bool,
+    /// How many matching commands fail before the injection is used up.
+    pub count: usize,
+}
+
+/// A command the controller has fetched, with the queue it came from.
+#[derive(Clone, Copy, Debug)]
+pub struct Fetched {
+    pub sqid: u16,
+    pub cmd: NvmeCommand,
+}
+
+struct SubQueue {
+    addr: usize,
+    len: u16,
+    head: u16,
+    cqid: u16,
+}
+
+struct CompQueue {
+    addr: usize,
+    len: u16,
+    tail: u16,
+    phase: bool,
+}
+
+struct Status {
+    sct: u8,
+    sc: u8,
+    dnr: bool,
+}
+
+impl Status {
+    fn generic(sc: u8) -> Self {
+        Self {
+            sct: SCT_GENERIC,
+            sc,
+            dnr: true,
+        }
+    }
+
+    fn specific(sc: u8) -> Self {
+        Self {
+            sct: SCT_COMMAND_SPECIFIC,
+            sc,
+            dnr: true,
+        }
+    }
+
+    /// The status field of a completion, without the phase tag.
+    fn field(&self) -> u16 {
+        (u16::from(self.dnr) << 14) | (u16::from(self.sct) << 8) | u16::from(self.sc)
+    }
+}
+
+struct Namespace {
+    data: Vec<u8>,
+    /// LBA format index.
+    format: u8,
+    /// Only namespaces attached to the controller are active, and take I/O.
+    attached: bool,
+}
+
+struct State {
+    config: Config,
+    cc: u32,
+    csts: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    sub_queues: BTreeMap<u16, SubQueue>,
+    comp_queues: BTreeMap<u16, CompQueue>,
+    /// Every allocated namespace, attached or not, by namespace ID.
+    namespaces: BTreeMap<u32, Namespace>,
+    injections: VecDeque<Injection>,
+    fetched: Vec<Fetched>,
+    resets: usize,
+    /// Dword 0 of the completion for the command being run.
+    result: u32,
+    power_state: u8,
+    /// Whether APST is enabled, and the table it was last given.
+    apst: (bool, [u64; 32]),
+    /// The Interrupt Coalescing feature: aggregation time and threshold.
+    interrupt_coalescing: u32,
+    /// Error Information log entries, newest first.
+    errors: VecDeque<[u8; 64]>,
+    error_count: u64,
+}
+
+/// A handle to the emulated controller. Clones share the same controller, so a test can keep
+/// one while nvmed owns another.
+#[derive(Clone)]
+pub struct Emulator(Rc<RefCell<State>>);
+
+impl Emulator {
+    pub fn new(config: Config) -> Self {
+        let namespaces = (1..=config.namespaces)
+            .map(|nsid| {
+                let namespace = Namespace {
+                    data: vec![0u8; config.blocks as usize * config.block_size],
+                    format: 0,
+                    attached: true,
+                };
+                (nsid, namespace)
+            })
+            .collect();
+
+        Self(Rc::new(RefCell::new(State {
+            config,
+            cc: 0,
+            csts: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sub_queues: BTreeMap::new(),
+            comp_queues: BTreeMap::new(),
+            namespaces,
+            injections: VecDeque::new(),
+            fetched: Vec::new(),
+            resets: 0,
+            result: 0,
+            power_state: 0,
+            apst: (false, [0; 32]),
+            interrupt_coalescing: 0,
+            errors: VecDeque::new(),
+            error_count: 0,
+        })))
+    }
+
+    /// Whether a normal shutdown has completed, with the I/O queues deleted beforehand.
+    pub fn shut_down(&self) -> bool {
+        let state = self.0.borrow();
+        state.csts & (0x3 << 2) == 0x2 << 2 && state.sub_queues.len() == 1
+    }
+
+    pub fn power_state(&self) -> u8 {
+        self.0.borrow().power_state
+    }
+
+    pub fn apst(&self) -> (bool, [u64; 32]) {
+        self.0.borrow().apst
+    }
+
+    pub fn interrupt_coalescing(&self) -> u32 {
+        self.0.borrow().interrupt_coalescing
+    }
+
+    pub fn inject(&self, injection: Injection) {
+        self.0.borrow_mut().injections.push_back(injection);
+    }
+
+    /// Every command fetched from an I/O queue so far.
+    pub fn io_commands(&self) -> Vec<NvmeCommand> {
+        self.0
+            .borrow()
+            .fetched
+            .iter()
+            .filter(|fetched| fetched.sqid != 0)
+            .map(|fetched| fetched.cmd)
+            .collect()
+    }
+
+    /// How many times the controller went from disabled to enabled.
+    pub fn enables(&self) -> usize {
+        self.0.borrow().resets
+    }
+
+    /// The contents of namespace `nsid`.
+    pub fn namespace(&self, nsid: u32) -> Vec<u8> {
+        self.0.borrow().namespaces[&nsid].data.clone()
+    }
+
+    pub fn fill_namespace(&self, nsid: u32, byte: u8) {
+        self.0
+            .borrow_mut()
+            .namespaces
+            .get_mut(&nsid)
+            .unwrap()
+            .data
+            .fill(byte);
+    }
+}
+
+impl RegisterIo for Emulator {
+    fn read32(&self, reg: usize) -> u32 {
+        let state = self.0.borrow();
+        match reg {
+            // MQES 63, contiguous queues required, 500 ms ready timeout.
+            REG_CAP => 63 | (1 << 16) | (1 << 24),
+            // NVM command set; DSTRD is 0.
+            0x04 => (1 << 5) | (u32::from(state.config.mpsmin) << 16),
+            REG_VS => 0x0001_0400,
+            REG_CC => state.cc,
+            REG_CSTS => state.csts,
+            REG_AQA => state.aqa,
+            REG_ASQ => state.asq as u32,
+            0x2C => (state.asq >> 32) as u32,
+            REG_ACQ => state.acq as u32,
+            0x34 => (state.acq >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write32(&self, reg: usize, value: u32) {
+        let mut state = self.0.borrow_mut();
+        match reg {
+            REG_CC => state.write_cc(value),
+            REG_AQA => state.aqa = value,
+            REG_ASQ => state.asq = (state.asq & !0xFFFF_FFFF) | u64::from(value),
+            0x2C => state.asq = (state.asq & 0xFFFF_FFFF) | (u64::from(value) << 32),
+            REG_ACQ => state.acq = (state.acq & !0xFFFF_FFFF) | u64::from(value),
+            0x34 => state.acq = (state.acq & 0xFFFF_FFFF) | (u64::from(value) << 32),
+            reg if reg >= DOORBELLS => {
+                let index = (reg - DOORBELLS) / 4;
+                // Completion queue head doorbells need no action, as entries are only ever
+                // posted in response to a submission.
+                if index.is_multiple_of(2) {
+                    state.run_queue((index / 2) as u16, value as u16);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+unsafe fn memory<'a>(addr: usize, len: usize) -> &'a mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, len) }
+}
+
+impl State {
+    fn write_cc(&mut self, value: u32) {
+        let was_enabled = self.cc & 1 != 0;
+        self.cc = value;
+
+        match (was_enabled, value & 1 != 0) {
+            (false, true) => {
+                let sq_len = (self.aqa & 0xFFF) as u16 + 1;
+                let cq_len = ((self.aqa >> 16) & 0xFFF) as u16 + 1;
+                self.sub_queues.insert(
+                    0,
+                    SubQueue {
+                        addr: self.asq as usize,
+                        len: sq_len,
+                        head: 0,
+                        cqid: 0,
+                    },
+                );
+                self.comp_queues.insert(
+                    0,
+                    CompQueue {
+                        addr: self.acq as usize,
+                        len: cq_len,
+                        tail: 0,
+                        phase: true,
+                    },
+                );
+                self.csts = 1;
+                self.resets += 1;
+            }
+            (true, false) => {
+                self.sub_queues.clear();
+                self.comp_queues.clear();
+                self.csts = 0;
+            }
+            (true, true) if value & (0x3 << 14) != 0 => {
+                // Shutdown is instant: report it complete.
+                self.csts = (self.csts & !(0x3 << 2)) | (0x2 << 2);
+            }
+            _ => {}
+        }
+    }
+
+    fn run_queue(&mut self, sqid: u16, tail: u16) {
+        loop {
+            let Some(sub_queue) = self.sub_queues.get_mut(&sqid) else {
+                return;
+            };
+            if sub_queue.head == tail {
+                return;
+            }
+
+            let cmd = unsafe {
+                core::ptr::read_unaligned(
+                    (sub_queue.addr + usize::from(sub_queue.head) * 64) as *const NvmeCommand,
+                )
+            };
+            sub_queue.head = (sub_queue.head + 1) % sub_queue.len;
+            let (sq_head, cqid) = (sub_queue.head, sub_queue.cqid);
+
+            self.fetched.push(Fetched { sqid, cmd });
+            self.result = 0;
+
+            let status = match self.take_injection(sqid != 0, cmd.opcode) {
+                Some(status) => Some(status),
+                None if sqid == 0 => self.admin_command(&cmd),
+                None => self.io_command(&cmd),
+            };
+
+            if let Some(status) = &status {
+                self.log_error(sqid, &cmd, status);
+            }
+            self.complete(cqid, sqid, sq_head, cmd.c_id, status);
+        }
+    }
+
+    fn log_error(&mut self, sqid: u16, cmd: &NvmeCommand, status: &Status) {
+        self.error_count += 1;
+
+        let mut entry = [0u8; 64];
+        entry[0..8].copy_from_slice(&self.error_count.to_le_bytes());
+        entry[8..10].copy_from_slice(&sqid.to_le_bytes());
+        entry[10..12].copy_from_slice(&cmd.c_id.to_le_bytes());
+        entry[12..14].copy_from_slice(&(status.field() << 1).to_le_bytes());
+        entry[14..16].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        if sqid != 0 {
+            entry[16..20].copy_from_slice(&cmd.cdw10.to_le_bytes());
+            entry[20..24].copy_from_slice(&cmd.cdw11.to_le_bytes());
+        }
+        entry[24..28].copy_from_slice(&cmd.ns_id.to_le_bytes());
+
+        self.errors.push_front(entry);
+        self.errors.truncate(ERROR_LOG_ENTRIES);
+    }
+
+    fn take_injection(&mut self, io: bool, opcode: u8) -> Option<Status> {
+        let index = self
+            .injections
+            .iter()
+            .position(|injection| injection.io == io && injection.opcode == opcode)?;
+        let injection = &mut self.injections[index];
+        let status = Status {
+            sct: injection.sct,
+            sc: injection.sc,
+            dnr: injection.dnr,
+        };
+
+        injection.count -= 1;
+        if injection.count == 0 {
+            self.injections.remove(index);
+        }
+
+        Some(status)
+    }
+
+    fn complete(&mut self, cqid: u16, sqid: u16, sq_head: u16, c_id: u16, status: Option<Status>) {
+        let comp_queue = self
+            .comp_queues
+            .get_mut(&cqid)
+            .expect("no completion queue");
+
+        let status = status.map_or(0, |status| status.field());
+
+        let mut entry = [0u8; 16];
+        entry[0..4].copy_from_slice(&self.result.to_le_bytes());
+        entry[8..10].copy_from_slice(&sq_head.to_le_bytes());
+        entry[10..12].copy_from_slice(&sqid.to_le_bytes());
+        entry[12..14].copy_from_slice(&c_id.to_le_bytes());
+        entry[14..16].copy_from_slice(&((status << 1) | u16::from(comp_queue.phase)).to_le_bytes());
+
+        unsafe { memory(comp_queue.addr + usize::from(comp_queue.tail) * 16, 16) }
+            .copy_from_slice(&entry);
+
+        comp_queue.tail = (comp_queue.tail + 1) % comp_queue.len;
+        if comp_queue.tail == 0 {
+            comp_queue.phase = !comp_queue.phase;
+        }
+    }
+
+    fn admin_command(&mut self, cmd: &NvmeCommand) -> Option<Status> {
+        let (cdw10, cdw11, prp1) = (cmd.cdw10, cmd.cdw11, cmd.d_ptr[0] as usize);
+
+        match cmd.opcode {
+            // Create I/O Submission Queue
+            0x01 => {
+                let qid = cdw10 as u16;
+                self.sub_queues.insert(
+                    qid,
+                    SubQueue {
+                        addr: prp1,
+                        len: (cdw10 >> 16) as u16 + 1,
+                        head: 0,
+                        cqid: (cdw11 >> 16) as u16,
+                    },
+                );
+            }
+            // Delete I/O Submission Queue
+            0x00 => {
+                if self.sub_queues.remove(&(cdw10 as u16)).is_none() {
+                    return Some(Status::generic(SC_INVALID_FIELD));
+                }
+            }
+            // Delete I/O Completion Queue
+            0x04 => {
+                if self.comp_queues.remove(&(cdw10 as u16)).is_none() {
+                    return Some(Status::generic(SC_INVALID_FIELD));
+                }
+            }
+            // Create I/O Completion Queue
+            0x05 => {
+                let qid = cdw10 as u16;
+                self.comp_queues.insert(
+                    qid,
+                    CompQueue {
+                        addr: prp1,
+                        len: (cdw10 >> 16) as u16 + 1,
+                        tail: 0,
+                        phase: true,
+                    },
+                );
+            }
+            // Get Log Page
+            0x02 => {
+                let len = ((((cdw10 >> 16) | (cdw11 << 16)) as usize) + 1) * 4;
+                let page = unsafe { memory(prp1, len) };
+                page.fill(0);
+
+                match cdw10 as u8 {
+                    0x01 => {
+                        for (entry, chunk) in self.errors.iter().zip(page.chunks_exact_mut(64)) {
+                            chunk.copy_from_slice(entry);
+                        }
+                    }
+                    0x02 => {
+                        // 40 degrees Celsius, full spare, 3% used, 1000 hours powered on.
+                        page[1..3].copy_from_slice(&313u16.to_le_bytes());
+                        page[3] = 100;
+                        page[4] = 10;
+                        page[5] = 3;
+                        page[128..144].copy_from_slice(&1000u128.to_le_bytes());
+                    }
+                    _ => return Some(Status::generic(SC_INVALID_FIELD)),
+                }
+            }
+            // Set Features
+            0x09 => match cdw10 as u8 {
+                0x02 => {
+                    let state = (cdw11 & 0x1F) as u8;
+                    if usize::from(state) >= self.config.power_states.len() {
+                        return Some(Status::generic(SC_INVALID_FIELD));
+                    }
+                    self.power_state = state;
+                }
+                0x08 => self.interrupt_coalescing = cdw11 & 0xFFFF,
+                0x0C if self.config.apst => {
+                    let mut table = [0; 32];
+                    for (entry, bytes) in table
+                        .iter_mut()
+                        .zip(unsafe { memory(prp1, 256) }.chunks_exact(8))
+                    {
+                        *entry = u64::from_le_bytes(bytes.try_into().unwrap());
+                    }
+                    self.apst = (cdw11 & 1 != 0, table);
+                }
+                _ => return Some(Status::generic(SC_INVALID_FIELD)),
+            },
+            // Get Features
+            0x0A => match cdw10 as u8 {
+                0x02 => self.result = u32::from(self.power_state),
+                0x08 => self.result = self.interrupt_coalescing,
+                0x0C if self.config.apst => self.result = u32::from(self.apst.0),
+                _ => return Some(Status::generic(SC_INVALID_FIELD)),
+            },
+            // Identify
+            0x06 => {
+                let page = unsafe { memory(prp1, PAGE_SIZE) };
+                page.fill(0);
+
+                match cdw10 as u8 {
+                    0x00 => {
+                        if !self.namespace_exists(cmd.ns_id) {
+                            return Some(Status::generic(SC_INVALID_NAMESPACE));
+                        }
+                        let blocks = self.blocks(cmd.ns_id);
+                        page[0..8].copy_from_slice(&blocks.to_le_bytes());
+                        page[8..16].copy_from_slice(&blocks.to_le_bytes());
+                        page[16..24].copy_from_slice(&blocks.to_le_bytes());
+                        page[25] = 1;
+                        page[26] = self.namespaces[&{ cmd.ns_id }].format;
+                        for lba_format in 0..2 {
+                            let lbads = self.format_block_size(lba_format).trailing_zeros();
+                            let offset = 128 + usize::from(lba_format) * 4;
+                            page[offset..offset + 4].copy_from_slice(&(lbads << 16).to_le_bytes());
+                        }
+                    }
+                    0x01 => {
+                        page[4..24].copy_from_slice(b"EMU0001             ");
+                        page[24..64].copy_from_slice(&[b' '; 40]);
+                        page[24..38].copy_from_slice(b"nvmed emulator");
+                        page[64..72].copy_from_slice(b"1.0     ");
+                        page[77] = self.config.mdts;
+                        page[256..258].copy_from_slice(&self.config.oacs.to_le_bytes());
+                        page[262] = (ERROR_LOG_ENTRIES - 1) as u8;
+                        page[263] = (self.config.power_states.len() - 1) as u8;
+                        page[265] = u8::from(self.config.apst);
+                        for (descriptor, power_state) in page[2048..]
+                            .chunks_exact_mut(32)
+                            .zip(&self.config.power_states)
+                        {
+                            // Max Power in 0.01 W where that is exact, 0.0001 W otherwise.
+                            let (max_power, scale) = match power_state.max_power % 100 {
+                                0 => (power_state.max_power / 100, 0),
+                                _ => (power_state.max_power, 1),
+                            };
+                            descriptor[0..2].copy_from_slice(&(max_power as u16).to_le_bytes());
+                            descriptor[3] = scale | (u8::from(power_state.non_operational) << 1);
+                            descriptor[4..8]
+                                .copy_from_slice(&power_state.entry_latency.to_le_bytes());
+                            descriptor[8..12]
+                                .copy_from_slice(&power_state.exit_latency.to_le_bytes());
+                        }
+                        page[520..522].copy_from_slice(&self.config.oncs.to_le_bytes());
+                        page[525] = u8::from(self.config.volatile_write_cache);
+                    }
+                    0x02 => {
+                        let active = self
+                            .namespaces
+                            .range(cmd.ns_id + 1..)
+                            .filter(|(_, namespace)| namespace.attached);
+                        for ((nsid, _), entry) in active.zip(page.chunks_exact_mut(4)) {
+                            entry.copy_from_slice(&nsid.to_le_bytes());
+                        }
+                    }
+                    _ => return Some(Status::generic(SC_INVALID_FIELD)),
+                }
+            }
+            // Format NVM
+            0x80 if self.config.oacs & OACS_FORMAT_NVM != 0 => {
+                let lba_format = (cdw10 & 0xF) as u8;
+                if !self.namespace_exists(cmd.ns_id) {
+                    return Some(Status::generic(SC_INVALID_NAMESPACE));
+                }
+                if lba_format > 1 {
+                    return Some(Status::specific(SC_INVALID_FORMAT));
+                }
+                let namespace = self.namespaces.get_mut(&{ cmd.ns_id }).unwrap();
+                namespace.format = lba_format;
+                namespace.data.fill(0);
+            }
+            // Namespace Management
+            0x0D if self.config.oacs & OACS_NAMESPACE_MANAGEMENT != 0 => match cdw10 & 0xF {
+                // Create
+                0 => {
+                    let data = unsafe { memory(prp1, PAGE_SIZE) };
+                    let blocks = u64::from_le_bytes(data[0..8].try_into().unwrap());
+                    let lba_format = data[26] & 0xF;
+                    if lba_format > 1 {
+                        return Some(Status::specific(SC_INVALID_FORMAT));
+                    }
+
+                    let nsid = (1..)
+                        .find(|nsid| !self.namespaces.contains_key(nsid))
+                        .unwrap();
+                    let len = blocks as usize * self.format_block_size(lba_format);
+                    self.namespaces.insert(
+                        nsid,
+                        Namespace {
+                            data: vec![0; len],
+                            format: lba_format,
+                            attached: false,
+                        },
+                    );
+                    self.result = nsid;
+                }
+                // Delete
+                1 => {
+                    if self.namespaces.remove(&{ cmd.ns_id }).is_none() {
+                        return Some(Status::generic(SC_INVALID_NAMESPACE));
+                    }
+                }
+                _ => return Some(Status::generic(SC_INVALID_FIELD)),
+            },
+            // Namespace Attachment
+            0x15 if self.config.oacs & OACS_NAMESPACE_MANAGEMENT != 0 => {
+                // The controller list must name this controller, whose ID is 0.
+                let list = unsafe { memory(prp1, PAGE_SIZE) };
+                let count = usize::from(u16::from_le_bytes([list[0], list[1]]));
+                let named = list[2..].chunks_exact(2).take(count).any(|id| id == [0, 0]);
+                if !named {
+                    return Some(Status::specific(SC_CONTROLLER_LIST_INVALID));
+                }
+
+                let Some(namespace) = self.namespaces.get_mut(&{ cmd.ns_id }) else {
+                    return Some(Status::generic(SC_INVALID_NAMESPACE));
+                };
+                let attach = match cdw10 & 0xF {
+                    0 => true,
+                    1 => false,
+                    _ => return Some(Status::generic(SC_INVALID_FIELD)),
+                };
+                match (namespace.attached, attach) {
+                    (true, true) => return Some(Status::specific(SC_NAMESPACE_ALREADY_ATTACHED)),
+                    (false, false) => return Some(Status::specific(SC_NAMESPACE_NOT_ATTACHED)),
+                    _ => namespace.attached = attach,
+                }
+            }
+            _ => return Some(Status::generic(SC_INVALID_OPCODE)),
+        }
+
+        None
+    }
+
+    /// Whether namespace `nsid` is active: allocated and attached.
+    fn namespace_exists(&self, nsid: u32) -> bool {
+        self.namespaces
+            .get(&nsid)
+            .is_some_and(|namespace| namespace.attached)
+    }
+
+    fn format_block_size(&self, lba_format: u8) -> usize {
+        match lba_format {
+            0 => self.config.block_size,
+            _ => 4096,
+        }
+    }
+
+    fn data(&mut self, nsid: u32) -> &mut [u8] {
+        &mut self.namespaces.get_mut(&nsid).unwrap().data
+    }
+
+    fn block_size(&self, nsid: u32) -> usize {
+        self.format_block_size(self.namespaces[&nsid].format)
+    }
+
+    fn blocks(&self, nsid: u32) -> u64 {
+        (self.namespaces[&nsid].data.len() / self.block_size(nsid)) as u64
+    }
+
+    /// Checks the namespace and LBA range of an I/O command, returning the byte range it
+    /// covers in the namespace.
+    fn lba_range(
+        &self,
+        nsid: u32,
+        lba: u64,
+        blocks: u64,
+    ) -> Result<std::ops::Range<usize>, Status> {
+        if !self.namespace_exists(nsid) {
+            return Err(Status::generic(SC_INVALID_NAMESPACE));
+        }
+        if lba + blocks > self.blocks(nsid) {
+            return Err(Status::generic(SC_LBA_OUT_OF_RANGE));
+        }
+
+        let block_size = self.block_size(nsid);
+        Ok(lba as usize * block_size..(lba + blocks) as usize * block_size)
+    }
+
+    fn max_transfer(&self) -> usize {
+        match self.config.mdts {
+            0 => usize::MAX,
+            mdts => PAGE_SIZE << mdts,
+        }
+    }
+
+    fn io_command(&mut self, cmd: &NvmeCommand) -> Option<Status> {
+        let lba = u64::from(cmd.cdw10) | (u64::from(cmd.cdw11) << 32);
+        let blocks = u64::from(cmd.cdw12 & 0xFFFF) + 1;
+
+        let result = match cmd.opcode {
+            // Flush
+            0x00 if self.namespace_exists(cmd.ns_id) => Ok(()),
+            0x00 => Err(Status::generic(SC_INVALID_NAMESPACE)),
+            // Write, Read
+            0x01 | 0x02 => self.lba_range(cmd.ns_id, lba, blocks).and_then(|range| {
+                if range.len() > self.max_transfer() {
+                    return Err(Status::generic(SC_INVALID_FIELD));
+                }
+
+                let namespace = &mut self.data(cmd.ns_id)[range];
+                let mut done = 0;
+                for (addr, len) in prp_segments(cmd.d_ptr[0], cmd.d_ptr[1], namespace.len()) {
+                    let buf = unsafe { memory(addr, len) };
+                    if cmd.opcode == 0x01 {
+                        namespace[done..done + len].copy_from_slice(buf);
+                    } else {
+                        buf.copy_from_slice(&namespace[done..done + len]);
+                    }
+                    done += len;
+                }
+                Ok(())
+            }),
+            // Write Zeroes
+            0x08 if self.config.oncs & ONCS_WRITE_ZEROES != 0 => self
+                .lba_range(cmd.ns_id, lba, blocks)
+                .map(|range| self.data(cmd.ns_id)[range].fill(0)),
+            // Dataset Management. Deallocated blocks read back as zeroes.
+            0x09 if self.config.oncs & ONCS_DATASET_MANAGEMENT != 0 => {
+                let ranges = (cmd.cdw10 & 0xFF) as usize + 1;
+                let list = unsafe { memory(cmd.d_ptr[0] as usize, ranges * 16) }.to_vec();
+
+                list.chunks_exact(16).try_for_each(|range| {
+                    let blocks = u32::from_le_bytes(range[4..8].try_into().unwrap());
+                    let lba = u64::from_le_bytes(range[8..16].try_into().unwrap());
+                    let range = self.lba_range(cmd.ns_id, lba, u64::from(blocks))?;
+                    if cmd.cdw11 & (1 << 2) != 0 {
+                        self.data(cmd.ns_id)[range].fill(0);
+                    }
+                    Ok(())
+                })
+            }
+            _ => Err(Status::generic(SC_INVALID_OPCODE)),
+        };
+
+        result.err()
+    }
+}
+
+/// Splits a transfer described by PRP1 and PRP2 into host memory segments.
+fn prp_segments(prp1: u64, prp2: u64, len: usize) -> Vec<(usize, usize)> {
+    let prp1 = prp1 as usize;
+    let first = len.min(PAGE_SIZE - prp1 % PAGE_SIZE);
+    let mut segments = vec![(prp1, first)];
+    let mut remaining = len - first;
+
+    if remaining == 0 {
+        return segments;
+    }
+    if remaining <= PAGE_SIZE {
+        segments.push((prp2 as usize, remaining));
+        return segments;
+    }
+
+    let list = prp2 as usize as *const u64;
+    for i in 0.. {
+        if remaining == 0 {
+            break;
+        }
+        let len = remaining.min(PAGE_SIZE);
+        let addr = unsafe { list.add(i).read() } as usize;
+        segments.push((addr, len));
+        remaining -= len;
+    }
+
+    segments
+}